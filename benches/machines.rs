@@ -0,0 +1,88 @@
+//! Compares the stack and register machines on recursion and list heavy
+//! programs.
+//! Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use lala::{
+  compile::{optimize::optimize, register, BytecodeInfo, Ctx, Machine},
+  desugar::Desugar,
+  lexer::Lexer,
+  parser::Parser,
+  Value,
+};
+
+const SRC: &str = r#"
+fn fib(0) -> 0
+fn fib(1) -> 1
+fn fib(n) -> fib(n - 1) + fib(n - 2)
+fn repeat(0, acc) -> acc
+fn repeat(n, _) -> repeat(n - 1, test())
+fn range(0, acc) -> acc
+fn range(n, acc) -> range(n - 1, [n | acc])
+fn sum([], acc) -> acc
+fn sum([x | xs], acc) -> sum(xs, acc + x)
+fn map(_, []) -> []
+fn map(f, [x | xs]) -> [f(x) | map(f, xs)]
+fn double(x) -> x * 2
+fn tails([]) -> []
+fn tails([x | xs]) -> [{x, xs} | tails(xs)]
+fn firsts([]) -> 0
+fn firsts([{x, _} | ts]) -> x + firsts(ts)
+fn lists(0, acc) -> acc
+fn lists(n, _) ->
+  let l = range(2000, []) in
+  lists(n - 1, sum(map(double, l), 0) + firsts(tails(l)))
+"#;
+
+fn compile(src: &str) -> BytecodeInfo {
+  let program = Parser::new(Lexer::new(src))
+    .program()
+    .unwrap()
+    .desugar()
+    .unwrap();
+  let mut ctx = Ctx::new();
+  ctx.program(program);
+  let mut info = ctx.bytecode();
+  optimize(&mut info);
+  info
+}
+
+fn machines(c: &mut Criterion) {
+  let main = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/main.lala")).unwrap();
+  let info = compile(&format!("{main}{SRC}"));
+  let program = register::Program::new(&info).unwrap();
+  let workloads = [
+    ("fib(20)", "fib", vec![Value::Number(20)]),
+    (
+      "main.lala x 200",
+      "repeat",
+      vec![Value::Number(200), Value::Number(0)],
+    ),
+    (
+      "lists x 20",
+      "lists",
+      vec![Value::Number(20), Value::Number(0)],
+    ),
+  ];
+  for (name, function, arguments) in workloads {
+    let mut group = c.benchmark_group(name);
+    group.bench_function(BenchmarkId::from_parameter("stack"), |b| {
+      b.iter(|| {
+        Machine::new(&info)
+          .call(function, arguments.clone())
+          .unwrap()
+      })
+    });
+    group.bench_function(BenchmarkId::from_parameter("register"), |b| {
+      b.iter(|| {
+        register::Machine::new(&program)
+          .call(function, arguments.clone())
+          .unwrap()
+      })
+    });
+    group.finish();
+  }
+}
+
+criterion_group!(benches, machines);
+criterion_main!(benches);
@@ -0,0 +1,198 @@
+//! Derives `lala::IntoValue` and `lala::FromValue` for structs and enums.
+//!
+//! A struct is a tuple of its fields tagged with its name in snake case, and
+//! an enum is tagged with the name of its variant. Types without fields are
+//! just the tag, as an atom.
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Generics, Ident};
+
+#[proc_macro_derive(IntoValue)]
+pub fn derive_into_value(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+  let input = parse_macro_input!(input as DeriveInput);
+  let name = &input.ident;
+  let generics = bounded(&input.generics, quote!(::lala::IntoValue));
+  let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+  let body = match &input.data {
+    Data::Struct(data) => {
+      let (pattern, bindings) = destructure(quote!(#name), &data.fields);
+      let value = tagged(&tag(name), &bindings);
+      quote!(let #pattern = self; #value)
+    }
+    Data::Enum(data) => {
+      let arms = data.variants.iter().map(|variant| {
+        let ident = &variant.ident;
+        let (pattern, bindings) = destructure(quote!(Self::#ident), &variant.fields);
+        let value = tagged(&tag(ident), &bindings);
+        quote!(#pattern => #value,)
+      });
+      quote!(match self { #(#arms)* })
+    }
+    Data::Union(_) => return unsupported(name),
+  };
+  quote! {
+    impl #impl_generics ::lala::IntoValue for #name #ty_generics #where_clause {
+      fn into_value(self) -> ::lala::Value {
+        #body
+      }
+    }
+  }
+  .into()
+}
+
+#[proc_macro_derive(FromValue)]
+pub fn derive_from_value(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+  let input = parse_macro_input!(input as DeriveInput);
+  let name = &input.ident;
+  let generics = bounded(&input.generics, quote!(::lala::FromValue));
+  let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+  let (attempts, expected) = match &input.data {
+    Data::Struct(data) => {
+      let attempt = untagged(&tag(name), quote!(#name), &data.fields);
+      (vec![attempt], shape(&tag(name), &data.fields))
+    }
+    Data::Enum(data) => {
+      let attempts = data.variants.iter().map(|variant| {
+        let ident = &variant.ident;
+        untagged(&tag(ident), quote!(Self::#ident), &variant.fields)
+      });
+      (attempts.collect(), format!("a {name}"))
+    }
+    Data::Union(_) => return unsupported(name),
+  };
+  quote! {
+    impl #impl_generics ::lala::FromValue for #name #ty_generics #where_clause {
+      fn from_value(
+        value: &::lala::Value,
+      ) -> ::core::result::Result<Self, ::std::string::String> {
+        #(#attempts)*
+        ::core::result::Result::Err(::lala::convert::mismatch(#expected, value))
+      }
+    }
+  }
+  .into()
+}
+
+fn unsupported(name: &Ident) -> proc_macro::TokenStream {
+  syn::Error::new(name.span(), "unions can't be converted to values")
+    .to_compile_error()
+    .into()
+}
+
+/// `generics` with every type parameter bound by `bound`.
+fn bounded(generics: &Generics, bound: TokenStream) -> Generics {
+  let mut generics = generics.clone();
+  for param in generics.type_params_mut() {
+    param.bounds.push(parse_quote!(#bound));
+  }
+  generics
+}
+
+/// The name of a type or variant in snake case, as used for its tag.
+fn tag(ident: &Ident) -> String {
+  let chars: Vec<char> = ident.to_string().chars().collect();
+  let mut tag = String::new();
+  for (i, c) in chars.iter().enumerate() {
+    if c.is_uppercase() && i > 0 {
+      let prev = chars[i - 1];
+      let next_lower = chars.get(i + 1).is_some_and(|c| c.is_lowercase());
+      if (!prev.is_uppercase() && prev != '_') || (prev.is_uppercase() && next_lower) {
+        tag.push('_');
+      }
+    }
+    tag.extend(c.to_lowercase());
+  }
+  tag
+}
+
+/// A pattern binding every field of `path`, along with the bindings.
+fn destructure(path: TokenStream, fields: &Fields) -> (TokenStream, Vec<Ident>) {
+  match fields {
+    Fields::Named(fields) => {
+      let names: Vec<_> = fields
+        .named
+        .iter()
+        .map(|f| f.ident.clone().unwrap())
+        .collect();
+      (quote!(#path { #(#names),* }), names)
+    }
+    Fields::Unnamed(fields) => {
+      let names: Vec<_> = (0..fields.unnamed.len())
+        .map(|i| format_ident!("f{i}"))
+        .collect();
+      (quote!(#path(#(#names),*)), names)
+    }
+    Fields::Unit => (path, vec![]),
+  }
+}
+
+/// The value of a tag followed by `bindings`.
+fn tagged(tag: &str, bindings: &[Ident]) -> TokenStream {
+  let atom = quote!(::lala::Value::Atom(::lala::Atom::new(#tag)));
+  if bindings.is_empty() {
+    return atom;
+  }
+  quote! {
+    ::lala::Value::Tuple(
+      [#atom, #(::lala::IntoValue::into_value(#bindings)),*].into()
+    )
+  }
+}
+
+/// Returns `path` built from `value` if it has `tag`, falling through
+/// otherwise.
+fn untagged(tag: &str, path: TokenStream, fields: &Fields) -> TokenStream {
+  if let Fields::Unit = fields {
+    return quote! {
+      if let ::lala::Value::Atom(atom) = value {
+        if atom.as_str() == #tag {
+          return ::core::result::Result::Ok(#path);
+        }
+      }
+    };
+  }
+  let (pattern, bindings) = destructure(path, fields);
+  let len = bindings.len();
+  let slots: Vec<_> = bindings
+    .iter()
+    .map(|b| Ident::new(&format!("{b}_value"), Span::call_site()))
+    .collect();
+  quote! {
+    if let ::core::option::Option::Some([#(#slots),*]) =
+      ::lala::convert::tagged(value, #tag, #len)
+    {
+      #(let #bindings = ::lala::FromValue::from_value(#slots)?;)*
+      return ::core::result::Result::Ok(#pattern);
+    }
+  }
+}
+
+/// How the value of a struct looks, for errors.
+fn shape(tag: &str, fields: &Fields) -> String {
+  if fields.is_empty() {
+    return format!("#{tag}");
+  }
+  let holes = ", _".repeat(fields.len());
+  format!("{{#{tag}{holes}}}")
+}
+
+#[cfg(test)]
+mod test {
+  use quote::format_ident;
+
+  use super::tag;
+
+  #[test]
+  fn tags() {
+    for (name, expected) in [
+      ("Point", "point"),
+      ("BoundingBox", "bounding_box"),
+      ("HTTPServer", "http_server"),
+      ("Vec2", "vec2"),
+      ("already_snake", "already_snake"),
+    ] {
+      assert_eq!(tag(&format_ident!("{name}")), expected);
+    }
+  }
+}
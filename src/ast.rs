@@ -40,6 +40,17 @@ pub enum Expression {
   },
   List {
     elements: Vec<Expression>,
+    tail: Option<Expr>,
+  },
+  Receive {
+    arms: Vec<Arm>,
+  },
+  /// `try body of arms catch catches end`, where `arms` is empty when there
+  /// is no `of` section and every catch arm has a class and a reason pattern.
+  Try {
+    body: Expr,
+    arms: Vec<Arm>,
+    catches: Vec<Arm>,
   },
 }
 
@@ -0,0 +1,191 @@
+//! Atoms interned in a table shared by the whole program, so that they are
+//! compared and copied as small integers.
+//!
+//! Like in Erlang, atoms are never freed, so there can be at most [`LIMIT`] of
+//! them. The names are leaked once when first interned, and stored in chunks
+//! that are never moved, so that printing an atom doesn't take a lock.
+
+use std::{
+  collections::HashMap,
+  sync::{Mutex, OnceLock},
+};
+
+/// The most atoms a program can have, as in Erlang.
+pub const LIMIT: usize = 1 << 20;
+
+/// The number of names in each chunk of the table.
+const CHUNK: usize = 1 << 10;
+
+/// Atoms the runtime itself uses, interned ahead of the others so that they
+/// have fixed ids.
+const PREDEFINED: [&str; 2] = ["true", "false"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Atom(u32);
+
+struct Table {
+  /// The id of each name, only locked to intern.
+  ids: Mutex<HashMap<&'static str, u32>>,
+  /// The names by id, in chunks allocated as the table grows.
+  names: Vec<OnceLock<Box<[OnceLock<&'static str>]>>>,
+  limit: usize,
+}
+
+fn table() -> &'static Table {
+  static TABLE: OnceLock<Table> = OnceLock::new();
+  TABLE.get_or_init(|| {
+    let table = Table::new(LIMIT);
+    for name in PREDEFINED {
+      table.intern(name).unwrap();
+    }
+    table
+  })
+}
+
+impl Table {
+  fn new(limit: usize) -> Self {
+    Table {
+      ids: Mutex::default(),
+      names: (0..limit.div_ceil(CHUNK))
+        .map(|_| OnceLock::new())
+        .collect(),
+      limit,
+    }
+  }
+
+  fn intern(&self, name: &str) -> Result<u32, String> {
+    let mut ids = self.ids.lock().unwrap();
+    if let Some(id) = ids.get(name) {
+      return Ok(*id);
+    }
+    let id = ids.len();
+    if id >= self.limit {
+      return Err(format!("Too many atoms, the limit is {}", self.limit));
+    }
+    let name: &'static str = Box::leak(name.into());
+    let chunk =
+      self.names[id / CHUNK].get_or_init(|| (0..CHUNK).map(|_| OnceLock::new()).collect());
+    chunk[id % CHUNK].set(name).unwrap();
+    ids.insert(name, id as u32);
+    Ok(id as u32)
+  }
+
+  fn name(&self, id: u32) -> &'static str {
+    let id = id as usize;
+    self.names[id / CHUNK]
+      .get()
+      .and_then(|chunk| chunk[id % CHUNK].get())
+      .expect("Atoms are interned before they are used")
+  }
+}
+
+impl Atom {
+  pub const TRUE: Atom = Atom(0);
+  pub const FALSE: Atom = Atom(1);
+
+  /// Interns `name`, panicking if the table is full. Names that come from
+  /// the program's input go through [`Atom::try_new`] instead.
+  pub fn new(name: &str) -> Self {
+    Self::try_new(name).unwrap_or_else(|err| panic!("{err}"))
+  }
+
+  /// Interns `name`, failing if it is new and there are already [`LIMIT`]
+  /// atoms.
+  pub fn try_new(name: &str) -> Result<Self, String> {
+    table().intern(name).map(Atom)
+  }
+
+  /// Whether `name` was ever interned.
+  #[cfg(test)]
+  pub(crate) fn is_interned(name: &str) -> bool {
+    table().ids.lock().unwrap().contains_key(name)
+  }
+
+  /// The position of the atom in the table. Unlike names, indices compare
+  /// as integers, but only in the order atoms were interned.
+  pub fn index(self) -> u32 {
+    self.0
+  }
+
+  pub fn boolean(b: bool) -> Self {
+    if b {
+      Atom::TRUE
+    } else {
+      Atom::FALSE
+    }
+  }
+
+  pub fn as_str(self) -> &'static str {
+    table().name(self.0)
+  }
+}
+
+impl From<&str> for Atom {
+  fn from(name: &str) -> Self {
+    Atom::new(name)
+  }
+}
+
+/// Atoms are ordered by name, so that the order doesn't depend on when they
+/// were interned.
+impl Ord for Atom {
+  fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+    if self == other {
+      return std::cmp::Ordering::Equal;
+    }
+    self.as_str().cmp(other.as_str())
+  }
+}
+
+impl PartialOrd for Atom {
+  fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl std::fmt::Display for Atom {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+
+impl std::fmt::Debug for Atom {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "#{}", self.as_str())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{Atom, Table};
+
+  #[test]
+  fn intern() {
+    let a = Atom::new("intern_test");
+    assert_eq!(a, Atom::new("intern_test"));
+    assert_ne!(a, Atom::new("intern_test_other"));
+    assert_eq!(a.as_str(), "intern_test");
+    assert_eq!(Atom::new("true"), Atom::TRUE);
+    assert_eq!(Atom::boolean(false).to_string(), "false");
+    assert!(Atom::new("a") < Atom::new("b"));
+    // Atoms interned on another thread can be printed here.
+    let b = std::thread::spawn(|| Atom::new("from_another_thread"))
+      .join()
+      .unwrap();
+    assert_eq!(b.as_str(), "from_another_thread");
+  }
+
+  #[test]
+  fn limit() {
+    let table = Table::new(3);
+    assert_eq!(table.intern("a"), Ok(0));
+    assert_eq!(table.intern("b"), Ok(1));
+    assert_eq!(table.intern("c"), Ok(2));
+    assert_eq!(
+      table.intern("d"),
+      Err("Too many atoms, the limit is 3".to_string())
+    );
+    assert_eq!(table.intern("a"), Ok(0));
+    assert_eq!(table.name(1), "b");
+  }
+}
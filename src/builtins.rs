@@ -0,0 +1,476 @@
+use std::io::{BufRead, Write};
+
+use crate::{atom::Atom, host::Host, value::Value};
+
+/// Functions implemented natively and available to every program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Builtin {
+  Length,
+  Reverse,
+  Append,
+  Nth,
+  Zip,
+  Hd,
+  Tl,
+  Element,
+  TupleSize,
+  TupleToList,
+  ListToTuple,
+  StringLength,
+  Concat,
+  AtomToString,
+  StringToAtom,
+  ToString,
+  Print,
+  Println,
+  ReadLine,
+  ReadFile,
+  WriteFile,
+  Args,
+  Spawn,
+  SpawnLink,
+  SelfPid,
+  Send,
+  Link,
+  Unlink,
+  Monitor,
+  Demonitor,
+  TrapExit,
+  Exit,
+  ExitProcess,
+  Throw,
+  Error,
+}
+
+const BUILTINS: &[(&str, Builtin)] = &[
+  ("length", Builtin::Length),
+  ("reverse", Builtin::Reverse),
+  ("append", Builtin::Append),
+  ("nth", Builtin::Nth),
+  ("zip", Builtin::Zip),
+  ("hd", Builtin::Hd),
+  ("tl", Builtin::Tl),
+  ("element", Builtin::Element),
+  ("tuple_size", Builtin::TupleSize),
+  ("tuple_to_list", Builtin::TupleToList),
+  ("list_to_tuple", Builtin::ListToTuple),
+  ("string_length", Builtin::StringLength),
+  ("concat", Builtin::Concat),
+  ("atom_to_string", Builtin::AtomToString),
+  ("string_to_atom", Builtin::StringToAtom),
+  ("to_string", Builtin::ToString),
+  ("print", Builtin::Print),
+  ("println", Builtin::Println),
+  ("read_line", Builtin::ReadLine),
+  ("read_file", Builtin::ReadFile),
+  ("write_file", Builtin::WriteFile),
+  ("args", Builtin::Args),
+  ("spawn", Builtin::Spawn),
+  ("spawn_link", Builtin::SpawnLink),
+  ("self", Builtin::SelfPid),
+  ("send", Builtin::Send),
+  ("link", Builtin::Link),
+  ("unlink", Builtin::Unlink),
+  ("monitor", Builtin::Monitor),
+  ("demonitor", Builtin::Demonitor),
+  ("trap_exit", Builtin::TrapExit),
+  ("exit", Builtin::Exit),
+  ("exit", Builtin::ExitProcess),
+  ("throw", Builtin::Throw),
+  ("error", Builtin::Error),
+];
+
+impl Builtin {
+  /// Finds the builtin called `name` taking `arity` arguments, or any
+  /// builtin called `name` if none takes that many.
+  pub fn from_name(name: &str, arity: usize) -> Option<Self> {
+    let mut candidates = BUILTINS.iter().filter(|(n, _)| *n == name);
+    let first = candidates.clone().next()?.1;
+    let exact = candidates.find(|(_, builtin)| builtin.arity() == arity);
+    Some(exact.map_or(first, |(_, builtin)| *builtin))
+  }
+
+  pub fn name(self) -> &'static str {
+    BUILTINS
+      .iter()
+      .find(|(_, builtin)| *builtin == self)
+      .map(|(n, _)| *n)
+      .unwrap()
+  }
+
+  pub fn arity(self) -> usize {
+    match self {
+      Builtin::Append
+      | Builtin::Nth
+      | Builtin::Zip
+      | Builtin::Element
+      | Builtin::Concat
+      | Builtin::WriteFile
+      | Builtin::Spawn
+      | Builtin::SpawnLink
+      | Builtin::Send
+      | Builtin::ExitProcess => 2,
+      Builtin::ReadLine | Builtin::Args | Builtin::SelfPid => 0,
+      _ => 1,
+    }
+  }
+
+  pub fn check_arity(self, arguments: usize) -> Result<(), String> {
+    if arguments != self.arity() {
+      return Err(format!(
+        "{}: expected {} arguments but got {}",
+        self.name(),
+        self.arity(),
+        arguments
+      ));
+    }
+    Ok(())
+  }
+
+  /// Whether the builtin acts on processes or raises exceptions, and so must
+  /// be run by the engine instead of [`Builtin::apply`].
+  pub fn is_process(self) -> bool {
+    matches!(
+      self,
+      Builtin::Spawn
+        | Builtin::SpawnLink
+        | Builtin::SelfPid
+        | Builtin::Send
+        | Builtin::Link
+        | Builtin::Unlink
+        | Builtin::Monitor
+        | Builtin::Demonitor
+        | Builtin::TrapExit
+        | Builtin::Exit
+        | Builtin::ExitProcess
+        | Builtin::Throw
+        | Builtin::Error
+    )
+  }
+
+  pub fn apply(self, host: &mut Host, arguments: Vec<Value>) -> Result<Value, String> {
+    self.check_arity(arguments.len())?;
+    let bad_argument = || Err(format!("{}: bad argument", self.name()));
+    let mut arguments = arguments.into_iter();
+    let mut next = || arguments.next().unwrap();
+    match self {
+      Builtin::Length => match next().to_vec() {
+        Some(xs) => Ok(Value::Number(xs.len() as i32)),
+        None => bad_argument(),
+      },
+      Builtin::Reverse => match next().to_vec() {
+        Some(mut xs) => {
+          xs.reverse();
+          Ok(Value::from_vec(xs))
+        }
+        None => bad_argument(),
+      },
+      Builtin::Append => {
+        let (xs, ys) = (next(), next());
+        match xs.to_vec() {
+          Some(xs) => Ok(xs.into_iter().rfold(ys, |acc, nxt| Value::cons(nxt, acc))),
+          None => bad_argument(),
+        }
+      }
+      Builtin::Nth => match (next(), next().to_vec()) {
+        (Value::Number(n), Some(xs)) if n >= 1 && n as usize <= xs.len() => {
+          Ok(xs[n as usize - 1].clone())
+        }
+        _ => bad_argument(),
+      },
+      Builtin::Zip => match (next().to_vec(), next().to_vec()) {
+        (Some(xs), Some(ys)) if xs.len() == ys.len() => Ok(Value::from_vec(
+          xs.into_iter()
+            .zip(ys)
+            .map(|(x, y)| Value::Tuple([x, y].into()))
+            .collect(),
+        )),
+        _ => bad_argument(),
+      },
+      Builtin::Hd => match next() {
+        Value::Cons(cell) => Ok(cell.0.clone()),
+        _ => bad_argument(),
+      },
+      Builtin::Tl => match next() {
+        Value::Cons(cell) => Ok(cell.1.clone()),
+        _ => bad_argument(),
+      },
+      Builtin::Element => match (next(), next()) {
+        (Value::Number(n), Value::Tuple(elements)) if n >= 1 && n as usize <= elements.len() => {
+          Ok(elements[n as usize - 1].clone())
+        }
+        _ => bad_argument(),
+      },
+      Builtin::TupleSize => match next() {
+        Value::Tuple(elements) => Ok(Value::Number(elements.len() as i32)),
+        _ => bad_argument(),
+      },
+      Builtin::TupleToList => match next() {
+        Value::Tuple(elements) => Ok(Value::from_vec(elements.to_vec())),
+        _ => bad_argument(),
+      },
+      Builtin::ListToTuple => match next().to_vec() {
+        Some(xs) => Ok(Value::Tuple(xs.into())),
+        None => bad_argument(),
+      },
+      Builtin::StringLength => match next() {
+        Value::String(s) => Ok(Value::Number(s.chars().count() as i32)),
+        _ => bad_argument(),
+      },
+      Builtin::Concat => match (next(), next()) {
+        (Value::String(a), Value::String(b)) => Ok(Value::String(format!("{a}{b}").into())),
+        _ => bad_argument(),
+      },
+      Builtin::AtomToString => match next() {
+        Value::Atom(a) => Ok(Value::String(a.as_str().into())),
+        _ => bad_argument(),
+      },
+      Builtin::StringToAtom => match next() {
+        Value::String(s) => Atom::try_new(&s).map(Value::Atom),
+        _ => bad_argument(),
+      },
+      Builtin::ToString => match next() {
+        Value::String(s) => Ok(Value::String(s)),
+        value => Ok(Value::String(value.to_string().into())),
+      },
+      Builtin::Print | Builtin::Println => {
+        let mut text = match next() {
+          Value::String(s) => s.to_string(),
+          value => value.to_string(),
+        };
+        if self == Builtin::Println {
+          text.push('\n');
+        }
+        let res = host
+          .stdout
+          .write_all(text.as_bytes())
+          .and_then(|_| host.stdout.flush());
+        Ok(io_result(res.map(|_| Value::Number(text.len() as i32))))
+      }
+      Builtin::ReadLine => {
+        let mut line = String::new();
+        match host.stdin.read_line(&mut line) {
+          Ok(0) => Ok(error(Value::Atom(Atom::new("eof")))),
+          res => Ok(io_result(res.map(|_| {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            Value::String(line.into())
+          }))),
+        }
+      }
+      Builtin::ReadFile => match next() {
+        Value::String(path) => Ok(io_result(
+          host.read_file(&path).map(|s| Value::String(s.into())),
+        )),
+        _ => bad_argument(),
+      },
+      Builtin::WriteFile => match (next(), next()) {
+        (Value::String(path), Value::String(contents)) => {
+          let len = contents.len() as i32;
+          Ok(io_result(
+            host
+              .write_file(&path, &contents)
+              .map(|_| Value::Number(len)),
+          ))
+        }
+        _ => bad_argument(),
+      },
+      Builtin::Args => Ok(Value::from_vec(
+        host
+          .args
+          .iter()
+          .map(|arg| Value::String(arg.as_str().into()))
+          .collect(),
+      )),
+      _ => Err(format!("{}: only available to processes", self.name())),
+    }
+  }
+}
+
+fn ok(value: Value) -> Value {
+  Value::Tuple([Value::Atom(Atom::new("ok")), value].into())
+}
+
+fn error(reason: Value) -> Value {
+  Value::Tuple([Value::Atom(Atom::new("error")), reason].into())
+}
+
+/// Turns the result of an I/O operation into `{#ok, value}` or
+/// `{#error, reason}`, using POSIX-style atoms for the common reasons.
+fn io_result(res: std::io::Result<Value>) -> Value {
+  use std::io::ErrorKind;
+  match res {
+    Ok(value) => ok(value),
+    Err(err) => error(match err.kind() {
+      ErrorKind::NotFound => Value::Atom(Atom::new("enoent")),
+      ErrorKind::PermissionDenied => Value::Atom(Atom::new("eacces")),
+      ErrorKind::AlreadyExists => Value::Atom(Atom::new("eexist")),
+      ErrorKind::InvalidData => Value::Atom(Atom::new("badarg")),
+      _ => Value::String(err.to_string().into()),
+    }),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    rc::Rc,
+  };
+
+  use crate::{
+    compile::{Ctx, Machine},
+    desugar::{Desugar, Program},
+    eval::Env,
+    host::{Capture, Files, Host},
+    lexer::Lexer,
+    parser::Parser,
+  };
+
+  fn eval(src: &str) -> Result<String, String> {
+    eval_with_host(src, Host::default())
+  }
+
+  fn run(src: &str) -> Result<String, String> {
+    run_with_host(src, Host::default())
+  }
+
+  fn eval_with_host(src: &str, host: Host) -> Result<String, String> {
+    let mut parser = Parser::new(Lexer::new(src));
+    let expr = parser.expression()?.desugar().unwrap();
+    let mut env = Env::from_program(Program {
+      definitions: BTreeMap::new(),
+    })
+    .with_host(host);
+    env.eval(expr).map(|v| v.to_string())
+  }
+
+  fn run_with_host(src: &str, host: Host) -> Result<String, String> {
+    let mut parser = Parser::new(Lexer::new(src));
+    let expr = parser.expression()?.desugar().unwrap();
+    let mut ctx = Ctx::new();
+    ctx.fn_clause(expr);
+    let info = ctx.bytecode();
+    let mut machine = Machine::new(&info).with_host(host);
+    Ok(machine.run()?.to_string())
+  }
+
+  #[test]
+  fn builtins_agree() {
+    let cases = [
+      ("length([1, 2, 3])", "3"),
+      ("length([])", "0"),
+      ("reverse([1, 2, 3])", "[3, 2, 1]"),
+      ("append([1, 2], [3 | 4])", "[1, 2, 3 | 4]"),
+      ("nth(2, [#a, #b, #c])", "#b"),
+      ("zip([1, 2], [#a, #b])", "[{1, #a}, {2, #b}]"),
+      ("hd([1, 2])", "1"),
+      ("tl([1, 2])", "[2]"),
+      ("element(1, {#ok, 2})", "#ok"),
+      ("tuple_size({1, 2, 3})", "3"),
+      ("tuple_to_list({1, 2})", "[1, 2]"),
+      ("list_to_tuple([1, 2])", "{1, 2}"),
+      ("string_length(\"hello\")", "5"),
+      ("concat(\"foo\", \"bar\")", "\"foobar\""),
+      ("atom_to_string(#ok)", "\"ok\""),
+      ("string_to_atom(\"ok\")", "#ok"),
+      ("to_string({#ok, [1, \"a\"]})", "\"{#ok, [1, \\\"a\\\"]}\""),
+      ("to_string(\"a\")", "\"a\""),
+    ];
+    for (src, expected) in cases {
+      assert_eq!(eval(src), Ok(expected.to_string()), "{src}");
+      assert_eq!(run(src), Ok(expected.to_string()), "{src}");
+    }
+  }
+
+  #[test]
+  fn builtins_bad_arguments() {
+    let cases = [
+      "length(1)",
+      "nth(4, [1, 2, 3])",
+      "zip([1], [])",
+      "element(0, {1})",
+      "concat(\"a\", #b)",
+      "hd([])",
+    ];
+    for src in cases {
+      assert!(eval(src).is_err(), "{src}");
+      assert_eq!(eval(src), run(src), "{src}");
+    }
+  }
+
+  #[test]
+  fn console_builtins() {
+    let src = r#"{print("a"), println({1, #b}), read_line(), read_line(), args()}"#;
+    let expected = r#"{{#ok, 1}, {#ok, 8}, {#ok, "hello"}, {#error, #eof}, ["x", "y"]}"#;
+    for engine in [eval_with_host, run_with_host] {
+      let stdout = Capture::default();
+      let host = Host {
+        args: vec!["x".to_string(), "y".to_string()],
+        stdin: Box::new(std::io::Cursor::new("hello\n")),
+        stdout: Box::new(stdout.clone()),
+        ..Host::default()
+      };
+      assert_eq!(engine(src, host), Ok(expected.to_string()));
+      assert_eq!(stdout.contents(), "a{1, #b}\n");
+    }
+  }
+
+  #[test]
+  fn file_builtins() {
+    let dir = std::env::temp_dir().join(format!("lala-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file.txt").display().to_string();
+    let src = format!(r#"{{write_file({path:?}, "contents"), read_file({path:?})}}"#);
+    let expected = r#"{{#ok, 8}, {#ok, "contents"}}"#;
+    assert_eq!(eval(&src), Ok(expected.to_string()));
+    assert_eq!(run(&src), Ok(expected.to_string()));
+
+    let missing = dir.join("missing.txt").display().to_string();
+    let src = format!("read_file({missing:?})");
+    assert_eq!(eval(&src), Ok("{#error, #enoent}".to_string()));
+    assert_eq!(run(&src), Ok("{#error, #enoent}".to_string()));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  /// Files kept in memory, shared with the test after being handed to a
+  /// [`Host`].
+  #[derive(Clone, Default)]
+  struct Memory(Rc<RefCell<HashMap<String, String>>>);
+
+  impl Files for Memory {
+    fn read(&mut self, path: &str) -> std::io::Result<String> {
+      self
+        .0
+        .borrow()
+        .get(path)
+        .cloned()
+        .ok_or(ErrorKind::NotFound.into())
+    }
+
+    fn write(&mut self, path: &str, contents: &str) -> std::io::Result<()> {
+      self
+        .0
+        .borrow_mut()
+        .insert(path.to_string(), contents.to_string());
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn host_files() {
+    let src = r#"{write_file("a", "contents"), read_file("a"), read_file("b")}"#;
+    let expected = r#"{{#ok, 8}, {#ok, "contents"}, {#error, #enoent}}"#;
+    for engine in [eval_with_host, run_with_host] {
+      let files = Memory::default();
+      let host = Host {
+        files: Box::new(files.clone()),
+        ..Host::default()
+      };
+      assert_eq!(engine(src, host), Ok(expected.to_string()));
+      assert_eq!(files.0.borrow()["a"], "contents");
+    }
+  }
+}
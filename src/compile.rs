@@ -1,10 +1,34 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+  cell::{Cell, RefCell},
+  collections::{HashMap, VecDeque},
+  rc::Rc,
+};
 
+use gc::{Stats, Word};
 use indexmap::IndexMap;
+use trace::Tracer;
 
-use crate::desugar::{self, Cond, Expression, Occurrence};
+use crate::{
+  atom::Atom,
+  builtins::Builtin,
+  desugar::{self, Cond, Expression, Occurrence, Operation},
+  host::Host,
+  prelude,
+  process::{
+    Context, Crash, Evaluation, Outcome, Pid, Process, Scheduler, Status, Suspended, Term,
+  },
+  value::{live_size, Value},
+};
 
-#[derive(Debug)]
+pub mod asm;
+pub mod gc;
+pub mod module;
+pub mod optimize;
+pub mod register;
+pub mod trace;
+pub mod verify;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Bytecode {
   Return,
   PushNumber {
@@ -43,19 +67,211 @@ pub enum Bytecode {
   },
   GetHd,
   GetTl,
+  /// Pushes a copy of the value on top of the stack.
+  Dup,
   Jump {
     index: usize,
   },
   MatchFail,
+  CallBuiltin {
+    builtin: Builtin,
+  },
+  /// Calls the native of the host named by the atom constant `id` with the
+  /// `arity` values on top of the stack.
+  CallNative {
+    id: u16,
+    arity: usize,
+  },
+  /// Arithmetic on the two numbers on top of the stack, failing on overflow
+  /// and division by zero.
+  Add,
+  Sub,
+  Mul,
+  Div,
+  /// Structural equality of the two values on top of the stack.
+  Eq,
+  /// Pushes an entry of the function table as a function value.
+  LoadFunction {
+    function: usize,
+  },
+  /// Calls an entry of the function table with as many values from the top
+  /// of the stack as it takes.
+  Call {
+    function: usize,
+  },
+  /// Like `Call`, but reuses the frame of the current function.
+  TailCall {
+    function: usize,
+  },
+  /// Calls the function value found below the top `arity` values of the
+  /// stack with them.
+  Apply {
+    arity: usize,
+  },
+  TailApply {
+    arity: usize,
+  },
   PutList,
   Nil,
+  /// Pushes the message under the mailbox cursor, or branches if there are no
+  /// messages left to look at.
+  PeekMessage {
+    branch: usize,
+  },
+  /// Removes the message under the cursor and rewinds the cursor.
+  RemoveMessage,
+  /// Moves the cursor to the next message and jumps back to `index`.
+  NextMessage {
+    index: usize,
+  },
+  /// Suspends the process until a new message arrives, then jumps to `index`.
+  Wait {
+    index: usize,
+  },
+  /// Installs a handler at `handler` for the exceptions raised before the
+  /// matching `EndTry`. The handler starts with the class and reason of the
+  /// exception on the stack.
+  Try {
+    handler: usize,
+  },
+  EndTry,
+  /// Raises an exception with the class and reason on top of the stack.
+  Raise,
+  /// Pops an atom and jumps to its case, or to `default` if it has none or
+  /// the value is not an atom. Cases are atom constants, sorted by name.
+  SwitchAtom {
+    cases: Vec<(u16, usize)>,
+    default: usize,
+  },
+  /// Like `SwitchAtom`, on numbers sorted by value.
+  SwitchNumber {
+    cases: Vec<(i32, usize)>,
+    default: usize,
+  },
+  /// Like `SwitchAtom`, on the size of tuples sorted by size.
+  SwitchTupleArity {
+    cases: Vec<(usize, usize)>,
+    default: usize,
+  },
   Undefined,
 }
 
+impl Bytecode {
+  /// The instructions the bytecode may jump to.
+  pub fn targets(&self) -> Vec<usize> {
+    match self {
+      Bytecode::TestExact { branch, .. }
+      | Bytecode::TestTuple { branch, .. }
+      | Bytecode::TestCons { branch }
+      | Bytecode::TestNil { branch }
+      | Bytecode::PeekMessage { branch }
+      | Bytecode::Jump { index: branch }
+      | Bytecode::NextMessage { index: branch }
+      | Bytecode::Wait { index: branch }
+      | Bytecode::Try { handler: branch } => vec![*branch],
+      Bytecode::SwitchAtom { cases, default } => switch_targets(cases, *default),
+      Bytecode::SwitchNumber { cases, default } => switch_targets(cases, *default),
+      Bytecode::SwitchTupleArity { cases, default } => switch_targets(cases, *default),
+      _ => vec![],
+    }
+  }
+
+  pub fn targets_mut(&mut self) -> Vec<&mut usize> {
+    match self {
+      Bytecode::TestExact { branch, .. }
+      | Bytecode::TestTuple { branch, .. }
+      | Bytecode::TestCons { branch }
+      | Bytecode::TestNil { branch }
+      | Bytecode::PeekMessage { branch }
+      | Bytecode::Jump { index: branch }
+      | Bytecode::NextMessage { index: branch }
+      | Bytecode::Wait { index: branch }
+      | Bytecode::Try { handler: branch } => vec![branch],
+      Bytecode::SwitchAtom { cases, default } => switch_targets_mut(cases, default),
+      Bytecode::SwitchNumber { cases, default } => switch_targets_mut(cases, default),
+      Bytecode::SwitchTupleArity { cases, default } => switch_targets_mut(cases, default),
+      _ => vec![],
+    }
+  }
+
+  /// Whether the bytecode may go on to the instruction after it.
+  pub fn falls_through(&self) -> bool {
+    !matches!(
+      self,
+      Bytecode::Return
+        | Bytecode::Jump { .. }
+        | Bytecode::MatchFail
+        | Bytecode::TailCall { .. }
+        | Bytecode::TailApply { .. }
+        | Bytecode::NextMessage { .. }
+        | Bytecode::Wait { .. }
+        | Bytecode::Raise
+        | Bytecode::SwitchAtom { .. }
+        | Bytecode::SwitchNumber { .. }
+        | Bytecode::SwitchTupleArity { .. }
+        | Bytecode::Undefined
+    )
+  }
+}
+
+fn switch_targets<K>(cases: &[(K, usize)], default: usize) -> Vec<usize> {
+  let cases = cases.iter().map(|(_, target)| *target);
+  std::iter::once(default).chain(cases).collect()
+}
+
+fn switch_targets_mut<'a, K>(
+  cases: &'a mut [(K, usize)],
+  default: &'a mut usize,
+) -> Vec<&'a mut usize> {
+  let cases = cases.iter_mut().map(|(_, target)| target);
+  std::iter::once(default).chain(cases).collect()
+}
+
+/// The atoms of the constants of `cases`, sorted by index for [`atom_case`].
+fn atom_cases(constants: &IndexMap<Constant, u16>, cases: &[(u16, usize)]) -> Vec<(Atom, usize)> {
+  let mut cases: Vec<_> = cases
+    .iter()
+    .map(|(id, target)| match constants.get_index(*id as usize) {
+      Some((Constant::Atom(atom), _)) => (*atom, *target),
+      _ => unreachable!("switch cases are verified to be atoms"),
+    })
+    .collect();
+  cases.sort_by_key(|(atom, _)| atom.index());
+  cases
+}
+
+/// The target of `atom` among cases sorted by [`atom_cases`], found by
+/// comparing indices rather than names.
+fn atom_case(cases: &[(Atom, usize)], atom: Atom) -> Option<usize> {
+  cases
+    .binary_search_by_key(&atom.index(), |(atom, _)| atom.index())
+    .ok()
+    .map(|idx| cases[idx].1)
+}
+
+/// The cases of every `SwitchAtom` of a module by ip, resolved once when the
+/// module is loaded.
+#[derive(Default)]
+struct AtomSwitches(HashMap<usize, Vec<(Atom, usize)>>);
+
+impl AtomSwitches {
+  fn new(info: &BytecodeInfo) -> Self {
+    let switches = info
+      .bytecode
+      .iter()
+      .enumerate()
+      .filter_map(|(ip, ins)| match ins {
+        Bytecode::SwitchAtom { cases, .. } => Some((ip, atom_cases(&info.constants, cases))),
+        _ => None,
+      });
+    AtomSwitches(switches.collect())
+  }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Constant {
   Number(i32),
-  Atom(String),
+  Atom(Atom),
   String(String),
 }
 
@@ -64,15 +280,64 @@ pub struct Ctx {
   bytecode: Vec<Bytecode>,
   constants: IndexMap<Constant, u16>,
   locals: HashMap<String, usize>,
+  next_local: usize,
+  functions: Vec<Function>,
+  function_ids: HashMap<String, usize>,
+  entry: Option<usize>,
 }
 
 const TEMP_BRANCH: usize = 0;
 
+/// What a case tree does when no pattern matches.
+enum Dispatch {
+  /// Fails with a match error, as in `case` and function clauses.
+  Case,
+  /// Moves on to the next message, recording the jumps to patch.
+  Receive { next: Vec<usize> },
+  /// Raises the caught exception, held in these locals, again.
+  Catch { class: usize, reason: usize },
+}
+
+/// An entry of the function table.
+#[derive(Debug)]
+pub struct Function {
+  /// Name of the definition, empty for an expression compiled on its own.
+  pub name: String,
+  pub arity: usize,
+  /// Index of the first instruction.
+  pub entry: usize,
+  /// Number of locals, the parameters being the first ones.
+  pub locals: usize,
+}
+
 #[derive(Debug)]
 pub struct BytecodeInfo {
   pub bytecode: Vec<Bytecode>,
-  pub locals: usize,
   pub constants: IndexMap<Constant, u16>,
+  pub functions: Vec<Function>,
+  /// The function [`Machine::run`] starts with.
+  pub entry: Option<usize>,
+  /// Path of the source the module was compiled from.
+  pub source: Option<String>,
+}
+
+impl BytecodeInfo {
+  pub fn function(&self, name: &str) -> Option<usize> {
+    self.functions.iter().position(|f| f.name == name)
+  }
+
+  /// The instructions of `function`, which span from its entry to the next
+  /// one.
+  pub fn code(&self, function: usize) -> std::ops::Range<usize> {
+    let entry = self.functions[function].entry;
+    let end = self
+      .functions
+      .iter()
+      .map(|f| f.entry)
+      .filter(|e| *e > entry)
+      .min();
+    entry..end.unwrap_or(self.bytecode.len())
+  }
 }
 
 impl Ctx {
@@ -80,28 +345,79 @@ impl Ctx {
     Self::default()
   }
 
+  /// Takes everything compiled so far. The entry is the last expression
+  /// compiled with [`Ctx::fn_clause`], or else `main/0`.
   pub fn bytecode(&mut self) -> BytecodeInfo {
     let bytecode = std::mem::take(&mut self.bytecode);
-    let locals = self.locals.len();
-    self.locals.clear();
     let constants = std::mem::take(&mut self.constants);
+    let functions = std::mem::take(&mut self.functions);
+    let main = self.function_ids.get("main").copied();
+    let main = main.filter(|id| functions[*id].arity == 0);
+    let entry = self.entry.take().or(main);
+    self.function_ids.clear();
     BytecodeInfo {
       bytecode,
-      locals,
       constants,
+      functions,
+      entry,
+      source: None,
     }
   }
 
-  pub fn fn_definition(&mut self, fun: desugar::FnDefinition) {
-    for param in fun.parameters {
-      self.make_local(param);
+  /// Compiles the prelude and the definitions of `program`, the latter
+  /// taking precedence.
+  pub fn program(&mut self, program: desugar::Program) {
+    let mut definitions = prelude::definitions();
+    definitions.extend(program.definitions);
+    for definition in definitions.values() {
+      self.declare(definition.name.clone(), definition.parameters.len());
     }
-    self.fn_clause(*fun.body);
+    for definition in definitions.into_values() {
+      self.fn_definition(definition);
+    }
+  }
+
+  /// Adds an entry to the function table, to be filled in once its body is
+  /// compiled.
+  fn declare(&mut self, name: String, arity: usize) -> usize {
+    let id = self.functions.len();
+    if !name.is_empty() {
+      self.function_ids.insert(name.clone(), id);
+    }
+    self.functions.push(Function {
+      name,
+      arity,
+      entry: TEMP_BRANCH,
+      locals: 0,
+    });
+    id
+  }
+
+  pub fn fn_definition(&mut self, fun: desugar::FnDefinition) {
+    let id = match self.function_ids.get(&fun.name) {
+      Some(id) => *id,
+      None => self.declare(fun.name, fun.parameters.len()),
+    };
+    self.function_body(id, fun.parameters, *fun.body);
   }
 
-  pub fn fn_clause(&mut self, expression: Expression) {
-    self.compile_expr(expression);
-    self.push(Bytecode::Return);
+  /// Compiles `expression` as a function without parameters, which becomes
+  /// the entry of the module.
+  pub fn fn_clause(&mut self, expression: Expression) -> usize {
+    let id = self.declare(String::new(), 0);
+    self.function_body(id, vec![], expression);
+    self.entry = Some(id);
+    id
+  }
+
+  fn function_body(&mut self, id: usize, parameters: Vec<String>, body: Expression) {
+    self.functions[id].entry = self.bytecode.len();
+    for param in parameters {
+      self.make_local(param);
+    }
+    self.compile_tail(body);
+    self.functions[id].locals = std::mem::take(&mut self.next_local);
+    self.locals.clear();
   }
 
   pub fn push(&mut self, bytecode: Bytecode) -> usize {
@@ -111,14 +427,37 @@ impl Ctx {
   }
 
   pub fn make_local(&mut self, name: String) -> usize {
-    let id = self.locals.len();
-    let e = self.locals.entry(name).or_default();
-    *e = id;
-    *e
+    let id = self.next_local;
+    self.next_local += 1;
+    self.locals.insert(name, id);
+    id
+  }
+
+  /// Binds `name` to a new local while `body` compiles, then gives it back
+  /// whatever it meant before, as bindings only reach the expression after
+  /// them.
+  fn with_local(&mut self, name: String, body: impl FnOnce(&mut Self, usize)) {
+    let id = self.next_local;
+    self.next_local += 1;
+    let shadowed = self.locals.insert(name.clone(), id);
+    body(self, id);
+    match shadowed {
+      Some(shadowed) => self.locals.insert(name, shadowed),
+      None => self.locals.remove(&name),
+    };
   }
 
   pub fn get_local(&mut self, name: &str) -> usize {
-    self.locals[name].clone()
+    self.locals[name]
+  }
+
+  /// Emits code raising a runtime error with message `err`.
+  fn fail(&mut self, err: String) {
+    let class = self.make_constant(Constant::Atom(Atom::new("error")));
+    let reason = self.make_constant(Constant::String(err));
+    self.push(Bytecode::LoadConstant { id: class });
+    self.push(Bytecode::LoadConstant { id: reason });
+    self.push(Bytecode::Raise);
   }
 
   fn make_constant(&mut self, constant: Constant) -> u16 {
@@ -132,13 +471,12 @@ impl Ctx {
 
   fn compile_occ(&mut self, occurrence: Occurrence) {
     self.compile_expr(occurrence.0);
-    for idx in occurrence.1.to_vec() {
+    for idx in occurrence.1 {
       match idx {
         desugar::Acc::Tup(idx) => _ = self.push(Bytecode::GetTuple { index: idx }),
         desugar::Acc::Head => _ = self.push(Bytecode::GetHd),
         desugar::Acc::Tail => _ = self.push(Bytecode::GetTl),
       }
-      // self.push(Bytecode::GetTuple { index: idx });
     }
   }
 
@@ -183,24 +521,89 @@ impl Ctx {
     tree: desugar::Tree,
     actions: Vec<Expression>,
     jumps: &mut Vec<usize>,
+    dispatch: &mut Dispatch,
+    tail: bool,
   ) {
     match tree {
-      desugar::Tree::Failure => {
-        self.push(Bytecode::MatchFail);
-      }
+      desugar::Tree::Failure => match dispatch {
+        Dispatch::Case => _ = self.push(Bytecode::MatchFail),
+        Dispatch::Receive { next } => {
+          next.push(self.push(Bytecode::Jump { index: TEMP_BRANCH }));
+        }
+        Dispatch::Catch { class, reason } => {
+          let (class, reason) = (*class, *reason);
+          self.push(Bytecode::GetLocal { id: class });
+          self.push(Bytecode::GetLocal { id: reason });
+          self.push(Bytecode::Raise);
+        }
+      },
       desugar::Tree::Leaf(index) => {
+        if let Dispatch::Receive { .. } = dispatch {
+          self.push(Bytecode::RemoveMessage);
+        }
         // TODO: don't repeat this compilation
-        self.compile_expr(actions[index].clone());
-        let idx = self.push(Bytecode::Jump { index: TEMP_BRANCH });
-        jumps.push(idx);
+        if tail {
+          self.compile_tail(actions[index].clone());
+        } else {
+          self.compile_expr(actions[index].clone());
+          let idx = self.push(Bytecode::Jump { index: TEMP_BRANCH });
+          jumps.push(idx);
+        }
+      }
+      desugar::Tree::Switch(occ, branches, default) if switchable(&branches) => {
+        self.compile_occ(*occ);
+        let switch = self.push(Bytecode::Undefined);
+        let mut cases = vec![];
+        for (cond, tree) in branches {
+          cases.push((cond, self.bytecode.len()));
+          self.compile_case_tree(tree, actions.clone(), jumps, dispatch, tail);
+        }
+        let default_case = self.bytecode.len();
+        self.compile_case_tree(*default, actions, jumps, dispatch, tail);
+        cases.sort();
+        self.bytecode[switch] = match cases[0].0 {
+          Cond::Atom(_) => {
+            let mut constants = vec![];
+            for (cond, target) in cases {
+              let Cond::Atom(atom) = cond else {
+                unreachable!()
+              };
+              constants.push((self.make_constant(Constant::Atom(atom)), target));
+            }
+            Bytecode::SwitchAtom {
+              cases: constants,
+              default: default_case,
+            }
+          }
+          Cond::Number(_) => Bytecode::SwitchNumber {
+            cases: cases
+              .into_iter()
+              .map(|(cond, target)| match cond {
+                Cond::Number(n) => (n, target),
+                _ => unreachable!(),
+              })
+              .collect(),
+            default: default_case,
+          },
+          _ => Bytecode::SwitchTupleArity {
+            cases: cases
+              .into_iter()
+              .map(|(cond, target)| match cond {
+                Cond::Tuple(size) => (size, target),
+                _ => unreachable!(),
+              })
+              .collect(),
+            default: default_case,
+          },
+        };
       }
       desugar::Tree::Switch(occ, branches, default) => {
         let mut branches = branches.into_iter().peekable();
         while let Some((cond, tree)) = branches.next() {
           self.compile_occ(*occ.clone());
           let cond_location = self.compile_cond(cond);
-          self.compile_case_tree(tree, actions.clone(), jumps);
-          if let Some(_) = branches.peek() {
+          self.compile_case_tree(tree, actions.clone(), jumps, dispatch, tail);
+          if branches.peek().is_some() {
             let len = self.bytecode.len();
             match &mut self.bytecode[cond_location] {
               Bytecode::TestExact { branch, .. }
@@ -213,7 +616,7 @@ impl Ctx {
             }
           } else {
             let len = self.bytecode.len();
-            self.compile_case_tree(*default.clone(), actions.clone(), jumps);
+            self.compile_case_tree(*default.clone(), actions.clone(), jumps, dispatch, tail);
             match &mut self.bytecode[cond_location] {
               Bytecode::TestExact { branch, .. }
               | Bytecode::TestTuple { branch, .. }
@@ -229,11 +632,145 @@ impl Ctx {
     }
   }
 
+  fn patch_jumps(&mut self, jumps: Vec<usize>, target: usize) {
+    for idx in jumps {
+      let Bytecode::Jump { index } = &mut self.bytecode[idx] else {
+        unreachable!()
+      };
+      *index = target;
+    }
+  }
+
+  /// Compiles `expression` in tail position, returning its value from the
+  /// current function.
+  fn compile_tail(&mut self, expression: Expression) {
+    match expression {
+      Expression::Let { bind, value, next } => {
+        self.compile_expr(*value);
+        self.with_local(bind, |ctx, id| {
+          ctx.push(Bytecode::SetLocal { id });
+          ctx.compile_tail(*next);
+        });
+      }
+      Expression::Match { tree, actions } => {
+        let mut jumps = Vec::new();
+        self.compile_case_tree(tree, actions, &mut jumps, &mut Dispatch::Case, true);
+      }
+      Expression::If {
+        condition,
+        then_branch,
+        else_branch,
+      } => self.compile_if(*condition, *then_branch, *else_branch, true),
+      Expression::Call { callee, arguments } => {
+        if !self.compile_call(*callee, arguments, true) {
+          self.push(Bytecode::Return);
+        }
+      }
+      expression => {
+        self.compile_expr(expression);
+        self.push(Bytecode::Return);
+      }
+    }
+  }
+
+  /// Compiles an `if`. As in eval, any condition other than `#true` takes the
+  /// else branch. In tail position both branches return on their own.
+  fn compile_if(&mut self, condition: Expression, then: Expression, other: Expression, tail: bool) {
+    self.compile_expr(condition);
+    let id = self.make_constant(Constant::Atom(Atom::TRUE));
+    let test = self.push(Bytecode::TestExact {
+      id,
+      branch: TEMP_BRANCH,
+    });
+    let jump = if tail {
+      self.compile_tail(then);
+      None
+    } else {
+      self.compile_expr(then);
+      Some(self.push(Bytecode::Jump { index: TEMP_BRANCH }))
+    };
+    let len = self.bytecode.len();
+    let Bytecode::TestExact { branch, .. } = &mut self.bytecode[test] else {
+      unreachable!()
+    };
+    *branch = len;
+    if tail {
+      self.compile_tail(other);
+    } else {
+      self.compile_expr(other);
+    }
+    if let Some(jump) = jump {
+      let len = self.bytecode.len();
+      let Bytecode::Jump { index } = &mut self.bytecode[jump] else {
+        unreachable!()
+      };
+      *index = len;
+    }
+  }
+
+  /// Compiles a call, returning whether it was compiled as a tail call.
+  /// Calls that eval rejects at runtime are compiled to code raising the
+  /// same error, and calls to unknown names are left to the natives of the
+  /// host.
+  fn compile_call(&mut self, callee: Expression, arguments: Vec<Expression>, tail: bool) -> bool {
+    let arity = arguments.len();
+    if let Expression::Variable { ref name } = callee {
+      if !self.locals.contains_key(name) {
+        let function = self.function_ids.get(name).copied();
+        if let Some(function) = function.filter(|f| self.functions[*f].arity == arity) {
+          for argument in arguments {
+            self.compile_expr(argument);
+          }
+          if tail {
+            self.push(Bytecode::TailCall { function });
+          } else {
+            self.push(Bytecode::Call { function });
+          }
+          return tail;
+        }
+        if function.is_none() {
+          let Some(builtin) = Builtin::from_name(name, arity) else {
+            let id = self.make_constant(Constant::Atom(Atom::new(name)));
+            for argument in arguments {
+              self.compile_expr(argument);
+            }
+            self.push(Bytecode::CallNative { id, arity });
+            return false;
+          };
+          for argument in arguments {
+            self.compile_expr(argument);
+          }
+          if let Err(err) = builtin.check_arity(arity) {
+            self.fail(err);
+          } else {
+            self.push(Bytecode::CallBuiltin { builtin });
+          }
+          return false;
+        }
+      }
+    }
+    self.compile_expr(callee);
+    for argument in arguments {
+      self.compile_expr(argument);
+    }
+    if tail {
+      self.push(Bytecode::TailApply { arity });
+    } else {
+      self.push(Bytecode::Apply { arity });
+    }
+    tail
+  }
+
   pub fn compile_expr(&mut self, expression: Expression) {
     match expression {
       Expression::Variable { ref name } => {
-        let id = self.get_local(name);
-        self.push(Bytecode::GetLocal { id });
+        if let Some(&id) = self.locals.get(name) {
+          self.push(Bytecode::GetLocal { id });
+        } else if let Some(&function) = self.function_ids.get(name) {
+          self.push(Bytecode::LoadFunction { function });
+        } else {
+          self.fail(format!("Unbound variable {name}"));
+        }
       }
       Expression::Number { value } => {
         self.push(Bytecode::PushNumber { val: value });
@@ -248,20 +785,72 @@ impl Ctx {
       }
       Expression::Let { bind, value, next } => {
         self.compile_expr(*value);
-        let id = self.make_local(bind);
-        self.push(Bytecode::SetLocal { id });
-        self.compile_expr(*next);
+        self.with_local(bind, |ctx, id| {
+          ctx.push(Bytecode::SetLocal { id });
+          ctx.compile_expr(*next);
+        });
       }
       Expression::Match { tree, actions } => {
         let mut jumps = Vec::new();
-        self.compile_case_tree(tree, actions, &mut jumps);
+        self.compile_case_tree(tree, actions, &mut jumps, &mut Dispatch::Case, false);
         let next_bytecode = self.bytecode.len();
-        for idx in jumps {
-          let Bytecode::Jump { index } = &mut self.bytecode[idx] else {
-            unreachable!()
-          };
-          *index = next_bytecode;
-        }
+        self.patch_jumps(jumps, next_bytecode);
+      }
+      Expression::Receive {
+        name,
+        tree,
+        actions,
+      } => {
+        let peek = self.push(Bytecode::PeekMessage {
+          branch: TEMP_BRANCH,
+        });
+        let mut jumps = Vec::new();
+        let mut dispatch = Dispatch::Receive { next: vec![] };
+        self.with_local(name, |ctx, id| {
+          ctx.push(Bytecode::SetLocal { id });
+          ctx.compile_case_tree(tree, actions, &mut jumps, &mut dispatch, false);
+        });
+        let next_message = self.push(Bytecode::NextMessage { index: peek });
+        let wait = self.push(Bytecode::Wait { index: peek });
+        let Bytecode::PeekMessage { branch } = &mut self.bytecode[peek] else {
+          unreachable!()
+        };
+        *branch = wait;
+        let Dispatch::Receive { next } = dispatch else {
+          unreachable!()
+        };
+        self.patch_jumps(next, next_message);
+        let next_bytecode = self.bytecode.len();
+        self.patch_jumps(jumps, next_bytecode);
+      }
+      Expression::Try {
+        body,
+        class,
+        reason,
+        tree,
+        actions,
+      } => {
+        let install = self.push(Bytecode::Try {
+          handler: TEMP_BRANCH,
+        });
+        self.compile_expr(*body);
+        self.push(Bytecode::EndTry);
+        let mut jumps = vec![self.push(Bytecode::Jump { index: TEMP_BRANCH })];
+        let handler = self.bytecode.len();
+        let Bytecode::Try { handler: target } = &mut self.bytecode[install] else {
+          unreachable!()
+        };
+        *target = handler;
+        self.with_local(class, |ctx, class| {
+          ctx.with_local(reason, |ctx, reason| {
+            ctx.push(Bytecode::SetLocal { id: reason });
+            ctx.push(Bytecode::SetLocal { id: class });
+            let mut dispatch = Dispatch::Catch { class, reason };
+            ctx.compile_case_tree(tree, actions, &mut jumps, &mut dispatch, false);
+          });
+        });
+        let next_bytecode = self.bytecode.len();
+        self.patch_jumps(jumps, next_bytecode);
       }
       Expression::Tuple { elements } => {
         let size = elements.len();
@@ -270,33 +859,24 @@ impl Ctx {
         }
         self.push(Bytecode::MakeTuple { size });
       }
-      Expression::Binary {
-        op: _,
-        lhs: _,
-        rhs: _,
-      } => todo!(),
-      Expression::Call {
-        callee: _,
-        arguments: _,
-      } => todo!(),
+      Expression::Binary { op, lhs, rhs } => {
+        self.compile_expr(*lhs);
+        self.compile_expr(*rhs);
+        self.push(match op {
+          Operation::Add => Bytecode::Add,
+          Operation::Sub => Bytecode::Sub,
+          Operation::Mul => Bytecode::Mul,
+          Operation::Div => Bytecode::Div,
+          Operation::Equal => Bytecode::Eq,
+        });
+      }
+      Expression::Call { callee, arguments } => _ = self.compile_call(*callee, arguments, false),
       Expression::If {
         condition,
         then_branch,
         else_branch,
       } => {
-        self.compile_expr(*condition);
-
-        let id = self.make_constant(Constant::Atom("true".to_string()));
-        self.push(Bytecode::LoadConstant { id });
-        let branch = self.push(Bytecode::TestExact { id, branch: 0 });
-
-        self.compile_expr(*else_branch);
-        let len = self.bytecode.len();
-        let Bytecode::TestExact { branch, .. } = &mut self.bytecode[branch] else {
-          unreachable!()
-        };
-        *branch = len;
-        self.compile_expr(*then_branch);
+        self.compile_if(*condition, *then_branch, *else_branch, false);
       }
       Expression::Access { expr, idx } => {
         self.compile_expr(*expr);
@@ -312,157 +892,713 @@ impl Ctx {
         self.push(Bytecode::PutList);
       }
       Expression::Nil => _ = self.push(Bytecode::Nil),
-      // Expression::Nil => _ = self.push(Bytecode::Undefined),
     }
   }
 }
 
-#[derive(Clone, Debug)]
-pub enum Value {
-  Number(i32),
-  Tuple(Vec<Value>),
-  Atom(String),
-  String(String),
-  ConsList(Box<Value>, Box<Value>),
-  NilList,
+pub struct Machine<'a> {
+  info: &'a BytecodeInfo,
+  switches: Rc<AtomSwitches>,
+  host: Host,
+  heap_limit: Option<usize>,
+  tracer: Option<Rc<RefCell<dyn Tracer>>>,
+  gc_stress: bool,
+  gc_stats: Rc<Cell<Stats>>,
+  scheduler: Scheduler<Task<'a>>,
 }
 
-impl Default for Value {
-  fn default() -> Self {
-    Self::Number(0)
+impl<'a> Machine<'a> {
+  pub fn new(info: &'a BytecodeInfo) -> Self {
+    Self {
+      info,
+      switches: Rc::new(AtomSwitches::new(info)),
+      host: Host::default(),
+      heap_limit: None,
+      tracer: None,
+      gc_stress: false,
+      gc_stats: Rc::default(),
+      scheduler: Scheduler::new(),
+    }
+  }
+
+  pub fn with_host(mut self, host: Host) -> Self {
+    self.host = host;
+    self
+  }
+
+  /// Gives back the host, once the machine is done with it.
+  pub fn into_host(self) -> Host {
+    self.host
+  }
+
+  /// Limits the values each process holds to `bytes`, raising a
+  /// `#system_limit` error in processes that go over.
+  pub fn with_heap_limit(mut self, bytes: usize) -> Self {
+    self.heap_limit = Some(bytes);
+    self
+  }
+
+  /// Reports what every process does to `tracer`. This slows runs down a
+  /// lot: every call copies its arguments out of the heap of the process for
+  /// the tracer, and so does every instruction with the top
+  /// [`trace::STACK_TOP`] values of the stack.
+  pub fn with_tracer(mut self, tracer: Rc<RefCell<dyn Tracer>>) -> Self {
+    self.tracer = Some(tracer);
+    self
+  }
+
+  /// Collects the heap of processes after every allocation, to shake out
+  /// values the collector misses.
+  pub fn with_gc_stress(mut self) -> Self {
+    self.gc_stress = true;
+    self
+  }
+
+  /// What the collections of every process did so far.
+  pub fn gc_stats(&self) -> Stats {
+    self.gc_stats.get()
+  }
+
+  /// Runs the entry of the module in a new process until it returns.
+  pub fn run(&mut self) -> Result<Value, String> {
+    let entry = self.info.entry.ok_or("The module has no entry")?;
+    self.apply(entry, vec![]).map_err(|crash| crash.to_string())
+  }
+
+  /// Calls the function `name` with `arguments` in a new process until it
+  /// returns.
+  pub fn call(&mut self, name: &str, arguments: Vec<Value>) -> Result<Value, String> {
+    let info = self.info;
+    let function = info
+      .function(name)
+      .ok_or(format!("Unbound variable {name}"))?;
+    let arity = info.functions[function].arity;
+    if arity != arguments.len() {
+      let len = arguments.len();
+      return Err(format!("Expected {arity} arguments but got {len}"));
+    }
+    self
+      .apply(function, arguments)
+      .map_err(|crash| crash.to_string())
+  }
+
+  /// Calls the function at index `function`, which must take as many
+  /// `arguments` as given, and returns how its process ended.
+  pub fn apply(&mut self, function: usize, arguments: Vec<Value>) -> Outcome<Value> {
+    let pid = self.spawn(function, arguments);
+    self.scheduler.outcome(&mut self.host, pid)
+  }
+
+  /// Like [`Machine::run`], but gives up once the processes have taken `fuel`
+  /// reductions, leaving the run to be resumed.
+  pub fn run_with_fuel(&mut self, fuel: usize) -> Evaluation<Value> {
+    let Some(entry) = self.info.entry else {
+      return Evaluation::Done(Err("The module has no entry".to_string()));
+    };
+    let pid = self.spawn(entry, vec![]);
+    self.scheduler.run_for(&mut self.host, pid, Some(fuel))
+  }
+
+  fn spawn(&mut self, function: usize, arguments: Vec<Value>) -> Pid {
+    let task = Task::call(self.info, self.switches.clone(), function, arguments)
+      .with_heap_limit(self.heap_limit)
+      .with_tracer(self.tracer.clone())
+      .with_gc(self.gc_stress, self.gc_stats.clone());
+    self.scheduler.spawn(task)
+  }
+
+  /// Continues a run that ran out of fuel with `fuel` more reductions.
+  pub fn resume(&mut self, suspended: Suspended, fuel: Option<usize>) -> Evaluation<Value> {
+    self.scheduler.run_for(&mut self.host, suspended.0, fuel)
   }
 }
 
-pub struct Machine<'a> {
-  code: &'a [Bytecode],
-  ip: RefCell<usize>,
-  constants: Vec<Constant>,
+/// A process of the VM.
+pub struct Task<'a> {
+  info: &'a BytecodeInfo,
+  ip: usize,
+  stack: Vec<Word>,
+  locals: Vec<Word>,
+  /// The callers of the running function.
+  frames: Vec<Frame>,
+  /// Index of the next message `PeekMessage` looks at.
+  cursor: usize,
+  handlers: Vec<Handler>,
+  /// Where the tuples, cons cells and strings of the process live.
+  memory: gc::Heap,
+  /// Where collections add up what they did, along with those of other
+  /// processes.
+  stats: Rc<Cell<Stats>>,
+  /// Bytes of values the process may hold.
+  heap_limit: Option<usize>,
+  tracer: Option<Rc<RefCell<dyn Tracer>>>,
+  /// The function the task starts with, until its call is traced.
+  start: Option<usize>,
+  /// The atom switches of `info`, shared with the machine.
+  switches: Rc<AtomSwitches>,
 }
 
-impl<'a> Machine<'a> {
-  pub fn new(info: &'a BytecodeInfo) -> Self {
+/// Where to continue once the running function returns.
+struct Frame {
+  ip: usize,
+  locals: Vec<Word>,
+}
+
+/// An exception handler installed by `Try`.
+struct Handler {
+  ip: usize,
+  /// Height of the stack when the handler was installed.
+  stack: usize,
+  /// Number of frames when the handler was installed.
+  frames: usize,
+}
+
+impl<'a> Task<'a> {
+  /// A task that calls entry `function` of the function table with
+  /// `arguments`, dispatching on atoms with the `switches` of `info`.
+  fn call(
+    info: &'a BytecodeInfo,
+    switches: Rc<AtomSwitches>,
+    function: usize,
+    arguments: Vec<Value>,
+  ) -> Self {
+    let Function { entry, locals, .. } = info.functions[function];
+    let mut memory = gc::Heap::new(false);
+    let mut arguments: Vec<_> = arguments.iter().map(|v| memory.import(v)).collect();
+    arguments.resize(locals, Word::default());
     Self {
-      code: &info.bytecode,
-      ip: RefCell::new(0),
-      constants: info.constants.keys().cloned().collect(),
+      info,
+      ip: entry,
+      stack: vec![],
+      locals: arguments,
+      frames: vec![],
+      cursor: 0,
+      handlers: vec![],
+      memory,
+      stats: Rc::default(),
+      heap_limit: None,
+      tracer: None,
+      start: Some(function),
+      switches,
+    }
+  }
+
+  pub fn with_heap_limit(mut self, limit: Option<usize>) -> Self {
+    self.heap_limit = limit;
+    self
+  }
+
+  pub fn with_tracer(mut self, tracer: Option<Rc<RefCell<dyn Tracer>>>) -> Self {
+    self.tracer = tracer;
+    self
+  }
+
+  /// Collects after every allocation if `stress` is set, and adds up what the
+  /// collections do in `stats`.
+  pub fn with_gc(mut self, stress: bool, stats: Rc<Cell<Stats>>) -> Self {
+    self.memory.set_stress(stress);
+    self.stats = stats;
+    self
+  }
+
+  /// Collects the heap if it is due or over the limit, raising
+  /// `#system_limit` if what is left is still over.
+  fn safepoint(&mut self, mailbox: &VecDeque<Value>) -> Result<(), Crash<Value>> {
+    let over = |size| self.heap_limit.is_some_and(|limit| size > limit);
+    if !self.memory.due() && !over(self.memory.size()) {
+      return Ok(());
+    }
+    let frames = self.frames.iter_mut().flat_map(|frame| &mut frame.locals);
+    let roots = self.stack.iter_mut().chain(&mut self.locals).chain(frames);
+    let stats = self.memory.collect(roots);
+    self.stats.set(self.stats.get() + stats);
+    if over(self.memory.size() + live_size(mailbox)) {
+      return Err(Crash::Raise(Value::atom("system_limit")));
     }
+    Ok(())
   }
 
-  fn load_constant(&self, id: u16) -> Value {
-    let c = self.constants[id as usize].clone();
+  fn export(&self, words: &[Word]) -> Vec<Value> {
+    words.iter().map(|word| self.memory.export(word)).collect()
+  }
+
+  fn atom(&self, id: u16) -> Atom {
+    match self.info.constants.get_index(id as usize) {
+      Some((Constant::Atom(atom), _)) => *atom,
+      _ => unreachable!(),
+    }
+  }
+
+  fn load_constant(&mut self, id: u16) -> Word {
+    let (c, _) = self.info.constants.get_index(id as usize).unwrap();
     match c {
-      Constant::Number(n) => Value::Number(n),
-      Constant::Atom(a) => Value::Atom(a),
-      Constant::String(s) => Value::String(s),
+      Constant::Number(n) => Word::Number(*n),
+      Constant::Atom(a) => Word::Atom(*a),
+      Constant::String(s) => self.memory.string(s.as_str().into()),
     }
   }
 
-  pub fn run(&mut self, stack: &mut Vec<Value>, mut locals: Vec<Value>) {
-    loop {
-      let ins = self.fetch();
-      println!("ins = {ins:?}");
-      match ins {
-        Bytecode::Return => break,
-        Bytecode::PushNumber { val } => {
-          stack.push(Value::Number(*val));
+  fn call_builtin(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+    builtin: Builtin,
+    arguments: Vec<Value>,
+  ) -> Result<Value, Crash<Value>> {
+    match builtin {
+      Builtin::Spawn | Builtin::SpawnLink => {
+        builtin.check_arity(arguments.len())?;
+        let mut arguments = arguments.into_iter();
+        let function = arguments.next().unwrap();
+        let Some(arguments) = arguments.next().unwrap().to_vec() else {
+          return Err(Crash::Error(format!("{}: bad argument", builtin.name())));
+        };
+        let function = callee(self.info, function, arguments.len())?;
+        let task = Task::call(self.info, self.switches.clone(), function, arguments)
+          .with_heap_limit(self.heap_limit)
+          .with_tracer(self.tracer.clone())
+          .with_gc(self.memory.stress(), self.stats.clone());
+        let pid = ctx.spawn(task);
+        if builtin == Builtin::SpawnLink {
+          ctx.link(pid);
         }
-        Bytecode::LoadConstant { id } => {
-          let c = self.load_constant(*id);
-          stack.push(c);
+        Ok(Value::Pid(pid))
+      }
+      _ if builtin.is_process() => ctx.call_builtin(builtin, arguments),
+      _ => Ok(builtin.apply(host, arguments)?),
+    }
+  }
+
+  /// Enters `function` with `arguments`, returning to the current function
+  /// afterwards unless it is a tail call.
+  fn enter(&mut self, pid: Pid, function: usize, mut arguments: Vec<Word>, tail: bool) {
+    let Function { entry, locals, .. } = self.info.functions[function];
+    if let Some(tracer) = &self.tracer {
+      let arguments = self.export(&arguments);
+      tracer
+        .borrow_mut()
+        .call(pid, self.info, function, &arguments);
+    }
+    arguments.resize(locals, Word::default());
+    let locals = std::mem::replace(&mut self.locals, arguments);
+    if !tail {
+      self.frames.push(Frame {
+        ip: self.ip,
+        locals,
+      });
+    }
+    self.ip = entry;
+  }
+
+  fn execute(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+    reductions: &mut usize,
+  ) -> Result<Status<Value>, Crash<Value>> {
+    if let (Some(tracer), Some(function)) = (&self.tracer, self.start.take()) {
+      let arity = self.info.functions[function].arity;
+      let arguments = self.export(&self.locals[..arity]);
+      tracer
+        .borrow_mut()
+        .call(ctx.pid, self.info, function, &arguments);
+    }
+    while *reductions > 0 {
+      *reductions -= 1;
+      match self.step(host, ctx) {
+        Ok(None) => (),
+        Ok(Some(status)) => return Ok(status),
+        Err(crash) => self.catch(crash)?,
+      }
+    }
+    Ok(Status::Yielded)
+  }
+
+  /// Jumps to the innermost handler with the class and reason of `crash` on
+  /// the stack, or gives `crash` back if there is none.
+  fn catch(&mut self, crash: Crash<Value>) -> Result<(), Crash<Value>> {
+    let Some(handler) = self.handlers.pop() else {
+      return Err(crash);
+    };
+    self.stack.truncate(handler.stack);
+    if self.frames.len() > handler.frames {
+      self.locals = std::mem::take(&mut self.frames[handler.frames].locals);
+      self.frames.truncate(handler.frames);
+    }
+    self.stack.push(Word::Atom(Atom::new(crash.class())));
+    let reason = self.memory.import(&crash.value());
+    self.stack.push(reason);
+    self.ip = handler.ip;
+    Ok(())
+  }
+
+  fn step(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+  ) -> Result<Option<Status<Value>>, Crash<Value>> {
+    self.safepoint(ctx.mailbox)?;
+    if let Some(tracer) = &self.tracer {
+      let top = self.stack.len().saturating_sub(trace::STACK_TOP);
+      let stack = self.export(&self.stack[top..]);
+      tracer
+        .borrow_mut()
+        .instruction(ctx.pid, self.info, self.ip, &stack);
+    }
+    let ins = self.fetch();
+    match ins {
+      Bytecode::Return => {
+        let word = self
+          .stack
+          .pop()
+          .ok_or("Return with an empty stack".to_string())?;
+        if let Some(tracer) = &self.tracer {
+          let value = self.memory.export(&word);
+          tracer.borrow_mut().returned(ctx.pid, &value);
         }
-        Bytecode::GetLocal { id } => {
-          let a = locals[*id].clone();
-          stack.push(a);
+        let Some(frame) = self.frames.pop() else {
+          return Ok(Some(Status::Exited(Ok(self.memory.export(&word)))));
+        };
+        self.ip = frame.ip;
+        self.locals = frame.locals;
+        self.stack.push(word);
+      }
+      Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::Div | Bytecode::Eq => {
+        let rhs = self.stack.pop().unwrap();
+        let lhs = self.stack.pop().unwrap();
+        let result = match (ins, lhs, rhs) {
+          (Bytecode::Eq, x, y) => Word::Atom(Atom::boolean(self.memory.equal(&x, &y))),
+          (_, Word::Number(a), Word::Number(b)) => Word::Number(arithmetic(ins, a, b)?),
+          _ => return Err(Crash::Error("Invalid binary operation.".to_string())),
+        };
+        self.stack.push(result);
+      }
+      Bytecode::LoadFunction { function } => {
+        let arity = self.info.functions[*function].arity;
+        self.stack.push(Word::Function {
+          id: *function,
+          arity,
+        });
+      }
+      Bytecode::Call { function } | Bytecode::TailCall { function } => {
+        let at = self.stack.len() - self.info.functions[*function].arity;
+        let arguments = self.stack.drain(at..).collect();
+        self.enter(
+          ctx.pid,
+          *function,
+          arguments,
+          matches!(ins, Bytecode::TailCall { .. }),
+        );
+      }
+      Bytecode::Apply { arity } | Bytecode::TailApply { arity } => {
+        let at = self.stack.len() - arity;
+        let arguments: Vec<_> = self.stack.drain(at..).collect();
+        let function = self.stack.pop().unwrap();
+        let function = callee(self.info, self.memory.export(&function), *arity)?;
+        self.enter(
+          ctx.pid,
+          function,
+          arguments,
+          matches!(ins, Bytecode::TailApply { .. }),
+        );
+      }
+      Bytecode::PushNumber { val } => {
+        self.stack.push(Word::Number(*val));
+      }
+      Bytecode::LoadConstant { id } => {
+        let c = self.load_constant(*id);
+        self.stack.push(c);
+      }
+      Bytecode::GetLocal { id } => {
+        self.stack.push(self.locals[*id]);
+      }
+      Bytecode::SetLocal { id } => {
+        let a = self.stack.pop().unwrap();
+        self.locals[*id] = a;
+      }
+      Bytecode::TestExact { id, branch } => {
+        let word = self.stack.pop().unwrap();
+        let (c, _) = self.info.constants.get_index(*id as usize).unwrap();
+        match (&word, c) {
+          (Word::Number(a), Constant::Number(b)) if a == b => {}
+          (Word::Atom(a), Constant::Atom(b)) if a == b => {}
+          (Word::String(_), Constant::String(b)) if self.memory.str(&word) == Some(b) => {}
+          _ => self.ip = *branch,
         }
-        Bytecode::SetLocal { id } => {
-          let a = stack.pop().unwrap();
-          locals[*id] = a;
+      }
+      Bytecode::TestTuple { size, branch } => {
+        let word = self.stack.pop().unwrap();
+        match self.memory.elements(&word) {
+          Some(elements) if elements.len() == *size => {}
+          _ => self.ip = *branch,
         }
-        Bytecode::TestExact { id, branch } => {
-          match (stack.pop().unwrap(), self.load_constant(*id)) {
-            (Value::Number(a), Value::Number(b)) if a == b => {}
-            (Value::Atom(ref a), Value::Atom(ref b)) if a == b => {}
-            (Value::String(ref a), Value::String(ref b)) if a == b => {}
-            _ => *self.ip.borrow_mut() = *branch,
+      }
+      Bytecode::TestCons { branch } => match self.stack.pop().unwrap() {
+        Word::Cons(_) => {}
+        _ => self.ip = *branch,
+      },
+      Bytecode::TestNil { branch } => match self.stack.pop().unwrap() {
+        Word::Nil => {}
+        _ => self.ip = *branch,
+      },
+      Bytecode::MakeTuple { size } => {
+        let at = self.stack.len() - size;
+        let tuple = self.memory.tuple(self.stack.drain(at..));
+        self.stack.push(tuple);
+      }
+      Bytecode::GetTuple { index } => {
+        // Only bytecode that skipped the tests of a case tree gets these wrong.
+        let word = self.stack.pop().unwrap();
+        let element = self
+          .memory
+          .elements(&word)
+          .and_then(|mut elements| elements.nth(*index))
+          .cloned();
+        self
+          .stack
+          .push(element.ok_or("GetTuple: bad argument".to_string())?);
+      }
+      Bytecode::Dup => {
+        let word = *self.stack.last().unwrap();
+        self.stack.push(word);
+      }
+      Bytecode::Jump { index } => self.ip = *index,
+      Bytecode::MatchFail => return Err(Crash::Error("Match failure".to_string())),
+      Bytecode::GetHd => {
+        let word = self.stack.pop().unwrap();
+        let hd = self.memory.hd(&word).cloned();
+        self
+          .stack
+          .push(hd.ok_or("GetHd: bad argument".to_string())?);
+      }
+      Bytecode::GetTl => {
+        let word = self.stack.pop().unwrap();
+        let tl = self.memory.tl(&word).cloned();
+        self
+          .stack
+          .push(tl.ok_or("GetTl: bad argument".to_string())?);
+      }
+      Bytecode::PutList => {
+        let tl = self.stack.pop().unwrap();
+        let hd = self.stack.pop().unwrap();
+        let cons = self.memory.cons(hd, tl);
+        self.stack.push(cons);
+      }
+      Bytecode::Nil => {
+        self.stack.push(Word::Nil);
+      }
+      Bytecode::CallBuiltin { builtin } => {
+        let at = self.stack.len() - builtin.arity();
+        let result = match heap_builtin(&mut self.memory, *builtin, &self.stack[at..]) {
+          Some(result) => {
+            self.stack.truncate(at);
+            result?
           }
-        }
-        Bytecode::TestTuple { size, branch } => match (stack.pop().unwrap(), *size) {
-          (Value::Tuple(x), y) if x.len() == y => {}
-          _ => *self.ip.borrow_mut() = *branch,
-        },
-        Bytecode::TestCons { branch } => match stack.pop().unwrap() {
-          Value::ConsList(..) => {}
-          _ => *self.ip.borrow_mut() = *branch,
-        },
-        Bytecode::TestNil { branch } => match stack.pop().unwrap() {
-          Value::NilList => {}
-          _ => *self.ip.borrow_mut() = *branch,
-        },
-        Bytecode::MakeTuple { size } => {
-          let mut s = Vec::with_capacity(*size);
-          for _ in 0..*size {
-            s.insert(0, stack.pop().unwrap());
+          None => {
+            let arguments = self.export(&self.stack[at..]);
+            self.stack.truncate(at);
+            let result = self.call_builtin(host, ctx, *builtin, arguments)?;
+            self.memory.import(&result)
           }
-          stack.push(Value::Tuple(s));
-        }
-        Bytecode::GetTuple { index } => {
-          let Value::Tuple(t) = stack.pop().unwrap() else {
-            unreachable!()
-          };
-          stack.push(t[*index].clone());
-        }
-        Bytecode::Jump { index } => *self.ip.borrow_mut() = *index,
-        Bytecode::MatchFail => panic!("Match failure"),
-        Bytecode::GetHd => {
-          let Value::ConsList(hd, _) = stack.pop().unwrap() else {
-            unreachable!()
-          };
-          stack.push(*hd);
-        }
-        Bytecode::GetTl => {
-          let Value::ConsList(_, tl) = stack.pop().unwrap() else {
-            unreachable!()
-          };
-          stack.push(*tl);
-        }
-        Bytecode::PutList => {
-          let tl = stack.pop().unwrap();
-          let hd = stack.pop().unwrap();
-          stack.push(Value::ConsList(Box::new(hd), Box::new(tl)));
-        }
-        Bytecode::Nil => {
-          stack.push(Value::NilList);
+        };
+        self.stack.push(result);
+      }
+      Bytecode::CallNative { id, arity } => {
+        let at = self.stack.len() - arity;
+        let arguments = self.export(&self.stack[at..]);
+        self.stack.truncate(at);
+        let result = host.natives.call(self.atom(*id).as_str(), arguments)?;
+        let result = self.memory.import(&result);
+        self.stack.push(result);
+      }
+      Bytecode::PeekMessage { branch } => match ctx.mailbox.get(self.cursor) {
+        Some(message) => {
+          let message = self.memory.import(message);
+          self.stack.push(message);
         }
-        Bytecode::Undefined => todo!(),
+        None => self.ip = *branch,
+      },
+      Bytecode::RemoveMessage => {
+        ctx.mailbox.remove(self.cursor);
+        self.cursor = 0;
+      }
+      Bytecode::NextMessage { index } => {
+        self.cursor += 1;
+        self.ip = *index;
+      }
+      Bytecode::Wait { index } => {
+        self.ip = *index;
+        return Ok(Some(Status::Waiting));
+      }
+      Bytecode::Try { handler } => {
+        let (stack, frames) = (self.stack.len(), self.frames.len());
+        self.handlers.push(Handler {
+          ip: *handler,
+          stack,
+          frames,
+        });
       }
+      Bytecode::EndTry => _ = self.handlers.pop(),
+      Bytecode::Raise => {
+        let reason = self.stack.pop().unwrap();
+        let class = self.stack.pop().unwrap();
+        let Word::Atom(class) = class else {
+          return Err(Crash::Error("Raise: bad class".to_string()));
+        };
+        return Err(Crash::raise(class.as_str(), self.memory.export(&reason)));
+      }
+      Bytecode::SwitchAtom { default, .. } => {
+        let case = match self.stack.pop().unwrap() {
+          Word::Atom(atom) => atom_case(&self.switches.0[&(self.ip - 1)], atom),
+          _ => None,
+        };
+        self.ip = case.unwrap_or(*default);
+      }
+      Bytecode::SwitchNumber { cases, default } => {
+        let case = match self.stack.pop().unwrap() {
+          Word::Number(n) => cases.binary_search_by_key(&n, |(n, _)| *n).ok(),
+          _ => None,
+        };
+        self.ip = case.map_or(*default, |idx| cases[idx].1);
+      }
+      Bytecode::SwitchTupleArity { cases, default } => {
+        let word = self.stack.pop().unwrap();
+        let case = self.memory.elements(&word).and_then(|elements| {
+          cases
+            .binary_search_by_key(&elements.len(), |(n, _)| *n)
+            .ok()
+        });
+        self.ip = case.map_or(*default, |idx| cases[idx].1);
+      }
+      Bytecode::Undefined => return Err(Crash::Error("Undefined instruction".to_string())),
     }
+    Ok(None)
   }
 
-  fn fetch(&self) -> &Bytecode {
-    let mut ip = self.ip.borrow_mut();
-    let r = &self.code[*ip];
-    *ip += 1;
+  fn fetch(&mut self) -> &'a Bytecode {
+    let r = &self.info.bytecode[self.ip];
+    self.ip += 1;
     r
   }
 }
 
+/// Checks that `function` is a function taking `arity` arguments and returns
+/// its entry in the function table of `info`.
+fn callee(info: &BytecodeInfo, function: Value, arity: usize) -> Result<usize, String> {
+  let Value::Function { id, .. } = function else {
+    return Err("Expected call to a function definition".to_string());
+  };
+  let expected = info.functions[id].arity;
+  if expected != arity {
+    return Err(format!("Expected {expected} arguments but got {arity}"));
+  }
+  Ok(id)
+}
+
+/// Whether the branches of a switch test the same kind of value, and are
+/// enough of them to be worth a jump table.
+fn switchable(branches: &[(Cond, desugar::Tree)]) -> bool {
+  let kind = |cond: &Cond| match cond {
+    Cond::Atom(_) => Some(0),
+    Cond::Number(_) => Some(1),
+    Cond::Tuple(_) => Some(2),
+    _ => None,
+  };
+  branches.len() >= 2
+    && kind(&branches[0].0).is_some()
+    && branches
+      .iter()
+      .all(|(cond, _)| kind(cond) == kind(&branches[0].0))
+}
+
+/// Runs `builtin` on the heap if it only takes apart or rearranges its
+/// arguments, so that they aren't copied out of the heap and back.
+fn heap_builtin(
+  memory: &mut gc::Heap,
+  builtin: Builtin,
+  arguments: &[Word],
+) -> Option<Result<Word, String>> {
+  let number = |n: usize| Word::Number(n as i32);
+  // Positions are 1-based.
+  let index = |n: i32| n.checked_sub(1).and_then(|i| usize::try_from(i).ok());
+  let result = match (builtin, arguments) {
+    (Builtin::Length, [xs]) => memory.list(xs).map(|xs| number(xs.len())),
+    (Builtin::Reverse, [xs]) => memory
+      .list(xs)
+      .map(|xs| xs.into_iter().fold(Word::Nil, |acc, x| memory.cons(x, acc))),
+    (Builtin::Append, [xs, ys]) => memory
+      .list(xs)
+      .map(|xs| xs.into_iter().rfold(*ys, |acc, x| memory.cons(x, acc))),
+    (Builtin::Nth, [Word::Number(n), xs]) => {
+      let xs = memory.list(xs);
+      xs.zip(index(*n)).and_then(|(xs, i)| xs.get(i).copied())
+    }
+    (Builtin::Hd, [xs]) => memory.hd(xs).copied(),
+    (Builtin::Tl, [xs]) => memory.tl(xs).copied(),
+    (Builtin::Element, [Word::Number(n), tuple]) => memory
+      .elements(tuple)
+      .zip(index(*n))
+      .and_then(|(mut elements, i)| elements.nth(i).copied()),
+    (Builtin::TupleSize, [tuple]) => memory.elements(tuple).map(|e| number(e.len())),
+    (Builtin::TupleToList, [tuple]) => {
+      let elements: Option<Vec<Word>> = memory.elements(tuple).map(|e| e.copied().collect());
+      elements.map(|e| e.into_iter().rfold(Word::Nil, |acc, x| memory.cons(x, acc)))
+    }
+    (Builtin::ListToTuple, [xs]) => memory.list(xs).map(|xs| memory.tuple(xs.into_iter())),
+    (Builtin::StringLength, [s]) => memory.str(s).map(|s| number(s.chars().count())),
+    (Builtin::Nth | Builtin::Element, [_, _]) => None,
+    _ => return None,
+  };
+  Some(result.ok_or(format!("{}: bad argument", builtin.name())))
+}
+
+/// Applies the binary operation `ins`, with the same semantics as in eval.
+fn binary(ins: &Bytecode, lhs: Value, rhs: Value) -> Result<Value, String> {
+  match (ins, lhs, rhs) {
+    (Bytecode::Eq, x, y) => Ok(Value::Atom(Atom::boolean(x == y))),
+    (_, Value::Number(a), Value::Number(b)) => arithmetic(ins, a, b).map(Value::Number),
+    _ => Err("Invalid binary operation.".to_string()),
+  }
+}
+
+/// Applies the arithmetic operation `ins` to two numbers.
+fn arithmetic(ins: &Bytecode, a: i32, b: i32) -> Result<i32, String> {
+  let result = match ins {
+    Bytecode::Div if b == 0 => return Err("Division by zero".to_string()),
+    Bytecode::Add => a.checked_add(b),
+    Bytecode::Sub => a.checked_sub(b),
+    Bytecode::Mul => a.checked_mul(b),
+    _ => a.checked_div(b),
+  };
+  result.ok_or("Arithmetic overflow".to_string())
+}
+
+impl<'a> Process for Task<'a> {
+  type Value = Value;
+  type Shared = Host;
+
+  fn run(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+    reductions: &mut usize,
+  ) -> Status<Value> {
+    self
+      .execute(host, ctx, reductions)
+      .unwrap_or_else(|err| Status::Exited(Err(err)))
+  }
+}
+
 #[cfg(test)]
 mod test {
-  use crate::{desugar::Desugar, lexer::Lexer, parser::Parser};
+  use std::path::Path;
 
-  use super::{Ctx, Machine, Value};
+  use crate::{desugar::Desugar, eval::Env, lexer::Lexer, parser::Parser, process::Term};
+
+  use super::{asm, live_size, verify, Bytecode, Ctx, Machine, Value};
 
   #[test]
-  fn test_compile() {
-    //     let src = r#"
-    // let x = {1, 99} in
-    // case x of
-    //   {1, 1} -> 42;
-    //   {1, x} -> x;
-    //   "oi"   -> "tchau";
-    //   _      -> 69
-    // end
-    // "#;
+  fn list_patterns() {
     let src = r#"
 case [1, 2, 3] of
   [1 | x] -> x;
@@ -472,24 +1608,362 @@ end
     let mut parser = Parser::new(Lexer::new(src));
     let expr = parser.expression().unwrap();
     let expr = expr.desugar().unwrap();
-    // println!("{expr:?}");
     let mut ctx = Ctx::new();
     ctx.fn_clause(expr);
-
     let info = ctx.bytecode();
 
-    for (idx, b) in info.bytecode.iter().enumerate() {
-      println!("{idx}: {b:?}");
+    let text = asm::disassemble(&info);
+    for mnemonic in ["test_cons", "get_hd", "get_tl"] {
+      assert!(text.contains(mnemonic), "{mnemonic} missing from\n{text}");
+    }
+    let res = Machine::new(&info).run().unwrap();
+    assert_eq!(res.to_string(), "[2, 3]");
+    let reassembled = asm::assemble(&text).unwrap();
+    assert_eq!(Machine::new(&reassembled).run().unwrap(), res);
+  }
+
+  #[test]
+  fn undefined() {
+    let info = asm::assemble(".entry 0\n.function 0 _/0 locals 0\nundefined").unwrap();
+    let res = Machine::new(&info).run();
+    assert_eq!(res, Err("Undefined instruction".to_string()));
+  }
+
+  #[test]
+  fn examples() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths = vec![root.join("main.lala")];
+    for entry in std::fs::read_dir(root.join("examples")).unwrap() {
+      let path = entry.unwrap().path();
+      if path.extension().is_some_and(|ext| ext == "lala") {
+        paths.push(path);
+      }
     }
-    for (c, id) in info.constants.iter() {
-      println!("{c:?}: {id}");
+    paths.sort();
+    for path in paths {
+      let src = std::fs::read_to_string(&path).unwrap();
+      let program = || {
+        Parser::new(Lexer::new(&src))
+          .program()
+          .unwrap()
+          .desugar()
+          .unwrap()
+      };
+      let main = Parser::new(Lexer::new("main()"))
+        .expression()
+        .unwrap()
+        .desugar()
+        .unwrap();
+      let name = path.file_name().unwrap().to_str().unwrap();
+      let expected = Env::from_program(program())
+        .eval(main)
+        .map(|v| v.to_string());
+      assert!(expected.is_ok(), "{name}: {expected:?}");
+      let mut ctx = Ctx::new();
+      ctx.program(program());
+      let info = ctx.bytecode();
+      assert_eq!(verify::verify(&info), Ok(()), "{name}");
+      let actual = Machine::new(&info).run().map(|v| v.to_string());
+      assert_eq!(actual, expected, "{name}");
+      let mut machine = Machine::new(&info).with_gc_stress();
+      let actual = machine.run().map(|v| v.to_string());
+      assert_eq!(actual, expected, "{name} collecting after every allocation");
+      assert!(machine.gc_stats().collections > 0, "{name}");
     }
-    println!("locals = {}", info.locals);
+  }
+
+  #[test]
+  fn calls() {
+    let src = r#"
+fn id(x) -> x
+fn apply(f, x) -> f(x)
+fn count([]) -> #done
+fn count([_ | xs]) -> count(xs)
+"#;
+    let program = Parser::new(Lexer::new(src))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    let cases = [
+      ("apply(id, #a)", Ok("#a")),
+      ("count([1, 2, 3])", Ok("#done")),
+      ("{id, apply}", Ok("{<fn/1>, <fn/2>}")),
+      ("apply(apply, #a)", Err("Expected 2 arguments but got 1")),
+      (
+        "apply(#id, 1)",
+        Err("Expected call to a function definition"),
+      ),
+      ("id(1, 2)", Err("Expected 1 arguments but got 2")),
+      ("nope(1)", Err("Unbound variable nope")),
+      (
+        "length(1, 2)",
+        Err("length: expected 1 arguments but got 2"),
+      ),
+    ];
+    let mut entries = vec![];
+    for (src, _) in cases {
+      let expr = Parser::new(Lexer::new(src))
+        .expression()
+        .unwrap()
+        .desugar()
+        .unwrap();
+      entries.push(ctx.fn_clause(expr));
+    }
+    let mut info = ctx.bytecode();
+    for (entry, (src, expected)) in entries.into_iter().zip(cases) {
+      info.entry = Some(entry);
+      let actual = Machine::new(&info).run().map(|v| v.to_string());
+      let expected = expected.map(str::to_string).map_err(str::to_string);
+      assert_eq!(actual, expected, "{src}");
+    }
+  }
+  #[test]
+  fn binary_operations() {
+    let cases = [
+      "1 + 2 * 3 - 4",
+      "7 / 2",
+      "-7 / 2",
+      "1 / 0",
+      "2147483647 + 1",
+      "-2147483648 - 1",
+      "65536 * 65536",
+      "-2147483648 / -1",
+      "1 + #a",
+      "\"a\" * 2",
+      "{1, [2, \"b\"]} == {1, [2, \"b\"]}",
+      "[1, 2] == [1]",
+      "#a == \"a\"",
+      "self() == self()",
+      "length == length",
+      "try 1 / 0 catch error:e -> e end",
+    ];
+    agree("", &cases);
+  }
 
+  #[test]
+  fn conditionals() {
+    let program = r#"
+fn sign(n) -> if n == 0 then #zero else if 0 == n / n + n / n - 2 then #one else #other
+fn loop(n) -> if n == 0 then #done else loop(n - 1)
+fn pick(c) -> {if c then #yes else #no, #after}
+"#;
+    let cases = [
+      "if #true then 1 else 2",
+      "if #false then 1 else 2",
+      "if 1 then 1 else 2",
+      "if \"true\" then 1 else 2",
+      "if {#true} then 1 else 2",
+      "if 1 == 1 then #a else #b",
+      "if 1 == 2 then #a else #b",
+      "{if #true then 1 else 2, if #false then 3 else 4}",
+      "if if #false then #true else #false then 1 else 2",
+      "if 1 / 0 then 1 else 2",
+      "if #true then 1 / 0 else 2",
+      "sign(0)",
+      "sign(5)",
+      "pick(#true)",
+      "pick(#nope)",
+      "loop(100000)",
+    ];
+    agree(program, &cases);
+  }
+
+  #[test]
+  fn switches() {
+    let program = r#"
+fn eval({#num, n}) -> n
+fn eval({#add, a, b}) -> eval(a) + eval(b)
+fn eval({#mul, a, b}) -> eval(a) * eval(b)
+fn eval({#neg, a}) -> 0 - eval(a)
+fn eval({#let, x}) -> x
+fn digit(0) -> #zero
+fn digit(1) -> #one
+fn digit(-1) -> #minus_one
+fn digit(_) -> #many
+fn size({}) -> 0
+fn size({_}) -> 1
+fn size({_, _}) -> 2
+fn size({_, _, _}) -> 3
+fn size(_) -> #other
+"#;
+    let cases = [
+      "eval({#add, {#num, 1}, {#mul, {#num, 2}, {#neg, {#num, 3}}}})",
+      "eval({#let, 7})",
+      "eval({#sub, 1, 2})",
+      "eval({#num})",
+      "eval(#num)",
+      "{digit(0), digit(1), digit(-1), digit(2), digit(#one), digit(\"0\")}",
+      "{size({}), size({1}), size({1, 2}), size({1, 2, 3}), size({1, 2, 3, 4}), size([1])}",
+    ];
+    agree(program, &cases);
+
+    let program = Parser::new(Lexer::new(program))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    let info = ctx.bytecode();
+    // Counts the switches and the tests of the function `name`.
+    let count = |name| {
+      let code = info.code(info.function(name).unwrap());
+      let mut counts = (0, 0);
+      for ins in &info.bytecode[code] {
+        match ins {
+          Bytecode::SwitchAtom { .. }
+          | Bytecode::SwitchNumber { .. }
+          | Bytecode::SwitchTupleArity { .. } => counts.0 += 1,
+          Bytecode::TestExact { .. } | Bytecode::TestTuple { .. } => counts.1 += 1,
+          _ => {}
+        }
+      }
+      counts
+    };
+    assert_eq!(count("eval"), (3, 0));
+    assert_eq!(count("digit"), (1, 0));
+
+    // Atoms first seen in the reverse of name order, so that their indices
+    // sort the other way round.
+    let program = "fn f(#switch_z) -> 1\nfn f(#switch_y) -> 2\nfn f(#switch_x) -> 3\nfn f(_) -> 0";
+    agree(
+      program,
+      &["{f(#switch_x), f(#switch_y), f(#switch_z), f(#switch_w), f(1)}"],
+    );
+    assert_eq!(count("size"), (1, 0));
+  }
+
+  #[test]
+  fn builtins() {
+    let program = "fn range(0, acc) -> acc\nfn range(n, acc) -> range(n - 1, [n | acc])";
+    let cases = [
+      "length([1, 2, 3])",
+      "length([1 | 2])",
+      "length(range(1000, []))",
+      "reverse([1, [2], 3])",
+      "append([1, 2], [3])",
+      "append([1], 2)",
+      "append(1, [2])",
+      "nth(2, [#a, #b, #c])",
+      "nth(0, [1])",
+      "nth(2, [1])",
+      "nth(#a, [1])",
+      "hd([1, 2])",
+      "hd([])",
+      "tl([1, 2])",
+      "tl(#a)",
+      "element(2, {1, {2}})",
+      "element(3, {1, 2})",
+      "element(-1, {1, 2})",
+      "tuple_size({1, 2})",
+      "tuple_size([1])",
+      "tuple_to_list({1, {2}, \"s\"})",
+      "list_to_tuple([1, [2]])",
+      "list_to_tuple(1)",
+      "string_length(\"hello\")",
+      "string_length(#a)",
+    ];
+    agree(program, &cases);
+  }
+
+  #[test]
+  fn shared_values() {
+    let slot = std::mem::size_of::<Value>();
+    let list = Value::cons(Value::Number(1), Value::cons(Value::Number(2), Value::Nil));
+    let pair = Value::tuple(vec![list.clone(), list.clone()]);
+    assert_eq!(live_size([&list]), 4 * slot);
+    // The list is shared by both elements of the tuple and counted once.
+    assert_eq!(live_size([&pair, &list]), 2 * slot + 4 * slot);
+    let s = Value::string("shared".to_string());
+    assert_eq!(live_size([&s, &s.clone()]), 6);
+  }
+
+  #[test]
+  fn garbage_collection() {
+    let program = Parser::new(Lexer::new(LISTS))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    let info = ctx.bytecode();
+    let arguments = vec![Value::Number(3), Value::Number(0)];
     let mut machine = Machine::new(&info);
-    let mut stack = vec![];
-    let locals = vec![Value::default(); info.locals + 1];
-    machine.run(&mut stack, locals);
-    println!("{stack:?}");
+    let res = machine.call("bench", arguments.clone()).unwrap();
+    assert_eq!(res.to_string(), "6003000");
+    let stats = machine.gc_stats();
+    // Each round leaves its lists behind.
+    assert!(stats.collections > 0 && stats.reclaimed > 0, "{stats:?}");
+
+    let mut machine = Machine::new(&info).with_gc_stress();
+    let res = machine.call("range", vec![Value::Number(300), Value::Nil]);
+    let elements = res.unwrap().to_vec().unwrap();
+    assert!(elements
+      .iter()
+      .map(ToString::to_string)
+      .eq((1..=300).map(|n| n.to_string())));
+    assert!(machine.gc_stats().collections >= 300);
+  }
+
+  const LISTS: &str = r#"
+fn range(0, acc) -> acc
+fn range(n, acc) -> range(n - 1, [n | acc])
+fn sum([], acc) -> acc
+fn sum([x | xs], acc) -> sum(xs, acc + x)
+fn map(_, []) -> []
+fn map(f, [x | xs]) -> [f(x) | map(f, xs)]
+fn double(x) -> x * 2
+fn tails([]) -> []
+fn tails([x | xs]) -> [{x, xs} | tails(xs)]
+fn firsts([]) -> 0
+fn firsts([{x, _} | ts]) -> x + firsts(ts)
+fn bench(0, acc) -> acc
+fn bench(n, _) ->
+  let l = range(2000, []) in
+  bench(n - 1, sum(map(double, l), 0) + firsts(tails(l)))
+"#;
+
+  /// Asserts that the VM agrees with eval on each expression of `cases`,
+  /// evaluated against the definitions in `src`.
+  fn agree(src: &str, cases: &[&str]) {
+    let program = || {
+      Parser::new(Lexer::new(src))
+        .program()
+        .unwrap()
+        .desugar()
+        .unwrap()
+    };
+    let expression = |src| {
+      Parser::new(Lexer::new(src))
+        .expression()
+        .unwrap()
+        .desugar()
+        .unwrap()
+    };
+    let mut ctx = Ctx::new();
+    ctx.program(program());
+    let entries: Vec<_> = cases
+      .iter()
+      .map(|src| ctx.fn_clause(expression(src)))
+      .collect();
+    let mut info = ctx.bytecode();
+    assert_eq!(verify::verify(&info), Ok(()));
+    for (entry, src) in entries.into_iter().zip(cases) {
+      let expected = Env::from_program(program())
+        .eval(expression(src))
+        .map(|v| v.to_string());
+      info.entry = Some(entry);
+      let actual = Machine::new(&info).run().map(|v| v.to_string());
+      assert_eq!(actual, expected, "{src}");
+      let actual = Machine::new(&info)
+        .with_gc_stress()
+        .run()
+        .map(|v| v.to_string());
+      assert_eq!(actual, expected, "{src} collecting after every allocation");
+    }
   }
 }
@@ -0,0 +1,571 @@
+//! A textual format for bytecode, to inspect what the compiler produces and
+//! to write VM tests by hand.
+//!
+//! ```text
+//! .constant 0 #done
+//! .entry 1
+//!
+//! .function 0 loop/1 locals 1
+//!   get_local 0
+//!   push_number 0
+//!   eq
+//!   test_exact 1 L7        ; #true
+//!   load_constant 0        ; #done
+//!   return
+//! L7:
+//!   ...
+//! ```
+//!
+//! Functions start at their `.function` line, and are given by their index in
+//! the function table, name, arity and number of locals. Branches refer to
+//! labels, and `;` starts a comment.
+
+use std::{collections::HashMap, fmt::Write};
+
+use indexmap::IndexMap;
+
+use crate::{atom::Atom, builtins::Builtin};
+
+use super::{Bytecode, BytecodeInfo, Constant, Function};
+
+/// Renders `info` in the textual format read by [`assemble`].
+pub fn disassemble(info: &BytecodeInfo) -> String {
+  let mut out = String::new();
+  for (constant, id) in &info.constants {
+    _ = writeln!(out, ".constant {id} {}", constant_text(constant));
+  }
+  if let Some(entry) = info.entry {
+    _ = writeln!(out, ".entry {entry}");
+  }
+  if let Some(source) = &info.source {
+    _ = writeln!(out, ".source {}", string_text(source));
+  }
+  let labels: Vec<usize> = info.bytecode.iter().flat_map(Bytecode::targets).collect();
+  for idx in 0..=info.bytecode.len() {
+    for (id, function) in info.functions.iter().enumerate() {
+      if function.entry == idx {
+        _ = writeln!(
+          out,
+          "\n.function {id} {} locals {}",
+          function_name(function),
+          function.locals
+        );
+      }
+    }
+    if labels.contains(&idx) {
+      _ = writeln!(out, "L{idx}:");
+    }
+    let Some(ins) = info.bytecode.get(idx) else {
+      break;
+    };
+    let (text, comment) = instruction(info, ins);
+    if let Some(comment) = comment {
+      _ = writeln!(out, "  {text:<22} ; {comment}");
+    } else {
+      _ = writeln!(out, "  {text}");
+    }
+  }
+  out
+}
+
+fn function_name(function: &Function) -> String {
+  let name = if function.name.is_empty() {
+    "_"
+  } else {
+    &function.name
+  };
+  format!("{name}/{}", function.arity)
+}
+
+fn constant_text(constant: &Constant) -> String {
+  match constant {
+    Constant::Number(n) => n.to_string(),
+    Constant::Atom(a) => format!("#{a}"),
+    Constant::String(s) => string_text(s),
+  }
+}
+
+fn string_text(s: &str) -> String {
+  let mut out = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => out.push_str("\\\""),
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\t' => out.push_str("\\t"),
+      '\r' => out.push_str("\\r"),
+      c => out.push(c),
+    }
+  }
+  out.push('"');
+  out
+}
+
+/// The text of an instruction as in [`disassemble`], along with its comment.
+pub fn instruction_text(info: &BytecodeInfo, ins: &Bytecode) -> String {
+  match instruction(info, ins) {
+    (text, Some(comment)) => format!("{text} ; {comment}"),
+    (text, None) => text,
+  }
+}
+
+/// The text of an instruction, along with a comment describing its operands.
+fn instruction(info: &BytecodeInfo, ins: &Bytecode) -> (String, Option<String>) {
+  let constant = |id: u16| {
+    let constant = info.constants.get_index(id as usize).map(|(c, _)| c);
+    constant.map_or("?".to_string(), constant_text)
+  };
+  let function = |id: usize| {
+    info
+      .functions
+      .get(id)
+      .map_or("?".to_string(), function_name)
+  };
+  match ins {
+    Bytecode::Return => ("return".to_string(), None),
+    Bytecode::PushNumber { val } => (format!("push_number {val}"), None),
+    Bytecode::LoadConstant { id } => (format!("load_constant {id}"), Some(constant(*id))),
+    Bytecode::GetLocal { id } => (format!("get_local {id}"), None),
+    Bytecode::SetLocal { id } => (format!("set_local {id}"), None),
+    Bytecode::TestExact { id, branch } => {
+      (format!("test_exact {id} L{branch}"), Some(constant(*id)))
+    }
+    Bytecode::TestTuple { size, branch } => (format!("test_tuple {size} L{branch}"), None),
+    Bytecode::TestCons { branch } => (format!("test_cons L{branch}"), None),
+    Bytecode::TestNil { branch } => (format!("test_nil L{branch}"), None),
+    Bytecode::MakeTuple { size } => (format!("make_tuple {size}"), None),
+    Bytecode::GetTuple { index } => (format!("get_tuple {index}"), None),
+    Bytecode::GetHd => ("get_hd".to_string(), None),
+    Bytecode::GetTl => ("get_tl".to_string(), None),
+    Bytecode::Dup => ("dup".to_string(), None),
+    Bytecode::Jump { index } => (format!("jump L{index}"), None),
+    Bytecode::MatchFail => ("match_fail".to_string(), None),
+    Bytecode::CallBuiltin { builtin } => (
+      format!("call_builtin {}/{}", builtin.name(), builtin.arity()),
+      None,
+    ),
+    Bytecode::CallNative { id, arity } => {
+      (format!("call_native {id} {arity}"), Some(constant(*id)))
+    }
+    Bytecode::Add => ("add".to_string(), None),
+    Bytecode::Sub => ("sub".to_string(), None),
+    Bytecode::Mul => ("mul".to_string(), None),
+    Bytecode::Div => ("div".to_string(), None),
+    Bytecode::Eq => ("eq".to_string(), None),
+    Bytecode::LoadFunction { function: id } => (format!("load_function {id}"), Some(function(*id))),
+    Bytecode::Call { function: id } => (format!("call {id}"), Some(function(*id))),
+    Bytecode::TailCall { function: id } => (format!("tail_call {id}"), Some(function(*id))),
+    Bytecode::Apply { arity } => (format!("apply {arity}"), None),
+    Bytecode::TailApply { arity } => (format!("tail_apply {arity}"), None),
+    Bytecode::PutList => ("put_list".to_string(), None),
+    Bytecode::Nil => ("nil".to_string(), None),
+    Bytecode::PeekMessage { branch } => (format!("peek_message L{branch}"), None),
+    Bytecode::RemoveMessage => ("remove_message".to_string(), None),
+    Bytecode::NextMessage { index } => (format!("next_message L{index}"), None),
+    Bytecode::Wait { index } => (format!("wait L{index}"), None),
+    Bytecode::Try { handler } => (format!("try L{handler}"), None),
+    Bytecode::EndTry => ("end_try".to_string(), None),
+    Bytecode::Raise => ("raise".to_string(), None),
+    Bytecode::SwitchAtom { cases, default } => {
+      let atoms: Vec<String> = cases.iter().map(|(id, _)| constant(*id)).collect();
+      (
+        switch("switch_atom", cases, *default),
+        Some(atoms.join(" ")),
+      )
+    }
+    Bytecode::SwitchNumber { cases, default } => (switch("switch_number", cases, *default), None),
+    Bytecode::SwitchTupleArity { cases, default } => {
+      (switch("switch_tuple_arity", cases, *default), None)
+    }
+    Bytecode::Undefined => ("undefined".to_string(), None),
+  }
+}
+
+fn switch<K: std::fmt::Display>(mnemonic: &str, cases: &[(K, usize)], default: usize) -> String {
+  let mut text = format!("{mnemonic} L{default}");
+  for (key, target) in cases {
+    _ = write!(text, " {key}:L{target}");
+  }
+  text
+}
+
+/// Parses the textual format produced by [`disassemble`].
+pub fn assemble(src: &str) -> Result<BytecodeInfo, String> {
+  let lines: Vec<(usize, &str)> = src
+    .lines()
+    .map(strip_comment)
+    .map(str::trim)
+    .enumerate()
+    .map(|(n, line)| (n + 1, line))
+    .filter(|(_, line)| !line.is_empty())
+    .collect();
+
+  // Labels may be used before they are defined, so they are collected first.
+  let mut labels = HashMap::new();
+  let mut len = 0;
+  for (n, line) in &lines {
+    if let Some(label) = line.strip_suffix(':') {
+      if labels.insert(label, len).is_some() {
+        return Err(format!("line {n}: Label {label} is defined twice"));
+      }
+    } else if !line.starts_with('.') {
+      len += 1;
+    }
+  }
+
+  let mut info = BytecodeInfo {
+    bytecode: vec![],
+    constants: IndexMap::new(),
+    functions: vec![],
+    entry: None,
+    source: None,
+  };
+  for (n, line) in lines {
+    if line.ends_with(':') {
+      continue;
+    }
+    directive(&mut info, &labels, line).map_err(|err| format!("line {n}: {err}"))?;
+  }
+  Ok(info)
+}
+
+/// Removes a comment from `line`, leaving the `;` of string constants alone.
+fn strip_comment(line: &str) -> &str {
+  let mut quoted = false;
+  let mut escaped = false;
+  for (idx, c) in line.char_indices() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' if quoted => escaped = true,
+      '"' => quoted = !quoted,
+      ';' if !quoted => return &line[..idx],
+      _ => {}
+    }
+  }
+  line
+}
+
+/// Adds the directive or instruction on `line` to `info`.
+fn directive(
+  info: &mut BytecodeInfo,
+  labels: &HashMap<&str, usize>,
+  line: &str,
+) -> Result<(), String> {
+  let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+  let rest = rest.trim();
+  match word {
+    ".constant" => {
+      let (id, constant) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+      let id: usize = number(id)?;
+      if id != info.constants.len() {
+        return Err(format!("Expected constant {}", info.constants.len()));
+      }
+      let constant = parse_constant(constant.trim())?;
+      if info.constants.contains_key(&constant) {
+        return Err(format!(
+          "Constant {} is defined twice",
+          constant_text(&constant)
+        ));
+      }
+      info.constants.insert(constant, id as u16);
+    }
+    ".entry" => info.entry = Some(number(rest)?),
+    ".source" => info.source = Some(parse_string(rest)?),
+    ".function" => {
+      let operands: Vec<&str> = rest.split_whitespace().collect();
+      let [id, signature, "locals", locals] = operands[..] else {
+        return Err("Expected .function <id> <name>/<arity> locals <n>".to_string());
+      };
+      if number::<usize>(id)? != info.functions.len() {
+        return Err(format!("Expected function {}", info.functions.len()));
+      }
+      let (name, arity) = signature
+        .split_once('/')
+        .ok_or(format!("Expected <name>/<arity> but got {signature}"))?;
+      info.functions.push(Function {
+        name: if name == "_" {
+          String::new()
+        } else {
+          name.to_string()
+        },
+        arity: number(arity)?,
+        entry: info.bytecode.len(),
+        locals: number(locals)?,
+      });
+    }
+    _ => {
+      let operands: Vec<&str> = rest.split_whitespace().collect();
+      let ins = parse_instruction(word, &operands, labels)?;
+      info.bytecode.push(ins);
+    }
+  }
+  Ok(())
+}
+
+fn number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+  text
+    .parse()
+    .map_err(|_| format!("Expected a number but got {text:?}"))
+}
+
+fn parse_constant(text: &str) -> Result<Constant, String> {
+  if let Some(atom) = text.strip_prefix('#') {
+    return Atom::try_new(atom).map(Constant::Atom);
+  }
+  if !text.starts_with('"') {
+    return number(text).map(Constant::Number);
+  }
+  parse_string(text).map(Constant::String)
+}
+
+fn parse_string(text: &str) -> Result<String, String> {
+  let quoted = text
+    .strip_prefix('"')
+    .ok_or(format!("Expected a string but got {text}"))?;
+  let mut value = String::new();
+  let mut chars = quoted.chars();
+  loop {
+    match chars.next() {
+      Some('"') => break,
+      Some('\\') => value.push(match chars.next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('r') => '\r',
+        Some(c @ ('"' | '\\')) => c,
+        _ => return Err(format!("Invalid escape in {text}")),
+      }),
+      Some(c) => value.push(c),
+      None => return Err(format!("Unterminated string {text}")),
+    }
+  }
+  if !chars.as_str().is_empty() {
+    return Err(format!("Unexpected {:?} after string", chars.as_str()));
+  }
+  Ok(value)
+}
+
+fn parse_instruction(
+  mnemonic: &str,
+  operands: &[&str],
+  labels: &HashMap<&str, usize>,
+) -> Result<Bytecode, String> {
+  let label = |label: &str| {
+    labels
+      .get(label)
+      .copied()
+      .ok_or(format!("Undefined label {label}"))
+  };
+  // The cases of a switch are given as `key:label`.
+  fn cases<K: std::str::FromStr>(
+    operands: &[&str],
+    label: impl Fn(&str) -> Result<usize, String>,
+  ) -> Result<Vec<(K, usize)>, String> {
+    let case = |operand: &&str| {
+      let (key, target) = operand
+        .split_once(':')
+        .ok_or(format!("Expected <key>:<label> but got {operand}"))?;
+      Ok((number(key)?, label(target)?))
+    };
+    operands.iter().map(case).collect()
+  }
+  let ins = match (mnemonic, operands) {
+    ("return", []) => Bytecode::Return,
+    ("push_number", [val]) => Bytecode::PushNumber { val: number(val)? },
+    ("load_constant", [id]) => Bytecode::LoadConstant { id: number(id)? },
+    ("get_local", [id]) => Bytecode::GetLocal { id: number(id)? },
+    ("set_local", [id]) => Bytecode::SetLocal { id: number(id)? },
+    ("test_exact", [id, branch]) => Bytecode::TestExact {
+      id: number(id)?,
+      branch: label(branch)?,
+    },
+    ("test_tuple", [size, branch]) => Bytecode::TestTuple {
+      size: number(size)?,
+      branch: label(branch)?,
+    },
+    ("test_cons", [branch]) => Bytecode::TestCons {
+      branch: label(branch)?,
+    },
+    ("test_nil", [branch]) => Bytecode::TestNil {
+      branch: label(branch)?,
+    },
+    ("make_tuple", [size]) => Bytecode::MakeTuple {
+      size: number(size)?,
+    },
+    ("get_tuple", [index]) => Bytecode::GetTuple {
+      index: number(index)?,
+    },
+    ("get_hd", []) => Bytecode::GetHd,
+    ("get_tl", []) => Bytecode::GetTl,
+    ("dup", []) => Bytecode::Dup,
+    ("jump", [index]) => Bytecode::Jump {
+      index: label(index)?,
+    },
+    ("match_fail", []) => Bytecode::MatchFail,
+    ("call_builtin", [signature]) => {
+      let builtin = signature
+        .split_once('/')
+        .and_then(|(name, arity)| Some((name, arity.parse().ok()?)))
+        .and_then(|(name, arity)| Builtin::from_name(name, arity).filter(|b| b.arity() == arity))
+        .ok_or(format!("Unknown builtin {signature}"))?;
+      Bytecode::CallBuiltin { builtin }
+    }
+    ("call_native", [id, arity]) => Bytecode::CallNative {
+      id: number(id)?,
+      arity: number(arity)?,
+    },
+    ("add", []) => Bytecode::Add,
+    ("sub", []) => Bytecode::Sub,
+    ("mul", []) => Bytecode::Mul,
+    ("div", []) => Bytecode::Div,
+    ("eq", []) => Bytecode::Eq,
+    ("load_function", [function]) => Bytecode::LoadFunction {
+      function: number(function)?,
+    },
+    ("call", [function]) => Bytecode::Call {
+      function: number(function)?,
+    },
+    ("tail_call", [function]) => Bytecode::TailCall {
+      function: number(function)?,
+    },
+    ("apply", [arity]) => Bytecode::Apply {
+      arity: number(arity)?,
+    },
+    ("tail_apply", [arity]) => Bytecode::TailApply {
+      arity: number(arity)?,
+    },
+    ("put_list", []) => Bytecode::PutList,
+    ("nil", []) => Bytecode::Nil,
+    ("peek_message", [branch]) => Bytecode::PeekMessage {
+      branch: label(branch)?,
+    },
+    ("remove_message", []) => Bytecode::RemoveMessage,
+    ("next_message", [index]) => Bytecode::NextMessage {
+      index: label(index)?,
+    },
+    ("wait", [index]) => Bytecode::Wait {
+      index: label(index)?,
+    },
+    ("try", [handler]) => Bytecode::Try {
+      handler: label(handler)?,
+    },
+    ("end_try", []) => Bytecode::EndTry,
+    ("raise", []) => Bytecode::Raise,
+    ("switch_atom", [default, rest @ ..]) => Bytecode::SwitchAtom {
+      cases: cases(rest, label)?,
+      default: label(default)?,
+    },
+    ("switch_number", [default, rest @ ..]) => Bytecode::SwitchNumber {
+      cases: cases(rest, label)?,
+      default: label(default)?,
+    },
+    ("switch_tuple_arity", [default, rest @ ..]) => Bytecode::SwitchTupleArity {
+      cases: cases(rest, label)?,
+      default: label(default)?,
+    },
+    ("undefined", []) => Bytecode::Undefined,
+    _ => {
+      return Err(format!(
+        "Unknown instruction {mnemonic} with {} operands",
+        operands.len()
+      ))
+    }
+  };
+  Ok(ins)
+}
+
+#[cfg(test)]
+mod test {
+  use std::path::Path;
+
+  use crate::{
+    compile::{Ctx, Machine},
+    desugar::Desugar,
+    lexer::Lexer,
+    parser::Parser,
+  };
+
+  use super::{assemble, constant_text, disassemble, Constant};
+
+  #[test]
+  fn round_trip() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("main.lala");
+    let src = std::fs::read_to_string(path).unwrap();
+    let program = Parser::new(Lexer::new(&src))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    let expr = Parser::new(Lexer::new(r#"{"a; b", main()}"#))
+      .expression()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    ctx.fn_clause(expr);
+    let info = ctx.bytecode();
+
+    let text = disassemble(&info);
+    let assembled = assemble(&text).unwrap();
+    assert_eq!(assembled.bytecode, info.bytecode);
+    assert_eq!(assembled.constants, info.constants);
+    assert_eq!(assembled.entry, info.entry);
+    assert_eq!(disassemble(&assembled), text);
+
+    let expected = Machine::new(&info).run().map(|v| v.to_string());
+    let actual = Machine::new(&assembled).run().map(|v| v.to_string());
+    assert_eq!(actual, expected);
+
+    let escaped = Constant::String("a\"; \\b\n".to_string());
+    let text = format!(".constant 0 {}", constant_text(&escaped));
+    assert_eq!(
+      assemble(&text).unwrap().constants.get_index(0),
+      Some((&escaped, &0))
+    );
+  }
+
+  #[test]
+  fn handwritten() {
+    let src = r#"
+.constant 0 #done
+.constant 1 #true
+.entry 1
+
+.function 0 loop/1 locals 1
+  get_local 0
+  push_number 0
+  eq
+  test_exact 1 recur        ; #true
+  load_constant 0
+  return
+recur:
+  get_local 0
+  push_number 1
+  sub
+  tail_call 0
+
+.function 1 _/0 locals 0
+  push_number 100000
+  call 0
+  call_builtin length/1
+  return
+"#;
+    let info = assemble(src).unwrap();
+    let res = Machine::new(&info).run().map(|v| v.to_string());
+    assert_eq!(res, Err("length: bad argument".to_string()));
+
+    let errors = [
+      ("jump nowhere", "line 1: Undefined label nowhere"),
+      (
+        "push_number",
+        "line 1: Unknown instruction push_number with 0 operands",
+      ),
+      ("call_builtin length/2", "line 1: Unknown builtin length/2"),
+      (".constant 1 #a", "line 1: Expected constant 0"),
+      ("a:\na:", "line 2: Label a is defined twice"),
+    ];
+    for (src, expected) in errors {
+      assert_eq!(assemble(src).err(), Some(expected.to_string()), "{src}");
+    }
+  }
+}
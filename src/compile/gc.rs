@@ -0,0 +1,420 @@
+//! The heap of a process of the VM, and the copying collector freeing it.
+//!
+//! Tuples, cons cells and strings a process builds live in its own heap, as
+//! runs of cells referred to by the index of their first one. Anything that
+//! leaves the process, like messages, results and the arguments of builtins,
+//! is exported to a [`Value`] first, and values coming in are imported.
+//!
+//! Collections copy every object reachable from the roots of the process to
+//! a new space, breadth first, leaving forwarding cells behind so that shared
+//! objects are copied once. They only happen between instructions, when all
+//! the values of the process are on its stack or in its locals.
+
+use std::rc::Rc;
+
+use crate::{atom::Atom, process::Pid, value::Value};
+
+/// Cells the heap grows to before its first collection.
+const INITIAL_THRESHOLD: usize = 1024;
+
+/// A value of a process, whose tuples, cons cells and strings are on its
+/// heap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Word {
+  Number(i32),
+  Atom(Atom),
+  Nil,
+  Pid(Pid),
+  Function {
+    id: usize,
+    arity: usize,
+  },
+  /// A tuple header followed by its elements.
+  Tuple(usize),
+  /// The head cell followed by the tail cell.
+  Cons(usize),
+  String(usize),
+}
+
+impl Default for Word {
+  fn default() -> Self {
+    Word::Number(0)
+  }
+}
+
+impl Word {
+  /// The object the word refers to, if any.
+  fn object(&self) -> Option<usize> {
+    match self {
+      Word::Tuple(at) | Word::Cons(at) | Word::String(at) => Some(*at),
+      _ => None,
+    }
+  }
+
+  fn moved(&self, to: usize) -> Word {
+    match self {
+      Word::Tuple(_) => Word::Tuple(to),
+      Word::Cons(_) => Word::Cons(to),
+      Word::String(_) => Word::String(to),
+      word => *word,
+    }
+  }
+}
+
+#[derive(Debug)]
+enum Cell {
+  /// Starts a tuple with this many elements.
+  Tuple(usize),
+  String(Rc<str>),
+  Word(Word),
+  /// Left behind by an object copied to this index of the new space.
+  Forward(usize),
+}
+
+/// What the collector did over the life of one or more processes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+  pub collections: usize,
+  /// Cells of live objects copied.
+  pub copied: usize,
+  /// Cells of dead objects freed.
+  pub reclaimed: usize,
+}
+
+impl std::ops::Add for Stats {
+  type Output = Self;
+
+  fn add(self, other: Self) -> Self {
+    Self {
+      collections: self.collections + other.collections,
+      copied: self.copied + other.copied,
+      reclaimed: self.reclaimed + other.reclaimed,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub struct Heap {
+  cells: Vec<Cell>,
+  /// Bytes of the strings in `cells`.
+  strings: usize,
+  /// Number of cells at which the next collection is due.
+  threshold: usize,
+  /// Collects after every allocation.
+  stress: bool,
+  /// Whether something was allocated since the last collection.
+  allocated: bool,
+  stats: Stats,
+}
+
+impl Heap {
+  pub fn new(stress: bool) -> Self {
+    Self {
+      cells: vec![],
+      strings: 0,
+      threshold: INITIAL_THRESHOLD,
+      stress,
+      allocated: false,
+      stats: Stats::default(),
+    }
+  }
+
+  pub fn stats(&self) -> Stats {
+    self.stats
+  }
+
+  pub fn stress(&self) -> bool {
+    self.stress
+  }
+
+  pub fn set_stress(&mut self, stress: bool) {
+    self.stress = stress;
+  }
+
+  /// Bytes the heap takes.
+  pub fn size(&self) -> usize {
+    self.cells.len() * std::mem::size_of::<Cell>() + self.strings
+  }
+
+  /// Whether it is time to collect.
+  pub fn due(&self) -> bool {
+    if self.stress {
+      self.allocated
+    } else {
+      self.cells.len() >= self.threshold
+    }
+  }
+
+  fn push(&mut self, cell: Cell) -> usize {
+    self.allocated = true;
+    self.cells.push(cell);
+    self.cells.len() - 1
+  }
+
+  pub fn tuple(&mut self, elements: impl ExactSizeIterator<Item = Word>) -> Word {
+    let at = self.push(Cell::Tuple(elements.len()));
+    self.cells.extend(elements.map(Cell::Word));
+    Word::Tuple(at)
+  }
+
+  pub fn cons(&mut self, hd: Word, tl: Word) -> Word {
+    let at = self.push(Cell::Word(hd));
+    self.cells.push(Cell::Word(tl));
+    Word::Cons(at)
+  }
+
+  pub fn string(&mut self, s: Rc<str>) -> Word {
+    self.strings += s.len();
+    Word::String(self.push(Cell::String(s)))
+  }
+
+  fn word(&self, at: usize) -> &Word {
+    match &self.cells[at] {
+      Cell::Word(word) => word,
+      cell => unreachable!("{cell:?} is not a word"),
+    }
+  }
+
+  pub fn elements(&self, word: &Word) -> Option<impl ExactSizeIterator<Item = &Word>> {
+    let Word::Tuple(at) = word else {
+      return None;
+    };
+    let Cell::Tuple(len) = self.cells[*at] else {
+      unreachable!()
+    };
+    Some((at + 1..at + 1 + len).map(|at| self.word(at)))
+  }
+
+  pub fn hd(&self, word: &Word) -> Option<&Word> {
+    match word {
+      Word::Cons(at) => Some(self.word(*at)),
+      _ => None,
+    }
+  }
+
+  pub fn tl(&self, word: &Word) -> Option<&Word> {
+    match word {
+      Word::Cons(at) => Some(self.word(at + 1)),
+      _ => None,
+    }
+  }
+
+  /// The elements of a proper list.
+  pub fn list(&self, word: &Word) -> Option<Vec<Word>> {
+    let mut elements = vec![];
+    let mut word = word;
+    while let Word::Cons(at) = word {
+      elements.push(*self.word(*at));
+      word = self.word(at + 1);
+    }
+    (*word == Word::Nil).then_some(elements)
+  }
+
+  pub fn str(&self, word: &Word) -> Option<&str> {
+    match (word, word.object().map(|at| &self.cells[at])) {
+      (Word::String(_), Some(Cell::String(s))) => Some(s),
+      _ => None,
+    }
+  }
+
+  /// Copies `value` to the heap.
+  pub fn import(&mut self, value: &Value) -> Word {
+    match value {
+      Value::Number(n) => Word::Number(*n),
+      Value::Atom(a) => Word::Atom(*a),
+      Value::String(s) => self.string(s.clone()),
+      Value::Nil => Word::Nil,
+      Value::Pid(pid) => Word::Pid(*pid),
+      Value::Function { id, arity } => Word::Function {
+        id: *id,
+        arity: *arity,
+      },
+      Value::Tuple(elements) => {
+        let elements: Vec<_> = elements.iter().map(|v| self.import(v)).collect();
+        self.tuple(elements.into_iter())
+      }
+      Value::Cons(_) => {
+        // Lists can be too long to follow their tail recursively.
+        let mut heads = vec![];
+        let mut curr = value;
+        while let Value::Cons(cell) = curr {
+          heads.push(self.import(&cell.0));
+          curr = &cell.1;
+        }
+        let tl = self.import(curr);
+        heads.into_iter().rev().fold(tl, |tl, hd| self.cons(hd, tl))
+      }
+    }
+  }
+
+  /// Copies `word` and what it refers to out of the heap.
+  pub fn export(&self, word: &Word) -> Value {
+    match word {
+      Word::Number(n) => Value::Number(*n),
+      Word::Atom(a) => Value::Atom(*a),
+      Word::Nil => Value::Nil,
+      Word::Pid(pid) => Value::Pid(*pid),
+      Word::Function { id, arity } => Value::Function {
+        id: *id,
+        arity: *arity,
+      },
+      Word::String(at) => match &self.cells[*at] {
+        Cell::String(s) => Value::String(s.clone()),
+        cell => unreachable!("{word:?} refers to {cell:?}"),
+      },
+      Word::Tuple(_) => Value::Tuple(
+        self
+          .elements(word)
+          .unwrap()
+          .map(|element| self.export(element))
+          .collect(),
+      ),
+      Word::Cons(_) => {
+        let mut heads = vec![];
+        let mut curr = word;
+        while let Word::Cons(_) = curr {
+          heads.push(self.export(self.hd(curr).unwrap()));
+          curr = self.tl(curr).unwrap();
+        }
+        let tl = self.export(curr);
+        heads
+          .into_iter()
+          .rev()
+          .fold(tl, |tl, hd| Value::cons(hd, tl))
+      }
+    }
+  }
+
+  /// Structural equality. As in eval, functions are never equal.
+  pub fn equal(&self, x: &Word, y: &Word) -> bool {
+    match (x, y) {
+      (Word::Number(a), Word::Number(b)) => a == b,
+      (Word::Atom(a), Word::Atom(b)) => a == b,
+      (Word::Nil, Word::Nil) => true,
+      (Word::Pid(a), Word::Pid(b)) => a == b,
+      (Word::String(_), Word::String(_)) => self.str(x) == self.str(y),
+      (Word::Tuple(_), Word::Tuple(_)) => {
+        let (a, b) = (self.elements(x).unwrap(), self.elements(y).unwrap());
+        a.len() == b.len() && a.zip(b).all(|(x, y)| self.equal(x, y))
+      }
+      (Word::Cons(_), Word::Cons(_)) => {
+        // Lists can be too long to follow their tail recursively.
+        let (mut x, mut y) = (x, y);
+        while let (Word::Cons(_), Word::Cons(_)) = (x, y) {
+          if !self.equal(self.hd(x).unwrap(), self.hd(y).unwrap()) {
+            return false;
+          }
+          (x, y) = (self.tl(x).unwrap(), self.tl(y).unwrap());
+        }
+        self.equal(x, y)
+      }
+      _ => false,
+    }
+  }
+
+  /// Frees every object that can't be reached from `roots`, updating them to
+  /// where their objects moved, and returns what the collection did.
+  pub fn collect<'w>(&mut self, roots: impl IntoIterator<Item = &'w mut Word>) -> Stats {
+    let before = self.cells.len();
+    let mut from = std::mem::take(&mut self.cells);
+    let mut to = Vec::new();
+    let mut strings = 0;
+    for root in roots {
+      *root = evacuate(&mut from, &mut to, &mut strings, root);
+    }
+    let mut scan = 0;
+    while scan < to.len() {
+      if let Cell::Word(word) = &to[scan] {
+        let word = *word;
+        to[scan] = Cell::Word(evacuate(&mut from, &mut to, &mut strings, &word));
+      }
+      scan += 1;
+    }
+    let stats = Stats {
+      collections: 1,
+      copied: to.len(),
+      reclaimed: before - to.len(),
+    };
+    self.stats = self.stats + stats;
+    self.threshold = INITIAL_THRESHOLD.max(2 * to.len());
+    self.cells = to;
+    self.strings = strings;
+    self.allocated = false;
+    stats
+  }
+}
+
+/// Copies the object `word` refers to from `from` to `to`, unless it was
+/// already, and returns the word referring to the copy.
+fn evacuate(from: &mut [Cell], to: &mut Vec<Cell>, strings: &mut usize, word: &Word) -> Word {
+  let Some(at) = word.object() else {
+    return *word;
+  };
+  if let Cell::Forward(moved) = from[at] {
+    return word.moved(moved);
+  }
+  let len = match (&from[at], word) {
+    (Cell::Tuple(len), _) => len + 1,
+    (Cell::String(s), _) => {
+      *strings += s.len();
+      1
+    }
+    (_, Word::Cons(_)) => 2,
+    (cell, _) => unreachable!("{word:?} refers to {cell:?}"),
+  };
+  let moved = to.len();
+  for cell in &mut from[at..at + len] {
+    to.push(std::mem::replace(cell, Cell::Forward(moved)));
+  }
+  word.moved(moved)
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{process::Term, value::Value};
+
+  use super::{Heap, Stats, Word};
+
+  #[test]
+  fn collect() {
+    let mut heap = Heap::new(false);
+    let value = Value::tuple(vec![
+      Value::string("s".to_string()),
+      Value::cons(Value::Number(1), Value::Nil),
+    ]);
+    heap.import(&Value::tuple(vec![Value::Number(1); 3]));
+    let mut kept = heap.import(&value);
+    let mut shared = heap.import(&Value::cons(Value::Number(2), Value::Nil));
+    let mut pair = heap.tuple([shared, shared].into_iter());
+    assert_eq!(heap.cells.len(), 4 + 6 + 2 + 3);
+    heap.collect([&mut kept, &mut pair, &mut shared]);
+    assert_eq!(
+      heap.stats(),
+      Stats {
+        collections: 1,
+        copied: 6 + 2 + 3,
+        reclaimed: 4,
+      }
+    );
+    assert_eq!(heap.export(&kept).to_string(), value.to_string());
+    assert_eq!(heap.export(&pair).to_string(), "{[2], [2]}");
+    // The shared list was copied once.
+    let elements: Vec<_> = heap.elements(&pair).unwrap().collect();
+    assert_eq!(elements, [&shared, &shared]);
+    let copy = heap.import(&value);
+    assert!(heap.equal(&kept, &copy));
+  }
+
+  #[test]
+  fn long_lists() {
+    let mut heap = Heap::new(false);
+    let (mut a, mut b) = (Word::Nil, Word::Nil);
+    for n in 0..200_000 {
+      a = heap.cons(Word::Number(n), a);
+      b = heap.cons(Word::Number(n), b);
+    }
+    assert!(heap.equal(&a, &b));
+    let c = heap.cons(Word::Number(0), Word::Nil);
+    assert!(!heap.equal(&a, &c));
+  }
+}
@@ -0,0 +1,542 @@
+//! The binary format of compiled modules, stored in `.lalac` files.
+//!
+//! A module starts with [`MAGIC`] and the format [`VERSION`], followed by the
+//! constant pool, the function table, the entry, the bytecode and the debug
+//! info, and ends with a CRC-32 of everything before it. Numbers are little
+//! endian, and strings are prefixed by their length in bytes.
+//!
+//! The debug info holds the path of the source and the names of the
+//! functions, which the VM only needs to call functions by name.
+
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{atom::Atom, builtins::Builtin};
+
+use super::{verify::verify_uninterned, Bytecode, BytecodeInfo, Constant, Function};
+
+pub const MAGIC: &[u8; 4] = b"LALC";
+
+/// Bumped whenever the format changes, as modules of another version can't be
+/// read.
+pub const VERSION: u16 = 4;
+
+/// Encodes `info` as a module.
+pub fn write(info: &BytecodeInfo) -> Vec<u8> {
+  let mut w = Writer(Vec::new());
+  w.0.extend(MAGIC);
+  w.0.extend(VERSION.to_le_bytes());
+
+  w.len(info.constants.len());
+  for constant in info.constants.keys() {
+    match constant {
+      Constant::Number(n) => {
+        w.u8(0);
+        w.i32(*n);
+      }
+      Constant::Atom(a) => {
+        w.u8(1);
+        w.string(a.as_str());
+      }
+      Constant::String(s) => {
+        w.u8(2);
+        w.string(s);
+      }
+    }
+  }
+
+  w.len(info.functions.len());
+  for function in &info.functions {
+    w.len(function.arity);
+    w.len(function.entry);
+    w.len(function.locals);
+  }
+
+  match info.entry {
+    Some(entry) => {
+      w.u8(1);
+      w.len(entry);
+    }
+    None => w.u8(0),
+  }
+
+  w.len(info.bytecode.len());
+  for ins in &info.bytecode {
+    w.instruction(ins);
+  }
+
+  w.string(info.source.as_deref().unwrap_or(""));
+  for function in &info.functions {
+    w.string(&function.name);
+  }
+
+  let checksum = checksum(&w.0);
+  w.0.extend(checksum.to_le_bytes());
+  w.0
+}
+
+/// Decodes a module written by [`write()`], checking that it is safe to run.
+pub fn read(bytes: &[u8]) -> Result<BytecodeInfo, String> {
+  if bytes.len() < MAGIC.len() + 2 + 4 || !bytes.starts_with(MAGIC) {
+    return Err("Not a compiled module".to_string());
+  }
+  let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+  if version != VERSION {
+    return Err(format!(
+      "Unsupported module version {version}, expected {VERSION}"
+    ));
+  }
+  let (body, checksum_bytes) = bytes.split_at(bytes.len() - 4);
+  if checksum(body).to_le_bytes() != checksum_bytes {
+    return Err("Corrupted module: checksum mismatch".to_string());
+  }
+
+  let mut r = Reader {
+    bytes: body,
+    pos: MAGIC.len() + 2,
+  };
+
+  let mut constants = IndexSet::new();
+  for id in 0..r.len()? {
+    let constant = match r.u8()? {
+      0 => Read::Constant(Constant::Number(r.i32()?)),
+      1 => Read::Atom(r.string()?),
+      2 => Read::Constant(Constant::String(r.string()?)),
+      tag => return Err(format!("Invalid constant tag {tag}")),
+    };
+    u16::try_from(id).map_err(|_| "Too many constants".to_string())?;
+    if !constants.insert(constant) {
+      return Err("Duplicate constant".to_string());
+    }
+  }
+
+  let mut functions = vec![];
+  for _ in 0..r.len()? {
+    functions.push(Function {
+      name: String::new(),
+      arity: r.len()?,
+      entry: r.len()?,
+      locals: r.len()?,
+    });
+  }
+
+  let entry = match r.u8()? {
+    0 => None,
+    _ => Some(r.len()?),
+  };
+
+  let mut bytecode = vec![];
+  for _ in 0..r.len()? {
+    bytecode.push(r.instruction()?);
+  }
+
+  let source = Some(r.string()?).filter(|s| !s.is_empty());
+  for function in &mut functions {
+    function.name = r.string()?;
+  }
+
+  if r.pos != body.len() {
+    return Err("Corrupted module: trailing bytes".to_string());
+  }
+  let mut info = BytecodeInfo {
+    bytecode,
+    constants: IndexMap::new(),
+    functions,
+    entry,
+    source,
+  };
+  let atoms: Vec<_> = constants
+    .iter()
+    .map(|constant| match constant {
+      Read::Atom(name) => Some(name.as_str()),
+      Read::Constant(_) => None,
+    })
+    .collect();
+  verify_uninterned(&info, &atoms)?;
+  for (id, constant) in constants.into_iter().enumerate() {
+    let constant = match constant {
+      Read::Constant(constant) => constant,
+      Read::Atom(name) => Constant::Atom(Atom::try_new(&name)?),
+    };
+    info.constants.insert(constant, id as u16);
+  }
+  Ok(info)
+}
+
+/// A constant as read from a module, whose atoms are only interned once the
+/// module is verified.
+#[derive(PartialEq, Eq, Hash)]
+enum Read {
+  Constant(Constant),
+  Atom(String),
+}
+
+/// CRC-32 of `bytes`, as used by zip and png.
+fn checksum(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+  for byte in bytes {
+    crc ^= *byte as u32;
+    for _ in 0..8 {
+      crc = if crc & 1 == 1 {
+        (crc >> 1) ^ 0xEDB8_8320
+      } else {
+        crc >> 1
+      };
+    }
+  }
+  !crc
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+  fn u8(&mut self, value: u8) {
+    self.0.push(value);
+  }
+
+  fn i32(&mut self, value: i32) {
+    self.0.extend(value.to_le_bytes());
+  }
+
+  fn len(&mut self, value: usize) {
+    let value = u32::try_from(value).expect("Module too large");
+    self.0.extend(value.to_le_bytes());
+  }
+
+  fn string(&mut self, value: &str) {
+    self.len(value.len());
+    self.0.extend(value.as_bytes());
+  }
+
+  fn instruction(&mut self, ins: &Bytecode) {
+    let (opcode, operands): (u8, &[usize]) = match ins {
+      Bytecode::Return => (0, &[]),
+      Bytecode::PushNumber { val } => {
+        self.u8(1);
+        self.i32(*val);
+        return;
+      }
+      Bytecode::LoadConstant { id } => (2, &[*id as usize]),
+      Bytecode::GetLocal { id } => (3, &[*id]),
+      Bytecode::SetLocal { id } => (4, &[*id]),
+      Bytecode::TestExact { id, branch } => (5, &[*id as usize, *branch]),
+      Bytecode::TestTuple { size, branch } => (6, &[*size, *branch]),
+      Bytecode::TestCons { branch } => (7, &[*branch]),
+      Bytecode::TestNil { branch } => (8, &[*branch]),
+      Bytecode::MakeTuple { size } => (9, &[*size]),
+      Bytecode::GetTuple { index } => (10, &[*index]),
+      Bytecode::GetHd => (11, &[]),
+      Bytecode::GetTl => (12, &[]),
+      Bytecode::Jump { index } => (13, &[*index]),
+      Bytecode::MatchFail => (14, &[]),
+      Bytecode::CallBuiltin { builtin } => {
+        // Builtins are stored by name, so their order can change.
+        self.u8(15);
+        self.string(builtin.name());
+        self.len(builtin.arity());
+        return;
+      }
+      Bytecode::CallNative { id, arity } => (40, &[*id as usize, *arity]),
+      Bytecode::Add => (16, &[]),
+      Bytecode::Sub => (17, &[]),
+      Bytecode::Mul => (18, &[]),
+      Bytecode::Div => (19, &[]),
+      Bytecode::Eq => (20, &[]),
+      Bytecode::LoadFunction { function } => (21, &[*function]),
+      Bytecode::Call { function } => (22, &[*function]),
+      Bytecode::TailCall { function } => (23, &[*function]),
+      Bytecode::Apply { arity } => (24, &[*arity]),
+      Bytecode::TailApply { arity } => (25, &[*arity]),
+      Bytecode::PutList => (26, &[]),
+      Bytecode::Nil => (27, &[]),
+      Bytecode::PeekMessage { branch } => (28, &[*branch]),
+      Bytecode::RemoveMessage => (29, &[]),
+      Bytecode::NextMessage { index } => (30, &[*index]),
+      Bytecode::Wait { index } => (31, &[*index]),
+      Bytecode::Try { handler } => (32, &[*handler]),
+      Bytecode::EndTry => (33, &[]),
+      Bytecode::Raise => (34, &[]),
+      Bytecode::Undefined => (35, &[]),
+      Bytecode::Dup => (36, &[]),
+      Bytecode::SwitchAtom { cases, default } => {
+        self.u8(37);
+        self.len(*default);
+        self.len(cases.len());
+        for (id, target) in cases {
+          self.len(*id as usize);
+          self.len(*target);
+        }
+        return;
+      }
+      Bytecode::SwitchNumber { cases, default } => {
+        self.u8(38);
+        self.len(*default);
+        self.len(cases.len());
+        for (n, target) in cases {
+          self.i32(*n);
+          self.len(*target);
+        }
+        return;
+      }
+      Bytecode::SwitchTupleArity { cases, default } => {
+        self.u8(39);
+        self.len(*default);
+        self.len(cases.len());
+        for (size, target) in cases {
+          self.len(*size);
+          self.len(*target);
+        }
+        return;
+      }
+    };
+    self.u8(opcode);
+    for operand in operands {
+      self.len(*operand);
+    }
+  }
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+    let bytes = self
+      .bytes
+      .get(self.pos..self.pos + n)
+      .ok_or("Corrupted module: unexpected end".to_string())?;
+    self.pos += n;
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn i32(&mut self) -> Result<i32, String> {
+    Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+  }
+
+  fn len(&mut self) -> Result<usize, String> {
+    Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()) as usize)
+  }
+
+  fn id(&mut self) -> Result<u16, String> {
+    u16::try_from(self.len()?).map_err(|_| "Invalid constant id".to_string())
+  }
+
+  fn string(&mut self) -> Result<String, String> {
+    let len = self.len()?;
+    let bytes = self.take(len)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid string".to_string())
+  }
+
+  fn instruction(&mut self) -> Result<Bytecode, String> {
+    let ins = match self.u8()? {
+      0 => Bytecode::Return,
+      1 => Bytecode::PushNumber { val: self.i32()? },
+      2 => Bytecode::LoadConstant { id: self.id()? },
+      3 => Bytecode::GetLocal { id: self.len()? },
+      4 => Bytecode::SetLocal { id: self.len()? },
+      5 => Bytecode::TestExact {
+        id: self.id()?,
+        branch: self.len()?,
+      },
+      6 => Bytecode::TestTuple {
+        size: self.len()?,
+        branch: self.len()?,
+      },
+      7 => Bytecode::TestCons {
+        branch: self.len()?,
+      },
+      8 => Bytecode::TestNil {
+        branch: self.len()?,
+      },
+      9 => Bytecode::MakeTuple { size: self.len()? },
+      10 => Bytecode::GetTuple { index: self.len()? },
+      11 => Bytecode::GetHd,
+      12 => Bytecode::GetTl,
+      13 => Bytecode::Jump { index: self.len()? },
+      14 => Bytecode::MatchFail,
+      15 => {
+        let name = self.string()?;
+        let arity = self.len()?;
+        let builtin = Builtin::from_name(&name, arity)
+          .filter(|b| b.arity() == arity)
+          .ok_or(format!("Unknown builtin {name}/{arity}"))?;
+        Bytecode::CallBuiltin { builtin }
+      }
+      16 => Bytecode::Add,
+      17 => Bytecode::Sub,
+      18 => Bytecode::Mul,
+      19 => Bytecode::Div,
+      20 => Bytecode::Eq,
+      21 => Bytecode::LoadFunction {
+        function: self.len()?,
+      },
+      22 => Bytecode::Call {
+        function: self.len()?,
+      },
+      23 => Bytecode::TailCall {
+        function: self.len()?,
+      },
+      24 => Bytecode::Apply { arity: self.len()? },
+      25 => Bytecode::TailApply { arity: self.len()? },
+      26 => Bytecode::PutList,
+      27 => Bytecode::Nil,
+      28 => Bytecode::PeekMessage {
+        branch: self.len()?,
+      },
+      29 => Bytecode::RemoveMessage,
+      30 => Bytecode::NextMessage { index: self.len()? },
+      31 => Bytecode::Wait { index: self.len()? },
+      32 => Bytecode::Try {
+        handler: self.len()?,
+      },
+      33 => Bytecode::EndTry,
+      34 => Bytecode::Raise,
+      35 => Bytecode::Undefined,
+      36 => Bytecode::Dup,
+      37 => {
+        let default = self.len()?;
+        let cases = (0..self.len()?)
+          .map(|_| Ok((self.id()?, self.len()?)))
+          .collect::<Result<_, String>>()?;
+        Bytecode::SwitchAtom { cases, default }
+      }
+      38 => {
+        let default = self.len()?;
+        let cases = (0..self.len()?)
+          .map(|_| Ok((self.i32()?, self.len()?)))
+          .collect::<Result<_, String>>()?;
+        Bytecode::SwitchNumber { cases, default }
+      }
+      39 => {
+        let default = self.len()?;
+        let cases = (0..self.len()?)
+          .map(|_| Ok((self.len()?, self.len()?)))
+          .collect::<Result<_, String>>()?;
+        Bytecode::SwitchTupleArity { cases, default }
+      }
+      40 => Bytecode::CallNative {
+        id: self.id()?,
+        arity: self.len()?,
+      },
+      opcode => return Err(format!("Invalid opcode {opcode}")),
+    };
+    Ok(ins)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::path::Path;
+
+  use crate::{
+    compile::{asm, Ctx, Machine},
+    desugar::Desugar,
+    lexer::Lexer,
+    parser::Parser,
+  };
+
+  use crate::atom::Atom;
+
+  use super::{checksum, read, write, VERSION};
+
+  #[test]
+  fn round_trip() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("main.lala");
+    let src = std::fs::read_to_string(&path).unwrap();
+    let program = Parser::new(Lexer::new(&src))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    let mut info = ctx.bytecode();
+    info.source = Some(path.display().to_string());
+
+    let bytes = write(&info);
+    let read = read(&bytes).unwrap();
+    assert_eq!(asm::disassemble(&read), asm::disassemble(&info));
+    assert_eq!(read.function("main"), info.function("main"));
+
+    let expected = Machine::new(&info).run().map(|v| v.to_string());
+    let actual = Machine::new(&read).run().map(|v| v.to_string());
+    assert_eq!(actual, expected);
+  }
+
+  #[test]
+  fn corrupted() {
+    let info = asm::assemble(
+      ".constant 0 \"hi\"\n.entry 0\n.function 0 _/0 locals 0\nload_constant 0\nreturn",
+    )
+    .unwrap();
+    let bytes = write(&info);
+
+    let mut flipped = bytes.clone();
+    flipped[10] ^= 1;
+    let mut version = bytes.clone();
+    version[4] = VERSION as u8 + 1;
+    let cases = [
+      (&b"LALA"[..], "Not a compiled module".to_string()),
+      (
+        &bytes[..bytes.len() - 1],
+        "Corrupted module: checksum mismatch".to_string(),
+      ),
+      (&flipped, "Corrupted module: checksum mismatch".to_string()),
+      (
+        &version,
+        format!(
+          "Unsupported module version {}, expected {VERSION}",
+          VERSION + 1
+        ),
+      ),
+    ];
+    for (bytes, expected) in cases {
+      assert_eq!(read(bytes).err(), Some(expected));
+    }
+
+    let unsafe_code = asm::assemble(".entry 0\n.function 0 _/0 locals 0\nreturn").unwrap();
+    assert_eq!(
+      read(&write(&unsafe_code)).err(),
+      Some("_/0: Returns with a stack of 0 at 0".to_string())
+    );
+
+    // Calls would allocate every local, so a module can't claim billions.
+    let mut huge_locals = asm::assemble(".entry 0\n.function 0 _/0 locals 0\nnil\nreturn").unwrap();
+    huge_locals.functions[0].locals = u32::MAX as usize;
+    assert_eq!(
+      read(&write(&huge_locals)).err(),
+      Some(format!("_/0: Has {} locals but uses 0", u32::MAX))
+    );
+  }
+
+  #[test]
+  fn atoms_interned_after_verify() {
+    // A string constant whose tag is changed to an atom, so that assembling
+    // the module doesn't intern the atom already.
+    let module = |code: &str| {
+      let src =
+        format!(".constant 0 \"module_test_atom\"\n.entry 0\n.function 0 _/0 locals 0\n{code}");
+      let mut bytes = write(&asm::assemble(&src).unwrap());
+      bytes[10] = 1;
+      let body = bytes.len() - 4;
+      let checksum = checksum(&bytes[..body]);
+      bytes[body..].copy_from_slice(&checksum.to_le_bytes());
+      bytes
+    };
+
+    assert_eq!(
+      read(&module("return")).err(),
+      Some("_/0: Returns with a stack of 0 at 0".to_string())
+    );
+    assert!(!Atom::is_interned("module_test_atom"));
+
+    let info = read(&module("load_constant 0\nreturn")).unwrap();
+    let value = Machine::new(&info).run().map(|v| v.to_string());
+    assert_eq!(value, Ok("#module_test_atom".to_string()));
+  }
+}
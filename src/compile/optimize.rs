@@ -0,0 +1,496 @@
+//! Optimizations on compiled bytecode.
+//!
+//! Case trees test the same occurrence once per branch, reloading it from the
+//! scrutinee every time, and their branches end with jumps to jumps. These
+//! passes cache repeated occurrences in locals, thread jumps and remove the
+//! code left unreachable.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::{Bytecode, BytecodeInfo};
+
+/// Number of instructions before and after optimizing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Counts {
+  pub before: usize,
+  pub after: usize,
+}
+
+pub fn optimize(info: &mut BytecodeInfo) -> Counts {
+  let before = info.bytecode.len();
+  cache_occurrences(info);
+  thread_jumps(&mut info.bytecode);
+  remove_dead_code(info);
+  Counts {
+    before,
+    after: info.bytecode.len(),
+  }
+}
+
+/// Replaces each instruction with the instructions at the same index of
+/// `replacements`, which may be none, and fixes the jumps and function
+/// entries.
+fn rebuild(info: &mut BytecodeInfo, replacements: Vec<Vec<Bytecode>>) {
+  let mut bytecode = Vec::with_capacity(info.bytecode.len());
+  let mut moved = Vec::with_capacity(replacements.len() + 1);
+  for replacement in replacements {
+    moved.push(bytecode.len());
+    bytecode.extend(replacement);
+  }
+  moved.push(bytecode.len());
+  for ins in &mut bytecode {
+    for target in ins.targets_mut() {
+      *target = moved[*target];
+    }
+  }
+  for function in &mut info.functions {
+    function.entry = moved[function.entry];
+  }
+  info.bytecode = bytecode;
+}
+
+/// A step from a value to one of its parts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Step {
+  Tuple(usize),
+  Hd,
+  Tl,
+}
+
+impl Step {
+  fn of(ins: &Bytecode) -> Option<Self> {
+    match ins {
+      Bytecode::GetTuple { index } => Some(Step::Tuple(*index)),
+      Bytecode::GetHd => Some(Step::Hd),
+      Bytecode::GetTl => Some(Step::Tl),
+      _ => None,
+    }
+  }
+}
+
+/// An occurrence, loaded by `GetLocal` followed by some steps.
+type Occurrence = (usize, Vec<Step>);
+
+/// Keeps the occurrences a function loads more than once in new locals, so
+/// that they are loaded from their local, or from the local of one of their
+/// parents, until the local they start from changes.
+fn cache_occurrences(info: &mut BytecodeInfo) {
+  let targets: HashSet<usize> = info.bytecode.iter().flat_map(Bytecode::targets).collect();
+  let mut replacements: Vec<Vec<Bytecode>> =
+    info.bytecode.iter().map(|ins| vec![ins.clone()]).collect();
+  for function in 0..info.functions.len() {
+    let mut cache = Cache {
+      bytecode: &info.bytecode,
+      code: info.code(function),
+      locals: info.functions[function].locals,
+      loads: HashMap::new(),
+      cached: vec![],
+    };
+
+    // Nothing may jump into the middle of the steps of a load.
+    for ip in cache.code.clone() {
+      let Bytecode::GetLocal { id } = info.bytecode[ip] else {
+        continue;
+      };
+      let steps: Vec<Step> = (ip + 1..cache.code.end)
+        .map_while(|ip| Step::of(&info.bytecode[ip]).filter(|_| !targets.contains(&ip)))
+        .collect();
+      if !steps.is_empty() {
+        cache.loads.insert(ip, (id, steps));
+      }
+    }
+    let mut seen: HashMap<Occurrence, usize> = HashMap::new();
+    for (id, steps) in cache.loads.values() {
+      for len in 1..=steps.len() {
+        *seen.entry((*id, steps[..len].to_vec())).or_default() += 1;
+      }
+    }
+    cache.cached = seen
+      .into_iter()
+      .filter(|(_, n)| *n > 1)
+      .map(|(o, _)| o)
+      .collect();
+    cache.cached.sort();
+
+    // Keeping an occurrence costs a `Dup` and a `SetLocal` each time it is
+    // loaded in full, so drop the ones that don't pay for themselves.
+    let (mut size, mut loads) = cache.emit();
+    let mut dropped = true;
+    while dropped {
+      dropped = false;
+      for idx in (0..cache.cached.len()).rev() {
+        let occurrence = cache.cached.remove(idx);
+        let (fewer, without) = cache.emit();
+        if fewer <= size {
+          (size, loads) = (fewer, without);
+          dropped = true;
+        } else {
+          cache.cached.insert(idx, occurrence);
+        }
+      }
+    }
+
+    for (ip, load) in loads {
+      let len = cache.loads[&ip].1.len() + 1;
+      replacements[ip] = load;
+      for replacement in &mut replacements[ip + 1..ip + len] {
+        replacement.clear();
+      }
+    }
+    info.functions[function].locals += cache.cached.len();
+  }
+  rebuild(info, replacements);
+}
+
+struct Cache<'a> {
+  bytecode: &'a [Bytecode],
+  code: std::ops::Range<usize>,
+  /// Number of locals of the function without the cached occurrences.
+  locals: usize,
+  /// The occurrences the function loads, by the index they start at.
+  loads: HashMap<usize, Occurrence>,
+  cached: Vec<Occurrence>,
+}
+
+impl Cache<'_> {
+  /// The code loading each reachable occurrence, along with the size of the
+  /// function once it is used.
+  fn emit(&self) -> (usize, HashMap<usize, Vec<Bytecode>>) {
+    let available = self.available();
+    let mut size = self.code.len();
+    let mut loads = HashMap::new();
+    for (ip, occurrence) in &self.loads {
+      if let Some(state) = available.get(ip) {
+        let (load, _) = self.load(occurrence, state);
+        size = size + load.len() - occurrence.1.len() - 1;
+        loads.insert(*ip, load);
+      }
+    }
+    (size, loads)
+  }
+
+  /// Loads `occurrence` from the closest of its cached parents whose local
+  /// is up to date in `state`, keeping the cached ones loaded on the way.
+  /// Gives back the code along with the occurrences kept.
+  fn load(&self, (id, steps): &Occurrence, state: &BTreeSet<usize>) -> (Vec<Bytecode>, Vec<usize>) {
+    let cached = |len: usize| {
+      let prefix = (*id, steps[..len].to_vec());
+      self.cached.binary_search(&prefix).ok()
+    };
+    let from = (1..=steps.len()).rev().find_map(|len| {
+      cached(len)
+        .filter(|idx| state.contains(idx))
+        .map(|idx| (len, idx))
+    });
+    let (mut code, start) = match from {
+      Some((len, idx)) => (
+        vec![Bytecode::GetLocal {
+          id: self.locals + idx,
+        }],
+        len,
+      ),
+      None => (vec![Bytecode::GetLocal { id: *id }], 0),
+    };
+    let mut kept = vec![];
+    for len in start + 1..=steps.len() {
+      code.push(match steps[len - 1] {
+        Step::Tuple(index) => Bytecode::GetTuple { index },
+        Step::Hd => Bytecode::GetHd,
+        Step::Tl => Bytecode::GetTl,
+      });
+      if let Some(idx) = cached(len) {
+        code.push(Bytecode::Dup);
+        code.push(Bytecode::SetLocal {
+          id: self.locals + idx,
+        });
+        kept.push(idx);
+      }
+    }
+    (code, kept)
+  }
+
+  /// The cached occurrences whose local is up to date before each reachable
+  /// instruction.
+  fn available(&self) -> HashMap<usize, BTreeSet<usize>> {
+    let mut available: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    available.insert(self.code.start, BTreeSet::new());
+    let mut pending = vec![self.code.start];
+    while let Some(ip) = pending.pop() {
+      let mut out = available[&ip].clone();
+      let mut next = ip + 1;
+      if let Some(occurrence) = self.loads.get(&ip) {
+        out.extend(self.load(occurrence, &out).1);
+        next = ip + occurrence.1.len() + 1;
+      }
+      let ins = &self.bytecode[next - 1];
+      if let Bytecode::SetLocal { id } = ins {
+        out.retain(|idx| self.cached[*idx].0 != *id);
+      }
+      let mut successors = vec![];
+      if ins.falls_through() && next < self.code.end {
+        successors.push((next, out.clone()));
+      }
+      match ins {
+        // The locals may have changed anywhere in the body of the `try`.
+        Bytecode::Try { handler } => successors.push((*handler, BTreeSet::new())),
+        ins => successors.extend(
+          ins
+            .targets()
+            .into_iter()
+            .map(|target| (target, out.clone())),
+        ),
+      }
+      for (ip, state) in successors {
+        match available.get_mut(&ip) {
+          None => {
+            available.insert(ip, state);
+            pending.push(ip);
+          }
+          Some(old) => {
+            let new: BTreeSet<usize> = old.intersection(&state).copied().collect();
+            if new != *old {
+              *old = new;
+              pending.push(ip);
+            }
+          }
+        }
+      }
+    }
+    available
+  }
+}
+
+/// Makes jumps to jumps go to their final target, and jumps to returns
+/// return.
+fn thread_jumps(bytecode: &mut [Bytecode]) {
+  let last = |mut target: usize, bytecode: &[Bytecode]| {
+    // Bounded, as jumps may go around in circles.
+    for _ in 0..bytecode.len() {
+      match bytecode.get(target) {
+        Some(Bytecode::Jump { index }) if *index != target => target = *index,
+        _ => break,
+      }
+    }
+    target
+  };
+  for idx in 0..bytecode.len() {
+    if let Bytecode::Jump { index } = bytecode[idx] {
+      if let Some(Bytecode::Return) = bytecode.get(last(index, bytecode)) {
+        bytecode[idx] = Bytecode::Return;
+        continue;
+      }
+    }
+    let targets: Vec<usize> = bytecode[idx]
+      .targets()
+      .into_iter()
+      .map(|target| last(target, bytecode))
+      .collect();
+    for (target, last) in bytecode[idx].targets_mut().into_iter().zip(targets) {
+      *target = last;
+    }
+  }
+}
+
+/// Removes the instructions no function can reach, and the jumps to the
+/// instruction that follows once they are gone.
+fn remove_dead_code(info: &mut BytecodeInfo) {
+  let mut reachable = vec![false; info.bytecode.len()];
+  let mut pending: Vec<usize> = info.functions.iter().map(|f| f.entry).collect();
+  while let Some(ip) = pending.pop() {
+    if reachable.get(ip).copied().unwrap_or(true) {
+      continue;
+    }
+    reachable[ip] = true;
+    let ins = &info.bytecode[ip];
+    if ins.falls_through() {
+      pending.push(ip + 1);
+    }
+    pending.extend(ins.targets());
+  }
+  let replacements = info
+    .bytecode
+    .iter()
+    .enumerate()
+    .map(|(ip, ins)| match ins {
+      _ if !reachable[ip] => vec![],
+      Bytecode::Jump { index } if *index > ip && !reachable[ip + 1..*index].contains(&true) => {
+        vec![]
+      }
+      ins => vec![ins.clone()],
+    })
+    .collect();
+  rebuild(info, replacements);
+}
+
+#[cfg(test)]
+mod test {
+  use std::path::Path;
+
+  use crate::{
+    compile::{
+      asm,
+      verify::{self, verify},
+      Bytecode, Ctx, Machine,
+    },
+    desugar::Desugar,
+    lexer::Lexer,
+    parser::Parser,
+  };
+
+  use super::{optimize, Counts};
+
+  fn compile(src: &str, expr: &str) -> super::BytecodeInfo {
+    let program = Parser::new(Lexer::new(src))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    if !expr.is_empty() {
+      let expr = Parser::new(Lexer::new(expr))
+        .expression()
+        .unwrap()
+        .desugar()
+        .unwrap();
+      ctx.fn_clause(expr);
+    }
+    ctx.bytecode()
+  }
+
+  fn run(info: &super::BytecodeInfo) -> Result<String, String> {
+    Machine::new(info).run().map(|v| v.to_string())
+  }
+
+  #[test]
+  fn examples() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths = vec![root.join("main.lala")];
+    for entry in std::fs::read_dir(root.join("examples")).unwrap() {
+      paths.push(entry.unwrap().path());
+    }
+    for path in paths {
+      let path = path.display();
+      let src = std::fs::read_to_string(path.to_string()).unwrap();
+      let expected = run(&compile(&src, ""));
+      let mut info = compile(&src, "");
+      optimize(&mut info);
+      assert_eq!(verify(&info), Ok(()), "{path}");
+      assert_eq!(run(&info), expected, "{path}");
+
+      // No jump is left going to another jump or to a return.
+      for (ip, ins) in info.bytecode.iter().enumerate() {
+        for target in ins.targets() {
+          let chained =
+            matches!(&info.bytecode[target], Bytecode::Jump { index } if *index != target);
+          assert!(!chained, "{path}: jump chain from {ip}");
+        }
+        if let Bytecode::Jump { index } = ins {
+          assert_ne!(
+            info.bytecode[*index],
+            Bytecode::Return,
+            "{path}: jump to return at {ip}"
+          );
+        }
+      }
+      // Every instruction left is reachable.
+      for id in 0..info.functions.len() {
+        let depths = verify::depths(&info, id).unwrap();
+        assert!(
+          depths.iter().all(Option::is_some),
+          "{path}: dead code in {id}"
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn occurrences() {
+    let src = r#"
+fn classify(x) ->
+  case x of
+    {#a, [1 | _]} -> 1;
+    {#a, [2 | _]} -> 2;
+    {#a, [_ | [3 | _]]} -> 3;
+    {#b, _} -> 4;
+    _ -> 5
+  end
+"#;
+    let cases = [
+      ("classify({#a, [1]})", "1"),
+      ("classify({#a, [2, 2]})", "2"),
+      ("classify({#a, [0, 3]})", "3"),
+      ("classify({#a, [0, 4]})", "5"),
+      ("classify({#b, 1})", "4"),
+      ("classify(#c)", "5"),
+    ];
+    let accesses = |info: &super::BytecodeInfo| {
+      let code = info.code(info.function("classify").unwrap());
+      info.bytecode[code]
+        .iter()
+        .filter(|ins| {
+          matches!(
+            ins,
+            Bytecode::GetTuple { .. } | Bytecode::GetHd | Bytecode::GetTl
+          )
+        })
+        .count()
+    };
+    for (expr, expected) in cases {
+      let mut info = compile(src, expr);
+      let function = info.function("classify").unwrap();
+      let locals = info.functions[function].locals;
+      let before = accesses(&info);
+      optimize(&mut info);
+      let after = accesses(&info);
+      assert!(after < before, "{before} -> {after}");
+      // The repeated loads come from the locals keeping them instead.
+      let code = &info.bytecode[info.code(function)];
+      assert!(info.functions[function].locals > locals);
+      assert!(code
+        .windows(2)
+        .any(|pair| matches!(pair, [Bytecode::Dup, Bytecode::SetLocal { id }] if *id >= locals)));
+      assert!(code
+        .iter()
+        .any(|ins| matches!(ins, Bytecode::GetLocal { id } if *id >= locals)));
+      assert_eq!(verify(&info), Ok(()));
+      assert_eq!(run(&info), Ok(expected.to_string()), "{expr}");
+    }
+  }
+
+  #[test]
+  fn jumps() {
+    let src = r#"
+.entry 0
+.function 0 _/0 locals 0
+  jump a
+  push_number 0
+b:
+  jump c
+a:
+  jump b
+c:
+  push_number 1
+  jump d
+d:
+  jump e
+  nil
+e:
+  return
+"#;
+    let mut info = asm::assemble(src).unwrap();
+    let counts = optimize(&mut info);
+    assert_eq!(
+      counts,
+      Counts {
+        before: 9,
+        after: 2
+      }
+    );
+    assert_eq!(
+      info.bytecode,
+      [Bytecode::PushNumber { val: 1 }, Bytecode::Return]
+    );
+    assert_eq!(run(&info), Ok("1".to_string()));
+  }
+}
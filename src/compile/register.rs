@@ -0,0 +1,1364 @@
+//! A register machine running the same programs as the stack machine.
+//!
+//! The bytecode of each function is translated into three-address
+//! instructions over a window of registers: its locals come first, followed
+//! by one register for every slot its part of the stack can reach. The stack
+//! depth at each instruction is known from verification, so stack slot `n`
+//! simply becomes register `locals + n`.
+//!
+//! During translation, values loaded from locals are not copied to their
+//! stack slot. Instructions read the local directly instead, and a value
+//! that ends up in a local is computed right into it. Slots are only filled
+//! in where control flow meets, so that every path agrees on where values
+//! are.
+
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use crate::{
+  atom::Atom,
+  builtins::Builtin,
+  heap::{Heap, HeapSize},
+  host::Host,
+  process::{Context, Crash, Outcome, Pid, Process, Scheduler, Status, Term},
+  value::{live_size, Value},
+};
+
+use super::{
+  arithmetic, atom_case, atom_cases, binary, callee, switch_targets_mut, trace::Tracer, verify,
+  Bytecode, BytecodeInfo, Constant,
+};
+
+/// Where an instruction reads one of its values from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+  /// A register that is still used afterwards, so its value is cloned.
+  Copy(usize),
+  /// A stack slot that is dead afterwards, so its value is moved out.
+  Move(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction {
+  Move {
+    dst: usize,
+    src: Operand,
+  },
+  LoadNumber {
+    dst: usize,
+    val: i32,
+  },
+  LoadConstant {
+    dst: usize,
+    id: u16,
+  },
+  LoadFunction {
+    dst: usize,
+    function: usize,
+  },
+  Nil {
+    dst: usize,
+  },
+  /// One of the binary operations of the stack machine: `Add`, `Sub`, `Mul`,
+  /// `Div` or `Eq`.
+  Binary {
+    op: Bytecode,
+    dst: usize,
+    lhs: Operand,
+    rhs: Operand,
+  },
+  MakeTuple {
+    dst: usize,
+    elements: Vec<Operand>,
+  },
+  PutList {
+    dst: usize,
+    hd: Operand,
+    tl: Operand,
+  },
+  GetTuple {
+    dst: usize,
+    src: Operand,
+    index: usize,
+  },
+  GetHd {
+    dst: usize,
+    src: Operand,
+  },
+  GetTl {
+    dst: usize,
+    src: Operand,
+  },
+  TestExact {
+    src: Operand,
+    id: u16,
+    branch: usize,
+  },
+  TestTuple {
+    src: Operand,
+    size: usize,
+    branch: usize,
+  },
+  TestCons {
+    src: Operand,
+    branch: usize,
+  },
+  TestNil {
+    src: Operand,
+    branch: usize,
+  },
+  Jump {
+    target: usize,
+  },
+  MatchFail,
+  CallBuiltin {
+    dst: usize,
+    builtin: Builtin,
+    arguments: Vec<Operand>,
+  },
+  /// Calls the native named by the atom constant `id`.
+  CallNative {
+    dst: usize,
+    id: u16,
+    arguments: Vec<Operand>,
+  },
+  Call {
+    dst: usize,
+    function: usize,
+    arguments: Vec<Operand>,
+  },
+  TailCall {
+    function: usize,
+    arguments: Vec<Operand>,
+  },
+  Apply {
+    dst: usize,
+    function: Operand,
+    arguments: Vec<Operand>,
+  },
+  TailApply {
+    function: Operand,
+    arguments: Vec<Operand>,
+  },
+  Return {
+    src: Operand,
+  },
+  PeekMessage {
+    dst: usize,
+    branch: usize,
+  },
+  RemoveMessage,
+  NextMessage {
+    target: usize,
+  },
+  Wait {
+    target: usize,
+  },
+  /// Installs a handler that starts with the class of the exception in `dst`
+  /// and its reason in the register after it.
+  Try {
+    handler: usize,
+    dst: usize,
+  },
+  EndTry,
+  Raise {
+    class: Operand,
+    reason: Operand,
+  },
+  /// Cases are sorted by atom index.
+  SwitchAtom {
+    src: Operand,
+    cases: Vec<(Atom, usize)>,
+    default: usize,
+  },
+  SwitchNumber {
+    src: Operand,
+    cases: Vec<(i32, usize)>,
+    default: usize,
+  },
+  SwitchTupleArity {
+    src: Operand,
+    cases: Vec<(usize, usize)>,
+    default: usize,
+  },
+}
+
+impl Instruction {
+  /// The register the instruction writes its result to, if any.
+  fn dst_mut(&mut self) -> Option<&mut usize> {
+    match self {
+      Instruction::Move { dst, .. }
+      | Instruction::LoadNumber { dst, .. }
+      | Instruction::LoadConstant { dst, .. }
+      | Instruction::LoadFunction { dst, .. }
+      | Instruction::Nil { dst }
+      | Instruction::Binary { dst, .. }
+      | Instruction::MakeTuple { dst, .. }
+      | Instruction::PutList { dst, .. }
+      | Instruction::GetTuple { dst, .. }
+      | Instruction::GetHd { dst, .. }
+      | Instruction::GetTl { dst, .. }
+      | Instruction::CallBuiltin { dst, .. }
+      | Instruction::CallNative { dst, .. }
+      | Instruction::Call { dst, .. }
+      | Instruction::Apply { dst, .. } => Some(dst),
+      _ => None,
+    }
+  }
+
+  fn targets_mut(&mut self) -> Vec<&mut usize> {
+    match self {
+      Instruction::TestExact { branch, .. }
+      | Instruction::TestTuple { branch, .. }
+      | Instruction::TestCons { branch, .. }
+      | Instruction::TestNil { branch, .. }
+      | Instruction::PeekMessage { branch, .. } => vec![branch],
+      Instruction::Jump { target }
+      | Instruction::NextMessage { target }
+      | Instruction::Wait { target } => vec![target],
+      Instruction::Try { handler, .. } => vec![handler],
+      Instruction::SwitchAtom { cases, default, .. } => switch_targets_mut(cases, default),
+      Instruction::SwitchNumber { cases, default, .. } => switch_targets_mut(cases, default),
+      Instruction::SwitchTupleArity { cases, default, .. } => switch_targets_mut(cases, default),
+      _ => vec![],
+    }
+  }
+}
+
+/// Where a function starts and how many registers its calls need.
+#[derive(Clone, Copy, Debug)]
+struct Layout {
+  entry: usize,
+  registers: usize,
+}
+
+/// The register code of a module.
+pub struct Program<'a> {
+  info: &'a BytecodeInfo,
+  pub code: Vec<Instruction>,
+  layouts: Vec<Layout>,
+  constants: Vec<Value>,
+}
+
+impl<'a> Program<'a> {
+  /// Translates the bytecode of `info`, which must pass verification.
+  pub fn new(info: &'a BytecodeInfo) -> Result<Self, String> {
+    verify::verify(info)?;
+    let mut translator = Translator {
+      info,
+      code: vec![],
+      map: vec![0; info.bytecode.len()],
+      locals: 0,
+      stack: vec![],
+      last: None,
+      registers: 0,
+    };
+    let mut layouts = vec![];
+    for id in 0..info.functions.len() {
+      layouts.push(translator.function(id)?);
+    }
+    let Translator { mut code, map, .. } = translator;
+    for ins in &mut code {
+      for target in ins.targets_mut() {
+        *target = map[*target];
+      }
+    }
+    let constants = info
+      .constants
+      .keys()
+      .map(|c| match c.clone() {
+        Constant::Number(n) => Value::Number(n),
+        Constant::Atom(a) => Value::Atom(a),
+        Constant::String(s) => Value::String(s.into()),
+      })
+      .collect();
+    Ok(Self {
+      info,
+      code,
+      layouts,
+      constants,
+    })
+  }
+
+  fn atom(&self, id: u16) -> Atom {
+    match self.constants[id as usize] {
+      Value::Atom(atom) => atom,
+      _ => unreachable!(),
+    }
+  }
+}
+
+/// A value of the stack during translation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Entry {
+  /// In the register of its own slot.
+  Slot,
+  /// Still in the given register, which is not overwritten until the entry
+  /// is popped or copied to its slot.
+  Register(usize),
+}
+
+struct Translator<'a> {
+  info: &'a BytecodeInfo,
+  code: Vec<Instruction>,
+  /// The register instruction each bytecode instruction starts at.
+  map: Vec<usize>,
+  locals: usize,
+  stack: Vec<Entry>,
+  /// The last instruction, while its result only goes to a stack slot and
+  /// could be written elsewhere instead.
+  last: Option<usize>,
+  /// The registers the function needs so far.
+  registers: usize,
+}
+
+impl Translator<'_> {
+  fn function(&mut self, id: usize) -> Result<Layout, String> {
+    let depths = verify::depths(self.info, id)?;
+    let range = self.info.code(id);
+    let mut starts = vec![false; range.len()];
+    starts[0] = true;
+    // Unreachable instructions aren't verified, so their targets may be
+    // anywhere.
+    for ip in range
+      .clone()
+      .filter(|ip| depths[ip - range.start].is_some())
+    {
+      for target in self.info.bytecode[ip].targets() {
+        starts[target - range.start] = true;
+      }
+    }
+    self.locals = self.info.functions[id].locals;
+    self.registers = self.locals;
+    self.stack.clear();
+    let entry = self.code.len();
+    for ip in range.clone() {
+      if starts[ip - range.start] {
+        self.fill();
+        self.last = None;
+      }
+      self.map[ip] = self.code.len();
+      let Some(depth) = depths[ip - range.start] else {
+        continue;
+      };
+      if starts[ip - range.start] {
+        self.stack = vec![Entry::Slot; depth];
+      }
+      let ins = &self.info.bytecode[ip];
+      self.instruction(ins);
+      if !ins.falls_through() {
+        self.stack.clear();
+      }
+    }
+    Ok(Layout {
+      entry,
+      registers: self.registers,
+    })
+  }
+
+  fn instruction(&mut self, ins: &Bytecode) {
+    match ins {
+      Bytecode::Return => {
+        let src = self.pop();
+        self.emit(Instruction::Return { src });
+      }
+      Bytecode::PushNumber { val } => {
+        let dst = self.push();
+        self.result(Instruction::LoadNumber { dst, val: *val });
+      }
+      Bytecode::LoadConstant { id } => {
+        let dst = self.push();
+        self.result(Instruction::LoadConstant { dst, id: *id });
+      }
+      Bytecode::LoadFunction { function } => {
+        let dst = self.push();
+        self.result(Instruction::LoadFunction {
+          dst,
+          function: *function,
+        });
+      }
+      Bytecode::Nil => {
+        let dst = self.push();
+        self.result(Instruction::Nil { dst });
+      }
+      Bytecode::GetLocal { id } => self.stack.push(Entry::Register(*id)),
+      Bytecode::SetLocal { id } => self.set_local(*id),
+      Bytecode::Dup => {
+        let top = match self.stack.last().unwrap() {
+          Entry::Slot => Entry::Register(self.locals + self.stack.len() - 1),
+          register => *register,
+        };
+        self.stack.push(top);
+      }
+      Bytecode::TestExact { id, branch } => {
+        let src = self.pop();
+        self.fill();
+        self.emit(Instruction::TestExact {
+          src,
+          id: *id,
+          branch: *branch,
+        });
+      }
+      Bytecode::TestTuple { size, branch } => {
+        let src = self.pop();
+        self.fill();
+        self.emit(Instruction::TestTuple {
+          src,
+          size: *size,
+          branch: *branch,
+        });
+      }
+      Bytecode::TestCons { branch } => {
+        let src = self.pop();
+        self.fill();
+        self.emit(Instruction::TestCons {
+          src,
+          branch: *branch,
+        });
+      }
+      Bytecode::TestNil { branch } => {
+        let src = self.pop();
+        self.fill();
+        self.emit(Instruction::TestNil {
+          src,
+          branch: *branch,
+        });
+      }
+      Bytecode::MakeTuple { size } => {
+        let elements = self.pop_n(*size);
+        let dst = self.push();
+        self.result(Instruction::MakeTuple { dst, elements });
+      }
+      Bytecode::GetTuple { index } => {
+        let src = self.pop();
+        let dst = self.push();
+        self.result(Instruction::GetTuple {
+          dst,
+          src,
+          index: *index,
+        });
+      }
+      Bytecode::GetHd => {
+        let src = self.pop();
+        let dst = self.push();
+        self.result(Instruction::GetHd { dst, src });
+      }
+      Bytecode::GetTl => {
+        let src = self.pop();
+        let dst = self.push();
+        self.result(Instruction::GetTl { dst, src });
+      }
+      Bytecode::Jump { index } => {
+        self.fill();
+        self.emit(Instruction::Jump { target: *index });
+      }
+      Bytecode::MatchFail => self.emit(Instruction::MatchFail),
+      Bytecode::CallBuiltin { builtin } => {
+        let arguments = self.pop_n(builtin.arity());
+        let dst = self.push();
+        self.result(Instruction::CallBuiltin {
+          dst,
+          builtin: *builtin,
+          arguments,
+        });
+      }
+      Bytecode::CallNative { id, arity } => {
+        let arguments = self.pop_n(*arity);
+        let dst = self.push();
+        self.result(Instruction::CallNative {
+          dst,
+          id: *id,
+          arguments,
+        });
+      }
+      Bytecode::Add | Bytecode::Sub | Bytecode::Mul | Bytecode::Div | Bytecode::Eq => {
+        let rhs = self.pop();
+        let lhs = self.pop();
+        let dst = self.push();
+        self.result(Instruction::Binary {
+          op: ins.clone(),
+          dst,
+          lhs,
+          rhs,
+        });
+      }
+      Bytecode::PutList => {
+        let tl = self.pop();
+        let hd = self.pop();
+        let dst = self.push();
+        self.result(Instruction::PutList { dst, hd, tl });
+      }
+      Bytecode::Call { function } => {
+        let arguments = self.pop_n(self.info.functions[*function].arity);
+        let dst = self.push();
+        self.result(Instruction::Call {
+          dst,
+          function: *function,
+          arguments,
+        });
+      }
+      Bytecode::TailCall { function } => {
+        let arguments = self.pop_n(self.info.functions[*function].arity);
+        self.emit(Instruction::TailCall {
+          function: *function,
+          arguments,
+        });
+      }
+      Bytecode::Apply { arity } => {
+        let arguments = self.pop_n(*arity);
+        let function = self.pop();
+        let dst = self.push();
+        self.result(Instruction::Apply {
+          dst,
+          function,
+          arguments,
+        });
+      }
+      Bytecode::TailApply { arity } => {
+        let arguments = self.pop_n(*arity);
+        let function = self.pop();
+        self.emit(Instruction::TailApply {
+          function,
+          arguments,
+        });
+      }
+      Bytecode::PeekMessage { branch } => {
+        self.fill();
+        let dst = self.push();
+        self.emit(Instruction::PeekMessage {
+          dst,
+          branch: *branch,
+        });
+      }
+      Bytecode::RemoveMessage => self.emit(Instruction::RemoveMessage),
+      Bytecode::NextMessage { index } => {
+        self.fill();
+        self.emit(Instruction::NextMessage { target: *index });
+      }
+      Bytecode::Wait { index } => {
+        self.fill();
+        self.emit(Instruction::Wait { target: *index });
+      }
+      Bytecode::Try { handler } => {
+        self.fill();
+        let dst = self.locals + self.stack.len();
+        self.registers = self.registers.max(dst + 2);
+        self.emit(Instruction::Try {
+          handler: *handler,
+          dst,
+        });
+      }
+      Bytecode::EndTry => self.emit(Instruction::EndTry),
+      Bytecode::Raise => {
+        let reason = self.pop();
+        let class = self.pop();
+        self.emit(Instruction::Raise { class, reason });
+      }
+      Bytecode::SwitchAtom { cases, default } => {
+        let src = self.pop();
+        self.fill();
+        self.emit(Instruction::SwitchAtom {
+          src,
+          cases: atom_cases(&self.info.constants, cases),
+          default: *default,
+        });
+      }
+      Bytecode::SwitchNumber { cases, default } => {
+        let src = self.pop();
+        self.fill();
+        self.emit(Instruction::SwitchNumber {
+          src,
+          cases: cases.clone(),
+          default: *default,
+        });
+      }
+      Bytecode::SwitchTupleArity { cases, default } => {
+        let src = self.pop();
+        self.fill();
+        self.emit(Instruction::SwitchTupleArity {
+          src,
+          cases: cases.clone(),
+          default: *default,
+        });
+      }
+      Bytecode::Undefined => unreachable!("verified bytecode has no undefined instructions"),
+    }
+  }
+
+  fn emit(&mut self, ins: Instruction) {
+    self.code.push(ins);
+    self.last = None;
+  }
+
+  /// Emits an instruction whose result goes to a stack slot.
+  fn result(&mut self, ins: Instruction) {
+    self.emit(ins);
+    self.last = Some(self.code.len() - 1);
+  }
+
+  /// Pushes a value computed into its slot, returning the register.
+  fn push(&mut self) -> usize {
+    self.stack.push(Entry::Slot);
+    let register = self.locals + self.stack.len() - 1;
+    self.registers = self.registers.max(register + 1);
+    register
+  }
+
+  fn pop(&mut self) -> Operand {
+    match self.stack.pop().unwrap() {
+      Entry::Slot => Operand::Move(self.locals + self.stack.len()),
+      Entry::Register(register) => Operand::Copy(register),
+    }
+  }
+
+  fn pop_n(&mut self, n: usize) -> Vec<Operand> {
+    let mut operands: Vec<_> = (0..n).map(|_| self.pop()).collect();
+    operands.reverse();
+    operands
+  }
+
+  /// Copies the values still in other registers to their slots, where
+  /// control flow meets expects them.
+  fn fill(&mut self) {
+    self.fill_if(|_| true);
+  }
+
+  fn fill_if(&mut self, mut pred: impl FnMut(usize) -> bool) {
+    for depth in 0..self.stack.len() {
+      if let Entry::Register(register) = self.stack[depth] {
+        if pred(register) {
+          let dst = self.locals + depth;
+          self.registers = self.registers.max(dst + 1);
+          self.emit(Instruction::Move {
+            dst,
+            src: Operand::Copy(register),
+          });
+          self.stack[depth] = Entry::Slot;
+        }
+      }
+    }
+  }
+
+  fn set_local(&mut self, id: usize) {
+    let src = self.pop();
+    if src == Operand::Copy(id) {
+      return;
+    }
+    // Values loaded from the local must be saved before it changes.
+    self.fill_if(|register| register == id);
+    if let (Operand::Move(slot), Some(last)) = (src, self.last) {
+      let dst = self.code[last].dst_mut().unwrap();
+      if *dst == slot {
+        *dst = id;
+        self.last = None;
+        return;
+      }
+    }
+    self.emit(Instruction::Move { dst: id, src });
+  }
+}
+
+/// Runs register code, with the same processes as [`super::Machine`].
+///
+/// Tracers attached to it are only told about calls and returns.
+pub struct Machine<'a> {
+  program: &'a Program<'a>,
+  host: Host,
+  heap_limit: Option<usize>,
+  tracer: Option<Rc<RefCell<dyn Tracer>>>,
+  scheduler: Scheduler<Task<'a>>,
+}
+
+impl<'a> Machine<'a> {
+  pub fn new(program: &'a Program<'a>) -> Self {
+    Self {
+      program,
+      host: Host::default(),
+      heap_limit: None,
+      tracer: None,
+      scheduler: Scheduler::new(),
+    }
+  }
+
+  pub fn with_host(mut self, host: Host) -> Self {
+    self.host = host;
+    self
+  }
+
+  /// Gives back the host, once the machine is done with it.
+  pub fn into_host(self) -> Host {
+    self.host
+  }
+
+  pub fn with_heap_limit(mut self, bytes: usize) -> Self {
+    self.heap_limit = Some(bytes);
+    self
+  }
+
+  pub fn with_tracer(mut self, tracer: Rc<RefCell<dyn Tracer>>) -> Self {
+    self.tracer = Some(tracer);
+    self
+  }
+
+  /// Runs the entry of the module in a new process until it returns.
+  pub fn run(&mut self) -> Result<Value, String> {
+    let entry = self.program.info.entry.ok_or("The module has no entry")?;
+    self.apply(entry, vec![]).map_err(|crash| crash.to_string())
+  }
+
+  /// Calls the function `name` with `arguments` in a new process until it
+  /// returns.
+  pub fn call(&mut self, name: &str, arguments: Vec<Value>) -> Result<Value, String> {
+    let info = self.program.info;
+    let function = info
+      .function(name)
+      .ok_or(format!("Unbound variable {name}"))?;
+    let arity = info.functions[function].arity;
+    if arity != arguments.len() {
+      let len = arguments.len();
+      return Err(format!("Expected {arity} arguments but got {len}"));
+    }
+    self
+      .apply(function, arguments)
+      .map_err(|crash| crash.to_string())
+  }
+
+  /// Calls the function at index `function`, which must take as many
+  /// `arguments` as given, and returns how its process ended.
+  pub fn apply(&mut self, function: usize, arguments: Vec<Value>) -> Outcome<Value> {
+    let pid = self.spawn(function, arguments);
+    self.scheduler.outcome(&mut self.host, pid)
+  }
+
+  fn spawn(&mut self, function: usize, arguments: Vec<Value>) -> Pid {
+    let task = Task::call(self.program, function, arguments)
+      .with_heap_limit(self.heap_limit)
+      .with_tracer(self.tracer.clone());
+    self.scheduler.spawn(task)
+  }
+}
+
+/// A process of the register machine.
+pub struct Task<'a> {
+  program: &'a Program<'a>,
+  ip: usize,
+  /// The registers of every call, those of the running function from `base`.
+  registers: Vec<Value>,
+  base: usize,
+  frames: Vec<Frame>,
+  /// Index of the next message `PeekMessage` looks at.
+  cursor: usize,
+  handlers: Vec<Handler>,
+  heap: Heap,
+  tracer: Option<Rc<RefCell<dyn Tracer>>>,
+  /// The function the task starts with, until its call is traced.
+  start: Option<usize>,
+}
+
+/// Where to continue once the running function returns.
+struct Frame {
+  ip: usize,
+  base: usize,
+  /// The register of the caller the result goes to.
+  dst: usize,
+}
+
+/// An exception handler installed by `Try`.
+struct Handler {
+  ip: usize,
+  dst: usize,
+  /// Number of frames when the handler was installed.
+  frames: usize,
+  base: usize,
+  /// Number of registers when the handler was installed.
+  registers: usize,
+}
+
+impl<'a> Task<'a> {
+  /// A task that calls entry `function` of the function table with
+  /// `arguments`.
+  pub fn call(program: &'a Program<'a>, function: usize, arguments: Vec<Value>) -> Self {
+    let Layout { entry, registers } = program.layouts[function];
+    let mut arguments = arguments;
+    arguments.resize(registers, Value::default());
+    Self {
+      program,
+      ip: entry,
+      registers: arguments,
+      base: 0,
+      frames: vec![],
+      cursor: 0,
+      handlers: vec![],
+      heap: Heap::default(),
+      tracer: None,
+      start: Some(function),
+    }
+  }
+
+  pub fn with_heap_limit(mut self, limit: Option<usize>) -> Self {
+    self.heap = Heap::new(limit);
+    self
+  }
+
+  pub fn with_tracer(mut self, tracer: Option<Rc<RefCell<dyn Tracer>>>) -> Self {
+    self.tracer = tracer;
+    self
+  }
+
+  fn get(&self, operand: Operand) -> &Value {
+    match operand {
+      Operand::Copy(register) | Operand::Move(register) => &self.registers[self.base + register],
+    }
+  }
+
+  fn read(&mut self, operand: Operand) -> Value {
+    match operand {
+      Operand::Copy(register) => self.registers[self.base + register].clone(),
+      Operand::Move(register) => std::mem::take(&mut self.registers[self.base + register]),
+    }
+  }
+
+  /// Reads `operands`, cloning before moving anything as the same register
+  /// may appear in both ways.
+  fn read_all(&mut self, operands: &[Operand]) -> Vec<Value> {
+    let mut values: Vec<_> = operands
+      .iter()
+      .map(|operand| match operand {
+        Operand::Copy(_) => self.read(*operand),
+        Operand::Move(_) => Value::default(),
+      })
+      .collect();
+    for (value, operand) in values.iter_mut().zip(operands) {
+      if let Operand::Move(_) = operand {
+        *value = self.read(*operand);
+      }
+    }
+    values
+  }
+
+  /// Reads two operands, cloning before moving like `read_all`.
+  fn read_pair(&mut self, lhs: Operand, rhs: Operand) -> (Value, Value) {
+    if let Operand::Move(_) = lhs {
+      let rhs = self.read(rhs);
+      (self.read(lhs), rhs)
+    } else {
+      let lhs = self.read(lhs);
+      (lhs, self.read(rhs))
+    }
+  }
+
+  /// Clears the register of `operand` if it is moved.
+  fn release(&mut self, operand: Operand) {
+    if let Operand::Move(_) = operand {
+      self.read(operand);
+    }
+  }
+
+  fn set(&mut self, dst: usize, value: Value) {
+    self.registers[self.base + dst] = value;
+  }
+
+  /// Writes a newly allocated `value`, raising `#system_limit` if the process
+  /// now holds too much.
+  fn alloc(
+    &mut self,
+    ctx: &Context<'_, Self>,
+    dst: usize,
+    value: Value,
+  ) -> Result<(), Crash<Value>> {
+    let size = value.heap_size();
+    self.set(dst, value);
+    if self.heap.alloc(size) && self.heap.measured(self.live_size(ctx.mailbox)) {
+      return Err(Crash::Raise(Value::atom("system_limit")));
+    }
+    Ok(())
+  }
+
+  fn live_size(&self, mailbox: &VecDeque<Value>) -> usize {
+    live_size(self.registers.iter().chain(mailbox))
+  }
+
+  fn call_builtin(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+    builtin: Builtin,
+    arguments: Vec<Value>,
+  ) -> Result<Value, Crash<Value>> {
+    match builtin {
+      Builtin::Spawn | Builtin::SpawnLink => {
+        builtin.check_arity(arguments.len())?;
+        let mut arguments = arguments.into_iter();
+        let function = arguments.next().unwrap();
+        let Some(arguments) = arguments.next().unwrap().to_vec() else {
+          return Err(Crash::Error(format!("{}: bad argument", builtin.name())));
+        };
+        let function = callee(self.program.info, function, arguments.len())?;
+        let task = Task::call(self.program, function, arguments)
+          .with_heap_limit(self.heap.limit())
+          .with_tracer(self.tracer.clone());
+        let pid = ctx.spawn(task);
+        if builtin == Builtin::SpawnLink {
+          ctx.link(pid);
+        }
+        Ok(Value::Pid(pid))
+      }
+      _ if builtin.is_process() => ctx.call_builtin(builtin, arguments),
+      _ => Ok(builtin.apply(host, arguments)?),
+    }
+  }
+
+  /// Copies or moves the values of `operands` to the end of the registers,
+  /// where the window of a call starts.
+  fn push_arguments(&mut self, operands: &[Operand]) {
+    let start = self.registers.len();
+    for operand in operands {
+      let value = match operand {
+        Operand::Copy(_) => self.read(*operand),
+        Operand::Move(_) => Value::default(),
+      };
+      self.registers.push(value);
+    }
+    for (idx, operand) in operands.iter().enumerate() {
+      if let Operand::Move(_) = operand {
+        self.registers[start + idx] = self.read(*operand);
+      }
+    }
+  }
+
+  /// Enters `function`, whose `arity` arguments are the last registers. The
+  /// result goes to register `dst` of the current function, unless it is a
+  /// tail call and the arguments replace its registers.
+  fn enter(&mut self, pid: Pid, function: usize, arity: usize, dst: Option<usize>) {
+    let Layout { entry, registers } = self.program.layouts[function];
+    if let Some(dst) = dst {
+      self.frames.push(Frame {
+        ip: self.ip,
+        base: self.base,
+        dst,
+      });
+      self.base = self.registers.len() - arity;
+    }
+    if let Some(tracer) = &self.tracer {
+      tracer.borrow_mut().call(
+        pid,
+        self.program.info,
+        function,
+        &self.registers[self.base..],
+      );
+    }
+    self
+      .registers
+      .resize(self.base + registers, Value::default());
+    self.ip = entry;
+  }
+
+  /// Replaces the registers of the running function with the values of
+  /// `operands`.
+  fn replace(&mut self, operands: &[Operand]) {
+    let start = self.registers.len();
+    self.push_arguments(operands);
+    self.registers.drain(self.base..start);
+  }
+
+  fn execute(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+    reductions: &mut usize,
+  ) -> Result<Status<Value>, Crash<Value>> {
+    if let (Some(tracer), Some(function)) = (&self.tracer, self.start.take()) {
+      let arity = self.program.info.functions[function].arity;
+      tracer.borrow_mut().call(
+        ctx.pid,
+        self.program.info,
+        function,
+        &self.registers[..arity],
+      );
+    }
+    while *reductions > 0 {
+      *reductions -= 1;
+      match self.step(host, ctx) {
+        Ok(None) => (),
+        Ok(Some(status)) => return Ok(status),
+        Err(crash) => self.catch(crash)?,
+      }
+    }
+    Ok(Status::Yielded)
+  }
+
+  /// Jumps to the innermost handler with the class and reason of `crash` in
+  /// its registers, or gives `crash` back if there is none.
+  fn catch(&mut self, crash: Crash<Value>) -> Result<(), Crash<Value>> {
+    let Some(handler) = self.handlers.pop() else {
+      return Err(crash);
+    };
+    self.frames.truncate(handler.frames);
+    self.registers.truncate(handler.registers);
+    self.base = handler.base;
+    self.set(handler.dst, Value::atom(crash.class()));
+    self.set(handler.dst + 1, crash.value());
+    self.ip = handler.ip;
+    Ok(())
+  }
+
+  fn step(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+  ) -> Result<Option<Status<Value>>, Crash<Value>> {
+    let program = self.program;
+    let ins = &program.code[self.ip];
+    self.ip += 1;
+    match ins {
+      Instruction::Move { dst, src } => {
+        let value = self.read(*src);
+        self.set(*dst, value);
+      }
+      Instruction::LoadNumber { dst, val } => self.set(*dst, Value::Number(*val)),
+      Instruction::LoadConstant { dst, id } => {
+        let value = program.constants[*id as usize].clone();
+        self.alloc(ctx, *dst, value)?;
+      }
+      Instruction::LoadFunction { dst, function } => {
+        let arity = program.info.functions[*function].arity;
+        let function = Value::Function {
+          id: *function,
+          arity,
+        };
+        self.set(*dst, function);
+      }
+      Instruction::Nil { dst } => self.set(*dst, Value::Nil),
+      Instruction::Binary { op, dst, lhs, rhs } => {
+        // Arithmetic is by far the most common, and needs no clones.
+        let result = match (self.get(*lhs), self.get(*rhs)) {
+          (Value::Number(a), Value::Number(b)) if *op != Bytecode::Eq => {
+            Value::Number(arithmetic(op, *a, *b)?)
+          }
+          (lhs, rhs) => binary(op, lhs.clone(), rhs.clone())?,
+        };
+        self.release(*lhs);
+        self.release(*rhs);
+        self.set(*dst, result);
+      }
+      Instruction::MakeTuple { dst, elements } => {
+        let elements = if elements
+          .iter()
+          .any(|operand| copied_after_move(operand, elements))
+        {
+          self.read_all(elements).into()
+        } else {
+          elements.iter().map(|operand| self.read(*operand)).collect()
+        };
+        self.alloc(ctx, *dst, Value::Tuple(elements))?;
+      }
+      Instruction::PutList { dst, hd, tl } => {
+        let (hd, tl) = self.read_pair(*hd, *tl);
+        self.alloc(ctx, *dst, Value::cons(hd, tl))?;
+      }
+      Instruction::GetTuple { dst, src, index } => {
+        // Only bytecode that skipped the tests of a case tree gets these wrong.
+        let element = match self.get(*src) {
+          Value::Tuple(t) => t.get(*index).cloned(),
+          _ => None,
+        };
+        let element = element.ok_or("GetTuple: bad argument".to_string())?;
+        self.release(*src);
+        self.set(*dst, element);
+      }
+      Instruction::GetHd { dst, src } => {
+        let Value::Cons(cell) = self.get(*src) else {
+          return Err(Crash::Error("GetHd: bad argument".to_string()));
+        };
+        let hd = cell.0.clone();
+        self.release(*src);
+        self.set(*dst, hd);
+      }
+      Instruction::GetTl { dst, src } => {
+        let Value::Cons(cell) = self.get(*src) else {
+          return Err(Crash::Error("GetTl: bad argument".to_string()));
+        };
+        let tl = cell.1.clone();
+        self.release(*src);
+        self.set(*dst, tl);
+      }
+      Instruction::TestExact { src, id, branch } => {
+        match (self.get(*src), &program.constants[*id as usize]) {
+          (Value::Number(a), Value::Number(b)) if a == b => {}
+          (Value::Atom(a), Value::Atom(b)) if a == b => {}
+          (Value::String(a), Value::String(b)) if a == b => {}
+          _ => self.ip = *branch,
+        }
+      }
+      Instruction::TestTuple { src, size, branch } => match self.get(*src) {
+        Value::Tuple(t) if t.len() == *size => {}
+        _ => self.ip = *branch,
+      },
+      Instruction::TestCons { src, branch } => match self.get(*src) {
+        Value::Cons(..) => {}
+        _ => self.ip = *branch,
+      },
+      Instruction::TestNil { src, branch } => match self.get(*src) {
+        Value::Nil => {}
+        _ => self.ip = *branch,
+      },
+      Instruction::Jump { target } => self.ip = *target,
+      Instruction::MatchFail => return Err(Crash::Error("Match failure".to_string())),
+      Instruction::CallBuiltin {
+        dst,
+        builtin,
+        arguments,
+      } => {
+        let arguments = self.read_all(arguments);
+        let result = self.call_builtin(host, ctx, *builtin, arguments)?;
+        self.alloc(ctx, *dst, result)?;
+      }
+      Instruction::CallNative { dst, id, arguments } => {
+        let arguments = self.read_all(arguments);
+        let result = host.natives.call(program.atom(*id).as_str(), arguments)?;
+        self.alloc(ctx, *dst, result)?;
+      }
+      Instruction::Call {
+        dst,
+        function,
+        arguments,
+      } => {
+        self.push_arguments(arguments);
+        self.enter(ctx.pid, *function, arguments.len(), Some(*dst));
+      }
+      Instruction::TailCall {
+        function,
+        arguments,
+      } => {
+        self.replace(arguments);
+        self.enter(ctx.pid, *function, arguments.len(), None);
+      }
+      Instruction::Apply {
+        dst,
+        function,
+        arguments,
+      } => {
+        let function = callee(program.info, self.get(*function).clone(), arguments.len())?;
+        self.push_arguments(arguments);
+        self.enter(ctx.pid, function, arguments.len(), Some(*dst));
+      }
+      Instruction::TailApply {
+        function,
+        arguments,
+      } => {
+        let function = callee(program.info, self.get(*function).clone(), arguments.len())?;
+        self.replace(arguments);
+        self.enter(ctx.pid, function, arguments.len(), None);
+      }
+      Instruction::Return { src } => {
+        let value = self.read(*src);
+        if let Some(tracer) = &self.tracer {
+          tracer.borrow_mut().returned(ctx.pid, &value);
+        }
+        let Some(frame) = self.frames.pop() else {
+          return Ok(Some(Status::Exited(Ok(value))));
+        };
+        self.registers.truncate(self.base);
+        self.ip = frame.ip;
+        self.base = frame.base;
+        self.set(frame.dst, value);
+      }
+      Instruction::PeekMessage { dst, branch } => match ctx.mailbox.get(self.cursor) {
+        Some(message) => {
+          let message = message.clone();
+          self.set(*dst, message);
+        }
+        None => self.ip = *branch,
+      },
+      Instruction::RemoveMessage => {
+        ctx.mailbox.remove(self.cursor);
+        self.cursor = 0;
+      }
+      Instruction::NextMessage { target } => {
+        self.cursor += 1;
+        self.ip = *target;
+      }
+      Instruction::Wait { target } => {
+        self.ip = *target;
+        return Ok(Some(Status::Waiting));
+      }
+      Instruction::Try { handler, dst } => {
+        self.handlers.push(Handler {
+          ip: *handler,
+          dst: *dst,
+          frames: self.frames.len(),
+          base: self.base,
+          registers: self.registers.len(),
+        });
+      }
+      Instruction::EndTry => _ = self.handlers.pop(),
+      Instruction::Raise { class, reason } => {
+        let (class, reason) = self.read_pair(*class, *reason);
+        let class = class.as_atom().ok_or("Raise: bad class".to_string())?;
+        return Err(Crash::raise(class, reason));
+      }
+      Instruction::SwitchAtom {
+        src,
+        cases,
+        default,
+      } => {
+        let case = match self.get(*src) {
+          Value::Atom(atom) => atom_case(cases, *atom),
+          _ => None,
+        };
+        self.ip = case.unwrap_or(*default);
+      }
+      Instruction::SwitchNumber {
+        src,
+        cases,
+        default,
+      } => {
+        let case = match self.get(*src) {
+          Value::Number(n) => cases.binary_search_by_key(n, |(n, _)| *n).ok(),
+          _ => None,
+        };
+        self.ip = case.map_or(*default, |idx| cases[idx].1);
+      }
+      Instruction::SwitchTupleArity {
+        src,
+        cases,
+        default,
+      } => {
+        let case = match self.get(*src) {
+          Value::Tuple(t) => cases.binary_search_by_key(&t.len(), |(n, _)| *n).ok(),
+          _ => None,
+        };
+        self.ip = case.map_or(*default, |idx| cases[idx].1);
+      }
+    }
+    Ok(None)
+  }
+}
+
+/// Whether `operand` moves a register that `operands` also copies, so that
+/// they can't be read in order.
+fn copied_after_move(operand: &Operand, operands: &[Operand]) -> bool {
+  matches!(operand, Operand::Move(register) if operands.contains(&Operand::Copy(*register)))
+}
+
+impl<'a> Process for Task<'a> {
+  type Value = Value;
+  type Shared = Host;
+
+  fn run(
+    &mut self,
+    host: &mut Host,
+    ctx: &mut Context<'_, Self>,
+    reductions: &mut usize,
+  ) -> Status<Value> {
+    self
+      .execute(host, ctx, reductions)
+      .unwrap_or_else(|err| Status::Exited(Err(err)))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::path::Path;
+
+  use crate::{
+    compile::{self, asm::assemble, optimize::optimize, Bytecode, BytecodeInfo, Ctx},
+    desugar::Desugar,
+    lexer::Lexer,
+    parser::Parser,
+    value::Value,
+  };
+
+  use super::{Instruction, Machine, Operand, Program};
+
+  fn compile(src: &str) -> BytecodeInfo {
+    let program = Parser::new(Lexer::new(src))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    ctx.bytecode()
+  }
+
+  /// Runs `main` of `src` on both machines, before and after optimization.
+  fn agree(name: &str, src: &str) {
+    let mut info = compile(src);
+    for _ in 0..2 {
+      let expected = compile::Machine::new(&info).run().map(|v| v.to_string());
+      let program = Program::new(&info).unwrap();
+      let actual = Machine::new(&program).run().map(|v| v.to_string());
+      assert_eq!(actual, expected, "{name}");
+      optimize(&mut info);
+    }
+  }
+
+  #[test]
+  fn examples() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let mut paths = vec![root.join("main.lala")];
+    for entry in std::fs::read_dir(root.join("examples")).unwrap() {
+      let path = entry.unwrap().path();
+      if path.extension().is_some_and(|ext| ext == "lala") {
+        paths.push(path);
+      }
+    }
+    for path in paths {
+      let src = std::fs::read_to_string(&path).unwrap();
+      agree(&path.display().to_string(), &src);
+    }
+  }
+
+  #[test]
+  fn programs() {
+    let cases = [
+      "fn main() -> let x = 1 in let y = x + 1 in let x = y * 3 in {x, y}",
+      "fn swap({a, b}) -> {b, a}\nfn main() -> swap(swap({1, [2, 3]}))",
+      "fn sum([], acc) -> acc\nfn sum([x | xs], acc) -> sum(xs, acc + x)\nfn main() -> sum([1, 2, 3, 4], 0)",
+      "fn apply(f, x) -> f(x)\nfn inc(x) -> x + 1\nfn main() -> apply(inc, apply(inc, 1))",
+      "fn f(x) -> if x == 0 then #zero else {#other, x}\nfn main() -> {f(0), f(2), f(#a)}",
+      "fn boom(x) -> 1 / x\nfn main() -> try boom(0) catch #error:r -> {#caught, r} end",
+      "fn deep(0) -> throw(#bottom)\nfn deep(n) -> {deep(n - 1)}\nfn main() -> {try deep(5) catch c:r -> {c, r} end, 1}",
+      "fn main() -> let p = self() in let m = send(p, {#hi, 1}) in receive {#hi, n} -> n + 1 end",
+      "fn main() -> let n = case {1, 2} of {a, b} -> a + b; _ -> 0 end in n + 1",
+      "fn f(#reg_z) -> 1\nfn f(#reg_y) -> 2\nfn f(#reg_x) -> 3\nfn f(_) -> 0\n\
+       fn main() -> {f(#reg_x), f(#reg_y), f(#reg_z), f(#reg_w), f(1)}",
+            "fn main() -> #a + 1",
+    ];
+    for src in cases {
+      agree(src, src);
+    }
+  }
+
+  #[test]
+  fn translation() {
+    let info = compile("fn add(x, y) -> x + y");
+    let program = Program::new(&info).unwrap();
+    let entry = program.layouts[info.function("add").unwrap()].entry;
+    let add = Bytecode::Add;
+    assert_eq!(
+      program.code[entry..entry + 2],
+      [
+        Instruction::Binary {
+          op: add,
+          dst: 2,
+          lhs: Operand::Copy(0),
+          rhs: Operand::Copy(1),
+        },
+        Instruction::Return {
+          src: Operand::Move(2),
+        },
+      ]
+    );
+  }
+
+  #[test]
+  fn unreachable() {
+    // The jump is never run, so it isn't verified and may leave the function.
+    let src = ".entry 1\n.function 0 f/0 locals 0\na:\npush_number 2\nreturn\n\
+               .function 1 _/0 locals 0\npush_number 1\nreturn\njump a";
+    let info = assemble(src).unwrap();
+    let program = Program::new(&info).unwrap();
+    assert_eq!(Machine::new(&program).run(), Ok(Value::Number(1)));
+  }
+}
@@ -0,0 +1,206 @@
+//! Hooks to follow what the VM does, attached with [`Machine::with_tracer`].
+//!
+//! [`Machine::with_tracer`]: super::Machine::with_tracer
+
+use std::{fmt, io::Write};
+
+use crate::{process::Pid, value::Value};
+
+use super::{asm, BytecodeInfo};
+
+/// The most values of the stack given to [`Tracer::instruction`], as
+/// copying all of them at every instruction would make tracing quadratic.
+pub const STACK_TOP: usize = 4;
+
+/// The most bytes [`Printer`] writes of a value.
+const WIDTH: usize = 80;
+
+/// Callbacks the VM makes as it runs. They do nothing unless overridden.
+pub trait Tracer {
+  /// Before the instruction at `ip` runs, with the top of the stack of the
+  /// process: at most [`STACK_TOP`] values, the topmost last.
+  fn instruction(&mut self, _pid: Pid, _info: &BytecodeInfo, _ip: usize, _stack: &[Value]) {}
+
+  /// Once `function` is entered with `arguments`, including the function a
+  /// process starts with.
+  fn call(&mut self, _pid: Pid, _info: &BytecodeInfo, _function: usize, _arguments: &[Value]) {}
+
+  /// When a function returns `value`.
+  fn returned(&mut self, _pid: Pid, _value: &Value) {}
+}
+
+/// Writes every instruction along with the top of the stack, and every call
+/// and return, cutting long values short with `...`.
+pub struct Printer<W> {
+  out: W,
+}
+
+impl<W: Write> Printer<W> {
+  pub fn new(out: W) -> Self {
+    Self { out }
+  }
+
+  pub fn get_ref(&self) -> &W {
+    &self.out
+  }
+}
+
+impl<W: Write> Tracer for Printer<W> {
+  fn instruction(&mut self, pid: Pid, info: &BytecodeInfo, ip: usize, stack: &[Value]) {
+    let ins = asm::instruction_text(info, &info.bytecode[ip]);
+    let stack: Vec<String> = stack.iter().map(brief).collect();
+    _ = writeln!(self.out, "{pid} {ip:>5}  {ins:<24} [{}]", stack.join(", "));
+  }
+
+  fn call(&mut self, pid: Pid, info: &BytecodeInfo, function: usize, arguments: &[Value]) {
+    let function = &info.functions[function];
+    let name = if function.name.is_empty() {
+      "_"
+    } else {
+      &function.name
+    };
+    let arguments: Vec<String> = arguments.iter().map(brief).collect();
+    _ = writeln!(self.out, "{pid} call {name}({})", arguments.join(", "));
+  }
+
+  fn returned(&mut self, pid: Pid, value: &Value) {
+    _ = writeln!(self.out, "{pid} return {}", brief(value));
+  }
+}
+
+/// The text of `value`, stopping once it is longer than [`WIDTH`].
+fn brief(value: &Value) -> String {
+  let mut text = Bounded(String::new());
+  if fmt::Write::write_fmt(&mut text, format_args!("{value}")).is_err() {
+    text.0.push_str("...");
+  }
+  text.0
+}
+
+/// Text that fails to grow past [`WIDTH`], so that large values aren't
+/// printed whole.
+struct Bounded(String);
+
+impl fmt::Write for Bounded {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    let room = WIDTH - self.0.len();
+    if s.len() <= room {
+      self.0.push_str(s);
+      return Ok(());
+    }
+    let mut end = room;
+    while !s.is_char_boundary(end) {
+      end -= 1;
+    }
+    self.0.push_str(&s[..end]);
+    Err(fmt::Error)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::{cell::RefCell, rc::Rc};
+
+  use crate::{
+    compile::{BytecodeInfo, Ctx, Machine, Value},
+    desugar::Desugar,
+    lexer::Lexer,
+    parser::Parser,
+    process::Pid,
+  };
+
+  use super::{Printer, Tracer, WIDTH};
+
+  const SRC: &str = r#"
+fn double(x) -> x * 2
+fn reply(x, to) -> send(to, double(x))
+fn main() ->
+  let p = spawn(reply, [1, self()]) in
+  receive n -> double(20) + n end
+"#;
+
+  fn compile() -> BytecodeInfo {
+    let program = Parser::new(Lexer::new(SRC))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    ctx.bytecode()
+  }
+
+  #[derive(Default)]
+  struct Calls(Vec<(Pid, String)>);
+
+  impl Tracer for Calls {
+    fn call(&mut self, pid: Pid, info: &BytecodeInfo, function: usize, _: &[Value]) {
+      self.0.push((pid, info.functions[function].name.clone()));
+    }
+  }
+
+  #[test]
+  fn calls() {
+    let info = compile();
+    let calls = Rc::new(RefCell::new(Calls::default()));
+    let res = Machine::new(&info).with_tracer(calls.clone()).run();
+    assert_eq!(res.map(|v| v.to_string()), Ok("42".to_string()));
+    let expected = [(0, "main"), (1, "reply"), (1, "double"), (0, "double")];
+    let expected: Vec<_> = expected
+      .into_iter()
+      .map(|(pid, name)| (Pid(pid), name.to_string()))
+      .collect();
+    assert_eq!(calls.borrow().0, expected);
+  }
+
+  #[test]
+  fn printer() {
+    let info = compile();
+    let printer = Rc::new(RefCell::new(Printer::new(vec![])));
+    Machine::new(&info)
+      .with_tracer(printer.clone())
+      .run()
+      .unwrap();
+    let out = String::from_utf8(printer.borrow().get_ref().clone()).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "<pid:0> call main()");
+    assert!(lines.contains(&"<pid:0> call double(20)"), "{out}");
+    assert!(lines.contains(&"<pid:1> call double(1)"), "{out}");
+    assert!(lines.contains(&"<pid:0> return 42"), "{out}");
+    assert!(
+      lines.iter().any(|line| line.starts_with("<pid:0>")
+        && line.contains("mul")
+        && line.ends_with("[20, 2]")),
+      "{out}"
+    );
+  }
+
+  #[test]
+  fn bounded() {
+    let program = Parser::new(Lexer::new(
+      "fn range(0, l) -> l\nfn range(n, l) -> range(n - 1, [n | l])\n\
+       fn main() -> {1, 2, 3, 4, 5, range(1000, [])}",
+    ))
+    .program()
+    .unwrap()
+    .desugar()
+    .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    let info = ctx.bytecode();
+    let printer = Rc::new(RefCell::new(Printer::new(vec![])));
+    Machine::new(&info)
+      .with_tracer(printer.clone())
+      .run()
+      .unwrap();
+    let out = String::from_utf8(printer.borrow().get_ref().clone()).unwrap();
+    // Only the top of the stack is shown, and the list is cut short.
+    let make_tuple = out.lines().find(|line| line.contains("make_tuple"));
+    let stack = make_tuple.and_then(|line| line.split_once('[')).unwrap().1;
+    assert!(stack.starts_with("3, 4, 5, [1, 2, 3, "), "{out}");
+    assert!(stack.ends_with(", ...]"), "{out}");
+    assert!(stack.len() < 3 * WIDTH, "{out}");
+    let returned = out.lines().find(|line| line.contains("return {"));
+    assert!(returned.is_some_and(|line| line.len() < 2 * WIDTH && line.ends_with("...")));
+  }
+}
@@ -0,0 +1,408 @@
+//! Static checks on bytecode, so that modules loaded from files can't make
+//! the VM index out of bounds or pop from an empty stack.
+//!
+//! Each function is checked on its own, following every path from its entry
+//! and tracking the depth of its part of the stack and how many exception
+//! handlers it has open. Both must be the same on all paths reaching an
+//! instruction, and every path must end in a raise, or in a return or a tail
+//! call with no handler open.
+
+use super::{Bytecode, BytecodeInfo, Constant, Function};
+
+/// Checks that `info` is safe to run.
+pub fn verify(info: &BytecodeInfo) -> Result<(), String> {
+  check(info, None)
+}
+
+/// Checks `info` as [`verify`] does, but with `constants` standing for its
+/// constants: the name of each atom, and `None` for the others. Modules are
+/// checked this way before interning their atoms, as atoms are never freed.
+pub(super) fn verify_uninterned(
+  info: &BytecodeInfo,
+  constants: &[Option<&str>],
+) -> Result<(), String> {
+  check(info, Some(constants))
+}
+
+fn check(info: &BytecodeInfo, atoms: Option<&[Option<&str>]>) -> Result<(), String> {
+  if let Some(entry) = info.entry {
+    let function = info
+      .functions
+      .get(entry)
+      .ok_or(format!("Entry {entry} is not a function"))?;
+    if function.arity != 0 {
+      return Err(format!("Entry {} takes arguments", name(function)));
+    }
+  }
+
+  for id in 0..info.functions.len() {
+    function_depths(info, atoms, id)?;
+  }
+  Ok(())
+}
+
+/// Checks the function `id` of `info`, returning the stack depth at each of
+/// its instructions, or `None` for the ones that are never reached.
+pub fn depths(info: &BytecodeInfo, id: usize) -> Result<Vec<Option<usize>>, String> {
+  function_depths(info, None, id)
+}
+
+fn function_depths(
+  info: &BytecodeInfo,
+  atoms: Option<&[Option<&str>]>,
+  id: usize,
+) -> Result<Vec<Option<usize>>, String> {
+  let function = &info.functions[id];
+  let end = info.code(id).end;
+  let mut verifier = Verifier {
+    info,
+    atoms,
+    function,
+    end,
+    depths: vec![None; end.saturating_sub(function.entry)],
+    handlers: vec![0; end.saturating_sub(function.entry)],
+  };
+  verifier
+    .run()
+    .map_err(|err| format!("{}: {err}", name(function)))?;
+  Ok(verifier.depths)
+}
+
+/// Checks that the keys of a switch are sorted without duplicates, as it
+/// looks them up by binary search.
+fn sorted<K: Ord>(keys: &[K]) -> Result<(), String> {
+  if keys.windows(2).all(|pair| pair[0] < pair[1]) {
+    Ok(())
+  } else {
+    Err("Switch cases are not sorted".to_string())
+  }
+}
+
+fn name(function: &Function) -> String {
+  let name = if function.name.is_empty() {
+    "_"
+  } else {
+    &function.name
+  };
+  format!("{name}/{}", function.arity)
+}
+
+struct Verifier<'a> {
+  info: &'a BytecodeInfo,
+  /// The names of the atoms by constant, when not yet in `info`.
+  atoms: Option<&'a [Option<&'a str>]>,
+  function: &'a Function,
+  end: usize,
+  /// The stack depth at each instruction of the function, once reached.
+  depths: Vec<Option<usize>>,
+  /// The number of handlers open at each instruction reached.
+  handlers: Vec<usize>,
+}
+
+impl<'a> Verifier<'a> {
+  /// The name of the constant `id`, which must be an atom.
+  fn atom(&self, id: u16) -> Result<&'a str, String> {
+    let name = match self.atoms {
+      Some(atoms) => atoms.get(id as usize).copied().flatten(),
+      None => match self.info.constants.get_index(id as usize) {
+        Some((Constant::Atom(atom), _)) => Some(atom.as_str()),
+        _ => None,
+      },
+    };
+    name.ok_or(format!("Constant {id} is not an atom"))
+  }
+
+  fn run(&mut self) -> Result<(), String> {
+    let Function {
+      arity,
+      entry,
+      locals,
+      ..
+    } = *self.function;
+    if entry >= self.end {
+      return Err("Has no code".to_string());
+    }
+    if locals < arity {
+      return Err(format!("Has {locals} locals for {arity} parameters"));
+    }
+    let mut pending = vec![entry];
+    self.depths[0] = Some(0);
+    while let Some(ip) = pending.pop() {
+      let depth = self.depths[ip - entry].unwrap();
+      let open = self.handlers[ip - entry];
+      let next = self
+        .instruction(ip, depth, open)
+        .map_err(|err| format!("{err} at {ip}"))?;
+      for (to, depth, open) in next {
+        if self
+          .reach(to, depth, open)
+          .map_err(|err| format!("{err} from {ip}"))?
+        {
+          pending.push(to);
+        }
+      }
+    }
+    // Every call allocates its locals, so a module can't claim more than it
+    // uses.
+    let used = self.info.bytecode[entry..self.end]
+      .iter()
+      .zip(&self.depths)
+      .filter(|(_, depth)| depth.is_some())
+      .filter_map(|(ins, _)| match ins {
+        Bytecode::GetLocal { id } | Bytecode::SetLocal { id } => Some(id + 1),
+        _ => None,
+      })
+      .fold(arity, usize::max);
+    if locals > used {
+      return Err(format!("Has {locals} locals but uses {used}"));
+    }
+    Ok(())
+  }
+
+  /// Records that `ip` is reached with `depth` and `open` handlers,
+  /// returning whether it is the first time.
+  fn reach(&mut self, ip: usize, depth: usize, open: usize) -> Result<bool, String> {
+    let idx = ip
+      .checked_sub(self.function.entry)
+      .filter(|idx| *idx < self.depths.len())
+      .ok_or(format!("Jump to {ip} outside of the function"))?;
+    let handlers = self.handlers[idx];
+    match self.depths[idx] {
+      None => {
+        self.depths[idx] = Some(depth);
+        self.handlers[idx] = open;
+        Ok(true)
+      }
+      Some(other) if other != depth => Err(format!("Stack depth {depth} where it was {other}")),
+      Some(_) if handlers != open => Err(format!("{open} handlers open where it was {handlers}")),
+      Some(_) => Ok(false),
+    }
+  }
+
+  /// Checks the instruction at `ip`, returning the instructions it may go to
+  /// next along with their stack depth and open handlers.
+  fn instruction(
+    &self,
+    ip: usize,
+    depth: usize,
+    open: usize,
+  ) -> Result<Vec<(usize, usize, usize)>, String> {
+    let pop = |n: usize| {
+      depth
+        .checked_sub(n)
+        .ok_or(format!("Pops {n} values from a stack of {depth}"))
+    };
+    let local = |id: usize| {
+      if id < self.function.locals {
+        Ok(())
+      } else {
+        Err(format!("Local {id} out of bounds"))
+      }
+    };
+    let constants = self.atoms.map_or(self.info.constants.len(), <[_]>::len);
+    let constant = |id: u16| {
+      if (id as usize) < constants {
+        Ok(())
+      } else {
+        Err(format!("Constant {id} out of bounds"))
+      }
+    };
+    let function = |id: usize| {
+      self
+        .info
+        .functions
+        .get(id)
+        .ok_or(format!("Function {id} out of bounds"))
+    };
+    let ins = &self.info.bytecode[ip];
+    // Switches go to any of their cases with the value popped.
+    let switch = |depth: usize| ins.targets().into_iter().map(|to| (to, depth)).collect();
+    let next = ip + 1;
+    let to = match ins {
+      Bytecode::Return => {
+        if depth != 1 {
+          return Err(format!("Returns with a stack of {depth}"));
+        }
+        vec![]
+      }
+      Bytecode::PushNumber { .. } | Bytecode::Nil => vec![(next, depth + 1)],
+      Bytecode::Dup => vec![(next, pop(1)? + 2)],
+      Bytecode::LoadConstant { id } => {
+        constant(*id)?;
+        vec![(next, depth + 1)]
+      }
+      Bytecode::GetLocal { id } => {
+        local(*id)?;
+        vec![(next, depth + 1)]
+      }
+      Bytecode::SetLocal { id } => {
+        local(*id)?;
+        vec![(next, pop(1)?)]
+      }
+      Bytecode::TestExact { id, branch } => {
+        constant(*id)?;
+        vec![(next, pop(1)?), (*branch, pop(1)?)]
+      }
+      Bytecode::TestTuple { branch, .. }
+      | Bytecode::TestCons { branch }
+      | Bytecode::TestNil { branch } => vec![(next, pop(1)?), (*branch, pop(1)?)],
+      Bytecode::MakeTuple { size } => vec![(next, pop(*size)? + 1)],
+      Bytecode::GetTuple { .. } | Bytecode::GetHd | Bytecode::GetTl => {
+        vec![(next, pop(1)? + 1)]
+      }
+      Bytecode::Jump { index } => vec![(*index, depth)],
+      Bytecode::MatchFail => vec![],
+      Bytecode::CallBuiltin { builtin } => vec![(next, pop(builtin.arity())? + 1)],
+      Bytecode::CallNative { id, arity } => {
+        self.atom(*id)?;
+        vec![(next, pop(*arity)? + 1)]
+      }
+      Bytecode::Add
+      | Bytecode::Sub
+      | Bytecode::Mul
+      | Bytecode::Div
+      | Bytecode::Eq
+      | Bytecode::PutList => vec![(next, pop(2)? + 1)],
+      Bytecode::LoadFunction { function: id } => {
+        function(*id)?;
+        vec![(next, depth + 1)]
+      }
+      Bytecode::Call { function: id } => vec![(next, pop(function(*id)?.arity)? + 1)],
+      Bytecode::TailCall { function: id } => {
+        pop(function(*id)?.arity)?;
+        vec![]
+      }
+      Bytecode::Apply { arity } => vec![(next, pop(arity + 1)? + 1)],
+      Bytecode::TailApply { arity } => {
+        pop(arity + 1)?;
+        vec![]
+      }
+      Bytecode::PeekMessage { branch } => vec![(next, depth + 1), (*branch, depth)],
+      Bytecode::RemoveMessage | Bytecode::EndTry => vec![(next, depth)],
+      Bytecode::NextMessage { index } | Bytecode::Wait { index } => vec![(*index, depth)],
+      // The handler starts with the class and reason of the exception.
+      Bytecode::Try { handler } => vec![(next, depth), (*handler, depth + 2)],
+      Bytecode::Raise => {
+        pop(2)?;
+        vec![]
+      }
+      Bytecode::SwitchAtom { cases, .. } => {
+        let mut atoms = vec![];
+        for (id, _) in cases {
+          constant(*id)?;
+          atoms.push(self.atom(*id)?);
+        }
+        sorted(&atoms)?;
+        switch(pop(1)?)
+      }
+      Bytecode::SwitchNumber { cases, .. } => {
+        sorted(&cases.iter().map(|(n, _)| n).collect::<Vec<_>>())?;
+        switch(pop(1)?)
+      }
+      Bytecode::SwitchTupleArity { cases, .. } => {
+        sorted(&cases.iter().map(|(size, _)| size).collect::<Vec<_>>())?;
+        switch(pop(1)?)
+      }
+      Bytecode::Undefined => return Err("Undefined instruction".to_string()),
+    };
+    if next == self.end && to.iter().any(|(ip, _)| *ip == next) {
+      return Err("Falls off the end of the function".to_string());
+    }
+    // A handler is removed when it catches, so it runs with the handlers
+    // open before its try.
+    let after = match ins {
+      Bytecode::Try { .. } => open + 1,
+      Bytecode::EndTry => open
+        .checked_sub(1)
+        .ok_or("Ends a try with no handler open")?,
+      Bytecode::Return | Bytecode::TailCall { .. } | Bytecode::TailApply { .. } if open > 0 => {
+        return Err(format!("Leaves the function with {open} handlers open"));
+      }
+      _ => open,
+    };
+    let handler = match ins {
+      Bytecode::Try { handler } => Some(*handler),
+      _ => None,
+    };
+    Ok(
+      to.into_iter()
+        .enumerate()
+        .map(|(i, (to, depth))| {
+          let caught = i == 1 && handler == Some(to);
+          (to, depth, if caught { open } else { after })
+        })
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::compile::asm::assemble;
+
+  use super::verify;
+
+  #[test]
+  fn rejects() {
+    let header = ".constant 0 #a\n.entry 0\n.function 0 _/0 locals 0\n";
+    let cases = [
+      ("load_constant 0\nreturn", Ok(())),
+      ("return", Err("_/0: Returns with a stack of 0 at 0")),
+      ("push_number 1\nget_tuple 0\nadd\nreturn", Err("_/0: Pops 2 values from a stack of 1 at 2")),
+      ("load_constant 1\nreturn", Err("_/0: Constant 1 out of bounds at 0")),
+      ("get_local 0\nreturn", Err("_/0: Local 0 out of bounds at 0")),
+      ("call 1\nreturn", Err("_/0: Function 1 out of bounds at 0")),
+      ("push_number 1", Err("_/0: Falls off the end of the function at 0")),
+      (
+        "push_number 1\npush_number 1\ntest_exact 0 a\npush_number 2\na:\nreturn",
+        Err("_/0: Stack depth 2 where it was 1 from 3"),
+      ),
+      ("undefined", Err("_/0: Undefined instruction at 0")),
+      (
+        "load_constant 0\nswitch_atom a 0:b\na:\nb:\npush_number 0\nreturn",
+        Ok(()),
+      ),
+      (
+        "push_number 1\nswitch_number a 2:a 1:a\na:\npush_number 0\nreturn",
+        Err("_/0: Switch cases are not sorted at 1"),
+      ),
+      (
+        "push_number 1\nreturn\n.function 1 f/0 locals 0\njump a\n.function 2 g/0 locals 0\nnil\na:\nreturn",
+        Err("f/0: Jump to 4 outside of the function from 2"),
+      ),
+      (
+        "try h\nmatch_fail\nh:\nmake_tuple 2\nreturn",
+        Ok(()),
+      ),
+      (
+        "try h\npush_number 1\nend_try\nreturn\nh:\nmake_tuple 2\nreturn",
+        Ok(()),
+      ),
+      (
+        "try h\npush_number 1\nreturn\nh:\nmake_tuple 2\nreturn",
+        Err("_/0: Leaves the function with 1 handlers open at 2"),
+      ),
+      (
+        "try h\ntail_call 0\nh:\nmake_tuple 2\nreturn",
+        Err("_/0: Leaves the function with 1 handlers open at 1"),
+      ),
+      ("end_try\npush_number 1\nreturn", Err("_/0: Ends a try with no handler open at 0")),
+      (
+        "push_number 1\ntest_exact 0 a\ntry h\na:\npush_number 1\nreturn\nh:\nmake_tuple 2\nreturn",
+        Err("_/0: 1 handlers open where it was 0 from 2"),
+      ),
+    ];
+    for (src, expected) in cases {
+      let info = assemble(&format!("{header}{src}")).unwrap();
+      assert_eq!(verify(&info), expected.map_err(str::to_string), "{src}");
+    }
+    let info = assemble(".entry 0\n.function 0 f/1 locals 1\nget_local 0\nreturn").unwrap();
+    assert_eq!(verify(&info), Err("Entry f/1 takes arguments".to_string()));
+    let info =
+      assemble(".function 0 f/1 locals 3\npush_number 1\nset_local 1\nget_local 0\nreturn");
+    assert_eq!(
+      verify(&info.unwrap()),
+      Err("f/1: Has 3 locals but uses 2".to_string())
+    );
+  }
+}
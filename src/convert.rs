@@ -0,0 +1,388 @@
+//! Conversions between Rust types and lala values.
+//!
+//! Vectors are lists, tuples are tuples, `Option` is `{#some, x}` or
+//! `#none` and `Result` is `{#ok, x}` or `{#error, e}`. Structs and enums
+//! deriving the traits are tagged tuples: `Point { x: 1, y: 2 }` is
+//! `{#point, 1, 2}` and a unit variant `Color::DarkRed` is `#dark_red`.
+
+use crate::{atom::Atom, process::Pid, value::Value};
+
+pub trait IntoValue {
+  fn into_value(self) -> Value;
+}
+
+/// A conversion to a value that fails on values lala can't hold, such as
+/// integers wider than numbers.
+pub trait TryIntoValue {
+  fn try_into_value(self) -> Result<Value, String>;
+}
+
+impl<T: IntoValue> TryIntoValue for T {
+  fn try_into_value(self) -> Result<Value, String> {
+    Ok(self.into_value())
+  }
+}
+
+pub trait FromValue: Sized {
+  fn from_value(value: &Value) -> Result<Self, String>;
+}
+
+/// The error of a [`FromValue`] impl given something other than `expected`.
+pub fn mismatch(expected: &str, value: &Value) -> String {
+  format!("Expected {expected} but got {value}")
+}
+
+/// The fields of `value` if it is a tuple tagged with `tag` and holding
+/// `len` more elements. Used by derived impls.
+pub fn tagged<'v>(value: &'v Value, tag: &str, len: usize) -> Option<&'v [Value]> {
+  match value {
+    Value::Tuple(elements) if elements.len() == len + 1 => match elements[0] {
+      Value::Atom(atom) if atom.as_str() == tag => Some(&elements[1..]),
+      _ => None,
+    },
+    _ => None,
+  }
+}
+
+impl IntoValue for Value {
+  fn into_value(self) -> Value {
+    self
+  }
+}
+
+impl FromValue for Value {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    Ok(value.clone())
+  }
+}
+
+impl IntoValue for i32 {
+  fn into_value(self) -> Value {
+    Value::Number(self)
+  }
+}
+
+impl FromValue for i32 {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::Number(n) => Ok(*n),
+      _ => Err(mismatch("a number", value)),
+    }
+  }
+}
+
+/// Numbers are 32 bits, so only narrower integers convert to them.
+macro_rules! into_number {
+  ($($int:ty),*) => {
+    $(
+      impl IntoValue for $int {
+        fn into_value(self) -> Value {
+          Value::Number(self.into())
+        }
+      }
+    )*
+  };
+}
+
+macro_rules! from_number {
+  ($($int:ty),*) => {
+    $(
+      impl FromValue for $int {
+        fn from_value(value: &Value) -> Result<Self, String> {
+          let n = i32::from_value(value)?;
+          n.try_into().map_err(|_| mismatch(stringify!($int), value))
+        }
+      }
+    )*
+  };
+}
+
+/// Wider integers only convert when they are in range.
+macro_rules! try_into_number {
+  ($($int:ty),*) => {
+    $(
+      impl TryIntoValue for $int {
+        fn try_into_value(self) -> Result<Value, String> {
+          let n = i32::try_from(self).map_err(|_| format!("{self} does not fit in a number"))?;
+          Ok(Value::Number(n))
+        }
+      }
+    )*
+  };
+}
+
+into_number!(i8, i16, u8, u16);
+try_into_number!(i64, u32, u64, usize);
+from_number!(i8, i16, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoValue for bool {
+  fn into_value(self) -> Value {
+    Value::Atom(Atom::boolean(self))
+  }
+}
+
+impl FromValue for bool {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::Atom(Atom::TRUE) => Ok(true),
+      Value::Atom(Atom::FALSE) => Ok(false),
+      _ => Err(mismatch("a boolean", value)),
+    }
+  }
+}
+
+impl IntoValue for Atom {
+  fn into_value(self) -> Value {
+    Value::Atom(self)
+  }
+}
+
+impl FromValue for Atom {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::Atom(atom) => Ok(*atom),
+      _ => Err(mismatch("an atom", value)),
+    }
+  }
+}
+
+impl IntoValue for Pid {
+  fn into_value(self) -> Value {
+    Value::Pid(self)
+  }
+}
+
+impl FromValue for Pid {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::Pid(pid) => Ok(*pid),
+      _ => Err(mismatch("a pid", value)),
+    }
+  }
+}
+
+impl IntoValue for String {
+  fn into_value(self) -> Value {
+    Value::String(self.into())
+  }
+}
+
+impl IntoValue for &str {
+  fn into_value(self) -> Value {
+    Value::String(self.into())
+  }
+}
+
+impl FromValue for String {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::String(s) => Ok(s.to_string()),
+      _ => Err(mismatch("a string", value)),
+    }
+  }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+  fn into_value(self) -> Value {
+    Value::from_vec(self.into_iter().map(T::into_value).collect())
+  }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    let elements = value.to_vec().ok_or_else(|| mismatch("a list", value))?;
+    elements.iter().map(T::from_value).collect()
+  }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+  fn into_value(self) -> Value {
+    match self {
+      Some(x) => Value::Tuple([Value::Atom(Atom::new("some")), x.into_value()].into()),
+      None => Value::Atom(Atom::new("none")),
+    }
+  }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    if let Some([x]) = tagged(value, "some", 1) {
+      return Ok(Some(T::from_value(x)?));
+    }
+    match value {
+      Value::Atom(atom) if atom.as_str() == "none" => Ok(None),
+      _ => Err(mismatch("an option", value)),
+    }
+  }
+}
+
+impl<T: IntoValue, E: IntoValue> IntoValue for Result<T, E> {
+  fn into_value(self) -> Value {
+    let (tag, x) = match self {
+      Ok(x) => ("ok", x.into_value()),
+      Err(e) => ("error", e.into_value()),
+    };
+    Value::Tuple([Value::Atom(Atom::new(tag)), x].into())
+  }
+}
+
+impl<T: FromValue, E: FromValue> FromValue for Result<T, E> {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    if let Some([x]) = tagged(value, "ok", 1) {
+      return Ok(Ok(T::from_value(x)?));
+    }
+    if let Some([e]) = tagged(value, "error", 1) {
+      return Ok(Err(E::from_value(e)?));
+    }
+    Err(mismatch("a result", value))
+  }
+}
+
+macro_rules! tuples {
+  ($(($($t:ident $idx:tt),*)),*) => {
+    $(
+      impl<$($t: IntoValue),*> IntoValue for ($($t,)*) {
+        fn into_value(self) -> Value {
+          Value::Tuple([$(self.$idx.into_value()),*].into())
+        }
+      }
+
+      impl<$($t: FromValue),*> FromValue for ($($t,)*) {
+        fn from_value(value: &Value) -> Result<Self, String> {
+          const LEN: usize = [$($idx),*].len();
+          match value {
+            Value::Tuple(elements) if elements.len() == LEN => {
+              Ok(($($t::from_value(&elements[$idx])?,)*))
+            }
+            _ => Err(mismatch(&format!("a tuple of size {LEN}"), value)),
+          }
+        }
+      }
+    )*
+  };
+}
+
+tuples!(
+  (A 0),
+  (A 0, B 1),
+  (A 0, B 1, C 2),
+  (A 0, B 1, C 2, D 3),
+  (A 0, B 1, C 2, D 3, E 4),
+  (A 0, B 1, C 2, D 3, E 4, F 5)
+);
+
+impl IntoValue for () {
+  fn into_value(self) -> Value {
+    Value::Tuple([].into())
+  }
+}
+
+impl FromValue for () {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::Tuple(elements) if elements.is_empty() => Ok(()),
+      _ => Err(mismatch("an empty tuple", value)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{value::Value, FromValue, IntoValue, TryIntoValue};
+
+  #[derive(Clone, Debug, PartialEq, IntoValue, FromValue)]
+  struct Point {
+    x: i32,
+    y: i32,
+  }
+
+  #[derive(Clone, Debug, PartialEq, IntoValue, FromValue)]
+  struct Meters(u16);
+
+  #[derive(Clone, Debug, PartialEq, IntoValue, FromValue)]
+  struct Pair<T>(T, T);
+
+  #[derive(Clone, Debug, PartialEq, IntoValue, FromValue)]
+  enum Shape {
+    Empty,
+    Circle(Point, Meters),
+    BoundingBox { corner: Point, size: (i32, i32) },
+  }
+
+  fn round_trip<T: IntoValue + FromValue + Clone + std::fmt::Debug + PartialEq>(x: T, shown: &str) {
+    let value = x.clone().into_value();
+    assert_eq!(value.to_string(), shown);
+    assert_eq!(T::from_value(&value), Ok(x));
+  }
+
+  #[test]
+  fn std_types() {
+    round_trip(-3, "-3");
+    round_trip(200u8, "200");
+    round_trip(true, "#true");
+    round_trip("hi".to_string(), r#""hi""#);
+    round_trip(vec![1, 2, 3], "[1, 2, 3]");
+    round_trip((1, "a".to_string()), r#"{1, "a"}"#);
+    round_trip(Some(vec![()]), "{#some, [{}]}");
+    round_trip(None::<i32>, "#none");
+    round_trip(Ok::<_, String>(1), "{#ok, 1}");
+    round_trip(Err::<i32, _>("no".to_string()), r#"{#error, "no"}"#);
+    assert_eq!(usize::from_value(&Value::Number(7)), Ok(7));
+    assert_eq!(
+      u8::from_value(&Value::Number(-1)),
+      Err("Expected u8 but got -1".to_string())
+    );
+    assert_eq!(
+      Vec::<i32>::from_value(&"[1, 2]".into_value()),
+      Err(r#"Expected a list but got "[1, 2]""#.to_string())
+    );
+  }
+
+  #[test]
+  fn wide_integers() {
+    assert_eq!(7i64.try_into_value(), Ok(Value::Number(7)));
+    assert_eq!((-7i64).try_into_value(), Ok(Value::Number(-7)));
+    assert_eq!(7u32.try_into_value(), Ok(Value::Number(7)));
+    assert_eq!(7u64.try_into_value(), Ok(Value::Number(7)));
+    assert_eq!(7usize.try_into_value(), Ok(Value::Number(7)));
+    assert_eq!(u8::MAX.try_into_value(), Ok(Value::Number(255)));
+    assert_eq!(
+      i64::MIN.try_into_value(),
+      Err("-9223372036854775808 does not fit in a number".to_string())
+    );
+    assert_eq!(
+      (i32::MAX as u32 + 1).try_into_value(),
+      Err("2147483648 does not fit in a number".to_string())
+    );
+    assert_eq!(
+      u64::MAX.try_into_value(),
+      Err("18446744073709551615 does not fit in a number".to_string())
+    );
+    assert_eq!(
+      usize::MAX.try_into_value(),
+      Err(format!("{} does not fit in a number", usize::MAX))
+    );
+    let round = i64::from_value(&i64::from(i32::MIN).try_into_value().unwrap());
+    assert_eq!(round, Ok(i32::MIN.into()));
+  }
+
+  #[test]
+  fn derived() {
+    round_trip(Point { x: 1, y: 2 }, "{#point, 1, 2}");
+    round_trip(Meters(5), "{#meters, 5}");
+    round_trip(Shape::Empty, "#empty");
+    let circle = Shape::Circle(Point { x: 0, y: 0 }, Meters(1));
+    round_trip(circle, "{#circle, {#point, 0, 0}, {#meters, 1}}");
+    let bounding = Shape::BoundingBox {
+      corner: Point { x: 1, y: 1 },
+      size: (2, 3),
+    };
+    round_trip(bounding, "{#bounding_box, {#point, 1, 1}, {2, 3}}");
+    round_trip(Pair(true, false), "{#pair, #true, #false}");
+    let wrong = Point::from_value(&Shape::Empty.into_value());
+    assert_eq!(
+      wrong,
+      Err("Expected {#point, _, _} but got #empty".to_string())
+    );
+  }
+}
@@ -1,5 +1,7 @@
 use std::collections::BTreeMap;
 
+use crate::atom::Atom;
+
 pub mod expression;
 pub mod fn_definition;
 pub mod pattern;
@@ -14,7 +16,7 @@ pub enum Expression {
     value: i32,
   },
   Atom {
-    value: String,
+    value: Atom,
   },
   String {
     value: String,
@@ -54,6 +56,23 @@ pub enum Expression {
     tl: Expr,
   },
   Nil,
+  /// Takes the first message of the mailbox accepted by `tree`, binding it to
+  /// `name`, and blocks while there is none.
+  Receive {
+    name: String,
+    tree: Tree,
+    actions: Vec<Expression>,
+  },
+  /// Evaluates `body`, and if it raises an exception binds its class and
+  /// reason to `class` and `reason` and runs the action selected by `tree`.
+  /// Exceptions no action accepts are raised again.
+  Try {
+    body: Expr,
+    class: String,
+    reason: String,
+    tree: Tree,
+    actions: Vec<Expression>,
+  },
 }
 
 #[derive(Clone, Debug)]
@@ -67,7 +86,7 @@ pub enum Tree {
 pub enum Cond {
   Number(i32),
   String(String),
-  Atom(String),
+  Atom(Atom),
   Tuple(usize),
   Cons,
   Nil,
@@ -126,7 +145,7 @@ pub enum Pattern {
     value: String,
   },
   Atom {
-    value: String,
+    value: Atom,
   },
   Tuple {
     elements: Vec<Pattern>,
@@ -1,4 +1,4 @@
-use crate::ast;
+use crate::{ast, atom::Atom};
 
 use super::{
   pattern::{self},
@@ -14,7 +14,9 @@ impl Desugar for ast::Expression {
     match self {
       ast::Expression::Variable { name } => Ok(Expression::Variable { name }),
       ast::Expression::Number { value } => Ok(Expression::Number { value }),
-      ast::Expression::Atom { value } => Ok(Expression::Atom { value }),
+      ast::Expression::Atom { value } => Ok(Expression::Atom {
+        value: Atom::new(&value),
+      }),
       ast::Expression::String { value } => Ok(Expression::String { value }),
       ast::Expression::Let { bind, value, next } => Ok(Expression::Let {
         bind,
@@ -52,15 +54,118 @@ impl Desugar for ast::Expression {
         then_branch: then_branch.desugar()?.into(),
         else_branch: else_branch.desugar()?.into(),
       }),
-      ast::Expression::List { elements } => Ok(
-        elements
+      ast::Expression::List { elements, tail } => {
+        let acc = match tail {
+          Some(tail) => tail.desugar()?,
+          None => Expression::Nil,
+        };
+        Ok(
+          elements
+            .into_iter()
+            .flat_map(|e| e.desugar())
+            .rfold(acc, |acc, nxt| Expression::Cons {
+              hd: Box::new(nxt),
+              tl: Box::new(acc),
+            }),
+        )
+      }
+      ast::Expression::Receive { arms } => {
+        let name = "m_0".to_string();
+        let mut left = vec![];
+        let mut actions = vec![];
+        for arm in arms.into_iter() {
+          left.push(arm.lhs.into_iter().map(|p| p.desugar()).collect());
+          actions.push(arm.rhs.desugar()?);
+        }
+        let (tree, actions) = pattern::Problem::with_parameters(vec![name.clone()], left, actions);
+        Ok(Expression::Receive {
+          name,
+          tree,
+          actions,
+        })
+      }
+      ast::Expression::Try {
+        body,
+        arms,
+        catches,
+      } if arms.is_empty() => {
+        let (class, reason) = ("c_0".to_string(), "r_0".to_string());
+        let mut left = vec![];
+        let mut actions = vec![];
+        for arm in catches.into_iter() {
+          left.push(arm.lhs.into_iter().map(|p| p.desugar()).collect());
+          actions.push(arm.rhs.desugar()?);
+        }
+        let parameters = vec![class.clone(), reason.clone()];
+        let (tree, actions) = pattern::Problem::with_parameters(parameters, left, actions);
+        Ok(Expression::Try {
+          body: body.desugar()?.into(),
+          class,
+          reason,
+          tree,
+          actions,
+        })
+      }
+      // Exceptions raised by the `of` arms are not caught, so the body is
+      // tagged with #ok and the catch arms with #caught, and the arms are
+      // matched after the try.
+      ast::Expression::Try {
+        body,
+        arms,
+        catches,
+      } => {
+        let atom = |value: &str| ast::Expression::Atom {
+          value: value.to_string(),
+        };
+        let tagged = |tag: &str, expr: ast::Expression| ast::Expression::Tuple {
+          elements: vec![atom(tag), expr],
+        };
+        let catches = catches
+          .into_iter()
+          .map(|arm| ast::Arm {
+            lhs: arm.lhs,
+            rhs: Box::new(tagged("caught", *arm.rhs)),
+          })
+          .collect();
+        let scrutinee = ast::Expression::Try {
+          body: Box::new(tagged("ok", *body)),
+          arms: vec![],
+          catches,
+        };
+        let tag = |value: &str, pattern| ast::Pattern::Tuple {
+          elements: vec![
+            ast::Pattern::Atom {
+              value: value.to_string(),
+            },
+            pattern,
+          ],
+        };
+        let mut arms: Vec<ast::Arm> = arms
           .into_iter()
-          .flat_map(|e| e.desugar())
-          .rfold(Expression::Nil, |acc, nxt| Expression::Cons {
-            hd: Box::new(nxt),
-            tl: Box::new(acc),
-          }),
-      ),
+          .map(|mut arm| {
+            let pattern = arm.lhs.remove(0);
+            ast::Arm {
+              lhs: vec![tag("ok", pattern)],
+              rhs: arm.rhs,
+            }
+          })
+          .collect();
+        let caught = "v_0".to_string();
+        arms.push(ast::Arm {
+          lhs: vec![tag(
+            "caught",
+            ast::Pattern::Variable {
+              name: caught.clone(),
+            },
+          )],
+          rhs: Box::new(ast::Expression::Variable { name: caught }),
+        });
+        ast::Expression::Match {
+          scrutinee: vec![scrutinee],
+          arms,
+        }
+        .desugar()
+      }
     }
   }
 }
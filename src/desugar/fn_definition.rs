@@ -23,18 +23,25 @@ impl Desugar for ast::FnDefinition {
         clause
           .body
           .desugar()
-          .map_err(|_| format!("Could not desugar clause body"))?,
+          .map_err(|_| "Could not desugar clause body".to_string())?,
       );
     }
     // println!("patterns = {patterns:?}");
-    assert!(patterns.len() > 0 && actions.len() > 0);
-    let arity = (&patterns[0]).len();
+    assert!(!patterns.is_empty() && !actions.is_empty());
+    let arity = patterns[0].len();
     if patterns.len() == 1
       && patterns[0]
         .iter()
         .all(|p| matches!(p, Pattern::Variable { .. } | Pattern::Wildcard))
     {
-      let parameters: Vec<String> = (0..arity).map(gen_name).collect();
+      let parameters: Vec<String> = patterns[0]
+        .iter()
+        .enumerate()
+        .map(|(gen, p)| match p {
+          Pattern::Variable { name } => name.clone(),
+          _ => gen_name(gen),
+        })
+        .collect();
 
       let body = Box::new(actions.into_iter().nth(0).unwrap());
 
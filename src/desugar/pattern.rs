@@ -2,6 +2,7 @@ use std::collections::{BTreeSet, VecDeque};
 
 use crate::{
   ast::{self},
+  atom::Atom,
   desugar::Expression,
 };
 
@@ -14,7 +15,9 @@ impl ast::Pattern {
       ast::Pattern::Variable { name } => Pattern::Variable { name },
       ast::Pattern::Number { value } => Pattern::Number { value },
       ast::Pattern::String { value } => Pattern::String { value },
-      ast::Pattern::Atom { value } => Pattern::Atom { value },
+      ast::Pattern::Atom { value } => Pattern::Atom {
+        value: Atom::new(&value),
+      },
       ast::Pattern::Tuple { elements } => Pattern::Tuple {
         elements: elements.into_iter().map(|e| e.desugar()).collect(),
       },
@@ -138,7 +141,7 @@ impl Case {
       Pattern::Variable { .. } | Pattern::Wildcard => None,
       Pattern::Number { value: n } => Some(Cond::Number(*n)),
       Pattern::Tuple { elements: pats } => Some(Cond::Tuple(pats.len())),
-      Pattern::Atom { value } => Some(Cond::Atom(value.clone())),
+      Pattern::Atom { value } => Some(Cond::Atom(*value)),
       Pattern::String { value } => Some(Cond::String(value.clone())),
       Pattern::Cons { .. } => Some(Cond::Cons),
       Pattern::Nil => Some(Cond::Nil),
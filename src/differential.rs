@@ -0,0 +1,274 @@
+//! Differential tests of the execution engines.
+//!
+//! Every program of the corpus, and many generated expressions, are run by
+//! the evaluator, the stack machine and the register machine, which must give
+//! the same value, or fail with an error of the same class. Only the class is
+//! compared, as the engines may word their messages differently.
+
+use std::path::Path;
+
+use crate::{
+  compile::{optimize::optimize, register, verify::verify, Ctx, Machine},
+  desugar::{self, Desugar},
+  eval::Env,
+  lexer::Lexer,
+  parser::Parser,
+  value::Value,
+};
+
+/// Definitions the generated expressions may call.
+const DEFINITIONS: &str = r#"
+fn double(x) -> x * 2
+fn pair(x) -> {x, x}
+fn first({x, _}) -> x
+fn len([]) -> 0
+fn len([_ | xs]) -> 1 + len(xs)
+"#;
+
+/// Expressions generated by [`generated`].
+const EXPRESSIONS: usize = 300;
+
+/// What a run gave: its value, or the class of its error.
+type Outcome = Result<String, &'static str>;
+
+fn outcome(res: Result<Value, String>) -> Outcome {
+  res.map(|value| value.to_string()).map_err(|err| {
+    if err.starts_with("Uncaught throw") {
+      "throw"
+    } else if err.starts_with("Exited with reason") {
+      "exit"
+    } else {
+      "error"
+    }
+  })
+}
+
+fn program(src: &str) -> desugar::Program {
+  Parser::new(Lexer::new(src))
+    .program()
+    .unwrap()
+    .desugar()
+    .unwrap()
+}
+
+fn expression(src: &str) -> desugar::Expression {
+  let expr = Parser::new(Lexer::new(src)).expression();
+  expr
+    .unwrap_or_else(|err| panic!("{src}: {err}"))
+    .desugar()
+    .unwrap()
+}
+
+/// Runs `main` with the definitions of `src` in every engine, asserting that
+/// they agree, and returns what they gave.
+fn check(name: &str, src: &str, main: &str) -> Outcome {
+  let expected = outcome(Env::from_program(program(src)).eval(expression(main)));
+  let mut ctx = Ctx::new();
+  ctx.program(program(src));
+  let entry = ctx.fn_clause(expression(main));
+  let mut info = ctx.bytecode();
+  info.entry = Some(entry);
+  assert_eq!(verify(&info), Ok(()), "{name}");
+  let actual = outcome(Machine::new(&info).run());
+  assert_eq!(actual, expected, "{name} on the stack machine");
+  let actual = outcome(Machine::new(&info).with_gc_stress().run());
+  assert_eq!(actual, expected, "{name} collecting after every allocation");
+  optimize(&mut info);
+  let actual = outcome(Machine::new(&info).run());
+  assert_eq!(actual, expected, "{name} once optimized");
+  let program = register::Program::new(&info).unwrap();
+  let actual = outcome(register::Machine::new(&program).run());
+  assert_eq!(actual, expected, "{name} on the register machine");
+  expected
+}
+
+#[test]
+fn corpus() {
+  let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+  let mut paths = vec![root.join("main.lala")];
+  for dir in ["examples", "tests/corpus"] {
+    for entry in std::fs::read_dir(root.join(dir)).unwrap() {
+      let path = entry.unwrap().path();
+      if path.extension().is_some_and(|ext| ext == "lala") {
+        paths.push(path);
+      }
+    }
+  }
+  let mut failures = vec![];
+  for path in paths {
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let src = std::fs::read_to_string(&path).unwrap();
+    if let Err(class) = check(name, &src, "main()") {
+      failures.push(class);
+    }
+  }
+  // The corpus exercises every class of error.
+  for class in ["error", "throw", "exit"] {
+    assert!(failures.contains(&class), "no program fails with {class}");
+  }
+}
+
+#[test]
+fn generated() {
+  let mut generator = Generator::new(0x1a1a);
+  let mut values = 0;
+  for _ in 0..EXPRESSIONS {
+    let src = generator.expression(4);
+    values += check(&src, DEFINITIONS, &src).is_ok() as usize;
+  }
+  // Expressions that fail early hardly exercise the engines.
+  assert!(
+    values >= EXPRESSIONS / 10,
+    "only {values} expressions gave a value"
+  );
+}
+
+/// Generates random expressions from a seed, so that failures can be
+/// reproduced.
+struct Generator {
+  state: u64,
+  variables: Vec<String>,
+  fresh: usize,
+}
+
+impl Generator {
+  fn new(seed: u64) -> Self {
+    Self {
+      state: seed,
+      variables: vec![],
+      fresh: 0,
+    }
+  }
+
+  /// A number below `n`, from a xorshift generator.
+  fn below(&mut self, n: usize) -> usize {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 7;
+    self.state ^= self.state << 17;
+    (self.state % n as u64) as usize
+  }
+
+  fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+    choices[self.below(choices.len())]
+  }
+
+  fn variable(&mut self) -> String {
+    self.fresh += 1;
+    format!("v{}", self.fresh)
+  }
+
+  fn leaf(&mut self) -> String {
+    match self.below(8) {
+      0 | 1 => self.below(10).to_string(),
+      2 => "2147483647".to_string(),
+      3 => self.pick(&["#a", "#b", "#true", "#false"]).to_string(),
+      4 => self.pick(&["\"s\"", "\"t\""]).to_string(),
+      5 => "[]".to_string(),
+      6 => self.pick(&["double", "pair", "first", "len"]).to_string(),
+      _ if self.variables.is_empty() => "{}".to_string(),
+      _ => {
+        let idx = self.below(self.variables.len());
+        self.variables[idx].clone()
+      }
+    }
+  }
+
+  /// A pattern, binding new variables in the scope of the generator.
+  fn pattern(&mut self) -> String {
+    match self.below(8) {
+      0 => self.below(3).to_string(),
+      1 => self.pick(&["#a", "#true"]).to_string(),
+      2 => "\"s\"".to_string(),
+      3 => "[]".to_string(),
+      4 => {
+        let (hd, tl) = (self.variable(), self.variable());
+        self.variables.extend([hd.clone(), tl.clone()]);
+        format!("[{hd} | {tl}]")
+      }
+      5 => {
+        let x = self.variable();
+        self.variables.push(x.clone());
+        format!("{{{x}, _}}")
+      }
+      6 => "_".to_string(),
+      _ => {
+        let x = self.variable();
+        self.variables.push(x.clone());
+        x
+      }
+    }
+  }
+
+  fn expression(&mut self, depth: usize) -> String {
+    if depth == 0 {
+      return self.leaf();
+    }
+    let depth = depth - 1;
+    match self.below(14) {
+      0 => self.leaf(),
+      1 => format!("{{{}, {}}}", self.expression(depth), self.expression(depth)),
+      2 => format!("[{}, {}]", self.expression(depth), self.expression(depth)),
+      3 => format!("[{} | {}]", self.expression(depth), self.expression(depth)),
+      4 => {
+        let op = self.pick(&["+", "-", "*", "/", "=="]);
+        format!(
+          "({} {op} {})",
+          self.expression(depth),
+          self.expression(depth)
+        )
+      }
+      5 => format!(
+        "(if {} == {} then {} else {})",
+        self.expression(depth),
+        self.expression(depth),
+        self.expression(depth),
+        self.expression(depth)
+      ),
+      6 => {
+        let value = self.expression(depth);
+        let x = self.variable();
+        self.variables.push(x.clone());
+        let body = self.expression(depth);
+        self.variables.pop();
+        format!("(let {x} = {value} in {body})")
+      }
+      7 => {
+        let scrutinee = self.expression(depth);
+        let bound = self.variables.len();
+        let pattern = self.pattern();
+        let action = self.expression(depth);
+        self.variables.truncate(bound);
+        let default = self.expression(depth);
+        format!("(case {scrutinee} of {pattern} -> {action}; _ -> {default} end)")
+      }
+      8 => format!("(try {} catch c:r -> {{c, r}} end)", self.expression(depth)),
+      9 => {
+        let raise = self.pick(&["throw", "error", "exit"]);
+        format!("{raise}({})", self.expression(depth))
+      }
+      10 => {
+        let builtin = self.pick(&["length", "hd", "tl", "reverse", "tuple_size", "to_string"]);
+        format!("{builtin}({})", self.expression(depth))
+      }
+      11 => {
+        let builtin = self.pick(&["append", "element", "concat", "map"]);
+        format!(
+          "{builtin}({}, {})",
+          self.expression(depth),
+          self.expression(depth)
+        )
+      }
+      12 => {
+        let function = self.pick(&["double", "pair", "first", "len"]);
+        format!("{function}({})", self.expression(depth))
+      }
+      _ => {
+        let (pid, sent, message) = (self.variable(), self.variable(), self.variable());
+        format!(
+          "(let {pid} = self() in let {sent} = send({pid}, {}) in receive {message} -> {message} end)",
+          self.expression(depth)
+        )
+      }
+    }
+  }
+}
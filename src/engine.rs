@@ -0,0 +1,432 @@
+//! An embeddable interpreter: load a program, register natives, then call its
+//! functions with Rust values.
+
+use std::{cell::RefCell, collections::BTreeMap, path::Path, rc::Rc};
+
+use crate::{
+  compile::{
+    gc::Stats, module, optimize::optimize, register, trace::Tracer, BytecodeInfo, Ctx, Machine,
+  },
+  desugar::{self, Desugar},
+  eval::Env,
+  host::{Capture, Host},
+  lexer::Lexer,
+  parser::Parser,
+  process::{Crash, Evaluation, Suspended},
+  value::Value,
+};
+
+/// Why loading or running a program failed.
+#[derive(Debug)]
+pub enum Error {
+  Io(std::io::Error),
+  /// The source does not parse or desugar.
+  Syntax(String),
+  /// The compiled module is malformed, or can't be run by the machine.
+  Module(String),
+  /// The process running the call crashed.
+  Crash(Crash<Value>),
+}
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Error::Io(err) => write!(f, "{err}"),
+      Error::Syntax(err) | Error::Module(err) => write!(f, "{err}"),
+      Error::Crash(crash) => write!(f, "{crash}"),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+  fn from(err: std::io::Error) -> Self {
+    Error::Io(err)
+  }
+}
+
+impl From<Crash<Value>> for Error {
+  fn from(crash: Crash<Value>) -> Self {
+    Error::Crash(crash)
+  }
+}
+
+/// A loaded program along with the host its runs see. Every run starts new
+/// processes, so nothing but the host is kept from one call to the next.
+pub struct Engine {
+  info: BytecodeInfo,
+  /// The source of the loaded program, unless it was loaded as a module.
+  source: Option<String>,
+  host: Host,
+  heap_limit: Option<usize>,
+  tracer: Option<Rc<RefCell<dyn Tracer>>>,
+  /// Whether runs use the register machine instead of the stack machine.
+  register: bool,
+  gc_stats: Stats,
+}
+
+impl Default for Engine {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Engine {
+  /// An engine with only the prelude loaded.
+  pub fn new() -> Self {
+    let mut ctx = Ctx::new();
+    ctx.program(desugar::Program {
+      definitions: BTreeMap::new(),
+    });
+    Self {
+      info: ctx.bytecode(),
+      source: Some(String::new()),
+      host: Host::default(),
+      heap_limit: None,
+      tracer: None,
+      register: false,
+      gc_stats: Stats::default(),
+    }
+  }
+
+  /// Sets the arguments programs get from `args()`.
+  pub fn with_args(mut self, args: Vec<String>) -> Self {
+    self.host.args = args;
+    self
+  }
+
+  /// Limits the values each process holds to `bytes`.
+  pub fn with_heap_limit(mut self, bytes: usize) -> Self {
+    self.heap_limit = Some(bytes);
+    self
+  }
+
+  /// Reports what every process does to `tracer`, which slows runs down as
+  /// described on [`Machine::with_tracer`].
+  pub fn with_tracer(mut self, tracer: Rc<RefCell<dyn Tracer>>) -> Self {
+    self.tracer = Some(tracer);
+    self
+  }
+
+  /// Runs programs with the register machine.
+  pub fn with_register_machine(mut self) -> Self {
+    self.register = true;
+    self
+  }
+
+  /// Replaces the loaded program with `src`, compiled along with the
+  /// prelude.
+  pub fn load_source(&mut self, src: &str) -> Result<(), Error> {
+    let program = Parser::new(Lexer::new(src))
+      .program()
+      .map_err(Error::Syntax)?;
+    let program = program.desugar().map_err(Error::Syntax)?;
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    let mut info = ctx.bytecode();
+    optimize(&mut info);
+    self.info = info;
+    self.source = Some(src.to_string());
+    Ok(())
+  }
+
+  /// Replaces the loaded program with a compiled module.
+  pub fn load_module(&mut self, bytes: &[u8]) -> Result<(), Error> {
+    self.info = module::read(bytes).map_err(Error::Module)?;
+    self.source = None;
+    Ok(())
+  }
+
+  /// Loads a compiled module if `path` ends with `.lalac`, and source
+  /// otherwise.
+  pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == "lalac") {
+      return self.load_module(&std::fs::read(path)?);
+    }
+    self.load_source(&std::fs::read_to_string(path)?)?;
+    self.info.source = Some(path.display().to_string());
+    Ok(())
+  }
+
+  /// The loaded program as a compiled module.
+  pub fn module(&self) -> Vec<u8> {
+    module::write(&self.info)
+  }
+
+  /// Lets programs call `native` as `name` with `arity` arguments, unless
+  /// they define or have a builtin of that name.
+  pub fn register(
+    &mut self,
+    name: &str,
+    arity: usize,
+    native: impl Fn(Vec<Value>) -> Result<Value, String> + 'static,
+  ) {
+    self.host.natives.register(name, arity, native);
+  }
+
+  /// Sends what programs print to a buffer instead of stdout, and returns it.
+  pub fn capture_output(&mut self) -> Capture {
+    let capture = Capture::default();
+    self.host.stdout = Box::new(capture.clone());
+    capture
+  }
+
+  /// What the collections of the stack machine did over every run so far.
+  pub fn gc_stats(&self) -> Stats {
+    self.gc_stats
+  }
+
+  /// Runs the entry of the program, `main/0` unless it is a compiled
+  /// expression.
+  pub fn run(&mut self) -> Result<Value, Error> {
+    let entry = self
+      .info
+      .entry
+      .ok_or(Crash::Error("The module has no entry".to_string()))?;
+    self.apply(entry, vec![])
+  }
+
+  /// Calls the function `name` of the program with `arguments`. Like
+  /// programs, callers can't reach the entry of a compiled expression or the
+  /// helpers of the prelude.
+  pub fn call(&mut self, name: &str, arguments: Vec<Value>) -> Result<Value, Error> {
+    let function = Some(name)
+      .filter(|name| !name.is_empty() && !name.contains('.'))
+      .and_then(|name| self.info.function(name))
+      .ok_or(Crash::Error(format!("Unbound variable {name}")))?;
+    let arity = self.info.functions[function].arity;
+    if arity != arguments.len() {
+      let len = arguments.len();
+      let err = format!("Expected {arity} arguments but got {len}");
+      return Err(Crash::Error(err).into());
+    }
+    self.apply(function, arguments)
+  }
+
+  /// Starts a REPL session over the loaded program, which takes the host
+  /// along. Sessions run on the evaluator, so the tracer and the register
+  /// machine don't apply to them.
+  pub fn into_session(self) -> Result<Session, Error> {
+    let source = self.source.ok_or(Error::Module(
+      "Sessions need a program loaded from source".to_string(),
+    ))?;
+    let program = Parser::new(Lexer::new(&source))
+      .program()
+      .and_then(|program| program.desugar())
+      .map_err(Error::Syntax)?;
+    let mut env = Env::from_program(program).with_host(self.host);
+    if let Some(bytes) = self.heap_limit {
+      env = env.with_heap_limit(bytes);
+    }
+    Ok(Session {
+      env,
+      fuel: None,
+      suspended: None,
+    })
+  }
+
+  fn apply(&mut self, function: usize, arguments: Vec<Value>) -> Result<Value, Error> {
+    // The machine borrows the host for the run and gives it back after.
+    let host = std::mem::take(&mut self.host);
+    let (outcome, host) = if self.register {
+      let program = match register::Program::new(&self.info) {
+        Ok(program) => program,
+        Err(err) => {
+          self.host = host;
+          return Err(Error::Module(err));
+        }
+      };
+      let mut machine = register::Machine::new(&program).with_host(host);
+      if let Some(bytes) = self.heap_limit {
+        machine = machine.with_heap_limit(bytes);
+      }
+      if let Some(tracer) = &self.tracer {
+        machine = machine.with_tracer(tracer.clone());
+      }
+      let outcome = machine.apply(function, arguments);
+      (outcome, machine.into_host())
+    } else {
+      let mut machine = Machine::new(&self.info).with_host(host);
+      if let Some(bytes) = self.heap_limit {
+        machine = machine.with_heap_limit(bytes);
+      }
+      if let Some(tracer) = &self.tracer {
+        machine = machine.with_tracer(tracer.clone());
+      }
+      let outcome = machine.apply(function, arguments);
+      self.gc_stats = self.gc_stats + machine.gc_stats();
+      (outcome, machine.into_host())
+    };
+    self.host = host;
+    Ok(outcome?)
+  }
+}
+
+/// Expressions evaluated one after another against a program. Unlike the
+/// runs of an [`Engine`], processes an expression spawns keep running through
+/// the next ones.
+pub struct Session {
+  env: Env,
+  fuel: Option<usize>,
+  /// The evaluation that last ran out of fuel.
+  suspended: Option<Suspended>,
+}
+
+impl Session {
+  /// Suspends evaluations once the processes have taken `fuel` reductions.
+  pub fn with_fuel(mut self, fuel: usize) -> Self {
+    self.fuel = Some(fuel);
+    self
+  }
+
+  /// Evaluates the expression `src`, cancelling the evaluation left
+  /// suspended, if any. Returns `None` if it runs out of fuel, in which case
+  /// [`Session::resume`] continues it.
+  pub fn eval(&mut self, src: &str) -> Result<Option<Value>, Error> {
+    if let Some(suspended) = self.suspended.take() {
+      self.env.cancel(suspended);
+    }
+    let expr = Parser::new(Lexer::new(src))
+      .expression()
+      .map_err(Error::Syntax)?
+      .desugar()
+      .map_err(|()| Error::Syntax("Desugar expr".to_string()))?;
+    let evaluation = match self.fuel {
+      Some(fuel) => self.env.eval_with_fuel(expr, fuel),
+      None => Evaluation::Done(self.env.eval(expr)),
+    };
+    self.finish(evaluation)
+  }
+
+  /// Continues the evaluation that last ran out of fuel with as much fuel
+  /// again.
+  pub fn resume(&mut self) -> Result<Option<Value>, Error> {
+    let suspended = self
+      .suspended
+      .take()
+      .ok_or(Crash::Error("Nothing to continue".to_string()))?;
+    let evaluation = self.env.resume(suspended, self.fuel);
+    self.finish(evaluation)
+  }
+
+  fn finish(&mut self, evaluation: Evaluation<Value>) -> Result<Option<Value>, Error> {
+    match evaluation {
+      Evaluation::Done(result) => Ok(Some(result.map_err(Crash::Error)?)),
+      Evaluation::OutOfFuel(suspended) => {
+        self.suspended = Some(suspended);
+        Ok(None)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{process::Crash, value::Value};
+
+  use super::{Engine, Error};
+
+  const SRC: &str = r#"
+fn greet(name) -> println(concat("hello ", name))
+fn area({w, h}) -> w * h
+fn twice(x) -> host_double(host_double(x))
+fn fail(x) -> throw(x)
+fn count(0) -> 0
+fn count(n) -> 1 + count(n - 1)
+fn main() -> area({2, 3})
+"#;
+
+  fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.load_source(SRC).unwrap();
+    engine.register("host_double", 1, |arguments| match arguments[..] {
+      [Value::Number(n)] => Ok(Value::Number(2 * n)),
+      _ => Err("host_double: bad argument".to_string()),
+    });
+    engine
+  }
+
+  #[test]
+  fn calls() {
+    for mut engine in [engine(), engine().with_register_machine()] {
+      assert_eq!(engine.run().unwrap(), Value::Number(6));
+      let size = Value::Tuple([Value::Number(4), Value::Number(5)].into());
+      assert_eq!(engine.call("area", vec![size]).unwrap(), Value::Number(20));
+      let twice = engine.call("twice", vec![Value::Number(3)]).unwrap();
+      assert_eq!(twice, Value::Number(12));
+      let output = engine.capture_output();
+      engine
+        .call("greet", vec![Value::String("lala".into())])
+        .unwrap();
+      assert_eq!(output.contents(), "hello lala\n");
+    }
+  }
+
+  #[test]
+  fn errors() {
+    let mut engine = engine();
+    let thrown = engine.call("fail", vec![Value::Number(1)]);
+    assert!(matches!(
+      thrown,
+      Err(Error::Crash(Crash::Throw(Value::Number(1))))
+    ));
+    let bad = engine.call("twice", vec![Value::Nil]);
+    let Err(Error::Crash(Crash::Error(err))) = bad else {
+      panic!("{bad:?}");
+    };
+    assert_eq!(err, "host_double: bad argument");
+    let missing = engine.call("area", vec![]).unwrap_err();
+    assert_eq!(missing.to_string(), "Expected 1 arguments but got 0");
+    let unbound = engine.call("nowhere", vec![]).unwrap_err();
+    assert_eq!(unbound.to_string(), "Unbound variable nowhere");
+    for internal in ["", "supervisor.supervise"] {
+      let unbound = engine.call(internal, vec![]).unwrap_err();
+      assert_eq!(unbound.to_string(), format!("Unbound variable {internal}"));
+    }
+    assert!(matches!(
+      engine.load_source("fn f( ->"),
+      Err(Error::Syntax(_))
+    ));
+    assert!(matches!(engine.load_module(b"nope"), Err(Error::Module(_))));
+  }
+
+  #[test]
+  fn modules() {
+    let bytes = engine().module();
+    let mut engine = Engine::new();
+    engine.register("host_double", 1, |arguments| Ok(arguments[0].clone()));
+    engine.load_module(&bytes).unwrap();
+    let twice = engine.call("twice", vec![Value::Number(3)]).unwrap();
+    assert_eq!(twice, Value::Number(3));
+  }
+
+  #[test]
+  fn sessions() {
+    let mut engine = engine();
+    let output = engine.capture_output();
+    let mut session = engine.into_session().unwrap().with_fuel(100);
+    assert_eq!(
+      session.eval("area({2, 5})").unwrap(),
+      Some(Value::Number(10))
+    );
+    assert_eq!(session.eval("twice(4)").unwrap(), Some(Value::Number(16)));
+    session.eval("greet(\"again\")").unwrap();
+    assert_eq!(output.contents(), "hello again\n");
+    let mut result = session.eval("count(500)");
+    let mut resumes = 0;
+    while let Ok(None) = result {
+      resumes += 1;
+      result = session.resume();
+    }
+    assert!(resumes > 0);
+    assert_eq!(result.unwrap(), Some(Value::Number(500)));
+    let nothing = session.resume().unwrap_err();
+    assert_eq!(nothing.to_string(), "Nothing to continue");
+    assert!(matches!(session.eval("area(("), Err(Error::Syntax(_))));
+    let mut engine = Engine::new();
+    engine.load_module(&Engine::new().module()).unwrap();
+    assert!(matches!(engine.into_session(), Err(Error::Module(_))));
+  }
+}
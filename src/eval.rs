@@ -1,140 +1,935 @@
-use std::collections::{BTreeMap, HashMap};
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  rc::Rc,
+};
 
-use crate::desugar::{self, Cond, Expression as Desugar, Operation, Tree};
+use indexmap::IndexMap;
+
+use crate::{
+  atom::Atom,
+  builtins::Builtin,
+  desugar::{self, Acc, Cond, Expression as Desugar, Occurrence, Operation, Tree},
+  heap::{Heap, HeapSize},
+  host::Host,
+  prelude,
+  process::{Context, Crash, Evaluation, Process, Scheduler, Status, Suspended, Term},
+  value::{self, Value},
+};
 
-#[derive(Clone)]
 pub struct Env {
-  fn_definitions: BTreeMap<String, desugar::FnDefinition>,
-  variables: HashMap<String, Value>,
+  program: Program,
+  scheduler: Scheduler<Task>,
 }
 
-#[derive(Clone, Debug)]
-pub enum Value {
-  Number(i32),
-  String(String),
-  Atom(String),
-  Tuple(Vec<Value>),
-  Function(Vec<String>, desugar::Expr),
+/// Definitions and host shared by every process of an [`Env`].
+pub struct Program {
+  /// Functions are referred to by their index in this table.
+  fn_definitions: IndexMap<String, desugar::FnDefinition>,
+  host: Host,
+  heap_limit: Option<usize>,
 }
 
 impl Env {
+  /// Creates an environment with the prelude and the definitions of `program`,
+  /// the latter taking precedence.
   pub fn from_program(program: desugar::Program) -> Self {
+    let mut fn_definitions: IndexMap<_, _> = prelude::definitions().into_iter().collect();
+    fn_definitions.extend(program.definitions);
     Self {
-      fn_definitions: program.definitions,
-      variables: HashMap::new(),
+      program: Program {
+        fn_definitions,
+        host: Host::default(),
+        heap_limit: None,
+      },
+      scheduler: Scheduler::new(),
     }
   }
 
-  fn fetch(&self, name: &str) -> Result<Value, String> {
-    if let Some(value) = self.variables.get(name).cloned() {
-      return Ok(value);
+  pub fn with_host(mut self, host: Host) -> Self {
+    self.program.host = host;
+    self
+  }
+
+  /// Limits the values each process holds to `bytes`, raising a
+  /// `#system_limit` error in processes that go over.
+  pub fn with_heap_limit(mut self, bytes: usize) -> Self {
+    self.program.heap_limit = Some(bytes);
+    self
+  }
+
+  /// Evaluates `expr` in a new process, running the other processes alongside
+  /// it until it exits. Processes that outlive it resume on the next call.
+  pub fn eval(&mut self, expr: desugar::Expression) -> Result<Value, String> {
+    let pid = self
+      .scheduler
+      .spawn(Task::new(expr).with_heap_limit(self.program.heap_limit));
+    self.scheduler.run_until(&mut self.program, pid)
+  }
+
+  /// Like [`Env::eval`], but gives up once the processes have taken `fuel`
+  /// reductions, leaving the evaluation to be resumed or cancelled.
+  pub fn eval_with_fuel(&mut self, expr: desugar::Expression, fuel: usize) -> Evaluation<Value> {
+    let pid = self
+      .scheduler
+      .spawn(Task::new(expr).with_heap_limit(self.program.heap_limit));
+    self.scheduler.run_for(&mut self.program, pid, Some(fuel))
+  }
+
+  /// Continues an evaluation that ran out of fuel with `fuel` more reductions.
+  pub fn resume(&mut self, suspended: Suspended, fuel: Option<usize>) -> Evaluation<Value> {
+    self.scheduler.run_for(&mut self.program, suspended.0, fuel)
+  }
+
+  /// Kills the process of an evaluation that ran out of fuel.
+  pub fn cancel(&mut self, suspended: Suspended) {
+    self.scheduler.kill(suspended.0);
+  }
+
+  /// Calls `function` with already evaluated `arguments` in a new process.
+  pub fn apply(&mut self, function: Value, arguments: Vec<Value>) -> Result<Value, String> {
+    let pid = self.scheduler.spawn(
+      Task::apply(&self.program, function, arguments)?.with_heap_limit(self.program.heap_limit),
+    );
+    self.scheduler.run_until(&mut self.program, pid)
+  }
+}
+
+impl Program {
+  fn fetch(&self, variables: &Variables, name: &str) -> Result<Value, String> {
+    if let Some(value) = variables.get(name).cloned() {
+      Ok(value)
     } else {
       self
         .fn_definitions
-        .get(name)
-        .map(|f| Value::Function(f.parameters.clone(), f.body.clone()))
+        .get_full(name)
+        .map(|(id, _, f)| Value::Function {
+          id,
+          arity: f.parameters.len(),
+        })
         .ok_or(format!("Unbound variable {name}"))
     }
   }
 
-  pub fn eval(&mut self, expr: desugar::Expression) -> Result<Value, String> {
+  fn is_bound(&self, variables: &Variables, name: &str) -> bool {
+    variables.contains_key(name) || self.fn_definitions.contains_key(name)
+  }
+}
+
+type Variables = Rc<HashMap<String, Value>>;
+
+/// A process of the evaluator. Evaluation keeps its continuation in `stack`
+/// instead of the Rust stack, so it can be suspended after any step.
+pub struct Task {
+  control: Control,
+  variables: Variables,
+  stack: Vec<Frame>,
+  heap: Heap,
+}
+
+enum Control {
+  Eval(Desugar),
+  Return(Value),
+}
+
+enum Callee {
+  Function(Value),
+  Builtin(Builtin),
+  /// A native of the host, called by name.
+  Native(String),
+}
+
+/// The rest of the evaluation, waiting for the value of a subexpression.
+enum Frame {
+  Let {
+    bind: String,
+    next: Desugar,
+    variables: Variables,
+  },
+  Callee {
+    arguments: Vec<Desugar>,
+    variables: Variables,
+  },
+  Arguments {
+    callee: Callee,
+    values: Vec<Value>,
+    /// Arguments still to evaluate, in reverse order.
+    rest: Vec<Desugar>,
+    variables: Variables,
+  },
+  Tuple {
+    values: Vec<Value>,
+    rest: Vec<Desugar>,
+    variables: Variables,
+  },
+  Lhs {
+    op: Operation,
+    rhs: Desugar,
+    variables: Variables,
+  },
+  Rhs {
+    op: Operation,
+    lhs: Value,
+  },
+  If {
+    then_branch: Desugar,
+    else_branch: Desugar,
+    variables: Variables,
+  },
+  Access {
+    idx: Acc,
+  },
+  Head {
+    tl: Desugar,
+    variables: Variables,
+  },
+  Tail {
+    hd: Value,
+  },
+  /// The handler of a `try` whose body is being evaluated.
+  Catch {
+    class: String,
+    reason: String,
+    tree: Tree,
+    actions: Vec<Desugar>,
+    variables: Variables,
+  },
+}
+
+enum Step {
+  Continue,
+  Wait,
+  Done(Value),
+}
+
+impl Task {
+  pub fn new(expr: Desugar) -> Self {
+    Self {
+      control: Control::Eval(expr),
+      variables: Rc::default(),
+      stack: vec![],
+      heap: Heap::default(),
+    }
+  }
+
+  pub fn with_heap_limit(mut self, limit: Option<usize>) -> Self {
+    self.heap = Heap::new(limit);
+    self
+  }
+
+  /// Accounts for `value` having been allocated, raising `#system_limit` if
+  /// the process now holds too much.
+  fn charge(&mut self, ctx: &Context<'_, Task>, value: &Value) -> Result<(), Crash<Value>> {
+    let size = value.heap_size();
+    if self.heap.alloc(size) && self.heap.measured(self.live_size(ctx.mailbox) + size) {
+      return Err(Crash::Raise(Value::atom("system_limit")));
+    }
+    Ok(())
+  }
+
+  /// Returns a newly allocated `value`.
+  fn alloc(&mut self, ctx: &Context<'_, Task>, value: Value) -> Result<Control, Crash<Value>> {
+    self.charge(ctx, &value)?;
+    Ok(Control::Return(value))
+  }
+
+  /// Size of the values reachable from the continuation and the mailbox.
+  fn live_size(&self, mailbox: &VecDeque<Value>) -> usize {
+    let mut scopes = vec![&self.variables];
+    let mut values: Vec<&Value> = mailbox.iter().collect();
+    if let Control::Return(value) = &self.control {
+      values.push(value);
+    }
+    for frame in &self.stack {
+      match frame {
+        Frame::Let { variables, .. }
+        | Frame::Callee { variables, .. }
+        | Frame::Lhs { variables, .. }
+        | Frame::If { variables, .. }
+        | Frame::Head { variables, .. }
+        | Frame::Catch { variables, .. } => scopes.push(variables),
+        Frame::Arguments {
+          callee,
+          values: evaluated,
+          variables,
+          ..
+        } => {
+          scopes.push(variables);
+          values.extend(evaluated);
+          if let Callee::Function(function) = callee {
+            values.push(function);
+          }
+        }
+        Frame::Tuple {
+          values: evaluated,
+          variables,
+          ..
+        } => {
+          scopes.push(variables);
+          values.extend(evaluated);
+        }
+        Frame::Rhs { lhs, .. } => values.push(lhs),
+        Frame::Tail { hd } => values.push(hd),
+        Frame::Access { .. } => (),
+      }
+    }
+    let mut seen = HashSet::new();
+    scopes.retain(|scope| seen.insert(Rc::as_ptr(scope)));
+    let variables = scopes.into_iter().flat_map(|scope| scope.values());
+    value::live_size(values.into_iter().chain(variables))
+  }
+
+  /// A task that calls `function` of `program` with `arguments`.
+  pub fn apply(program: &Program, function: Value, arguments: Vec<Value>) -> Result<Self, String> {
+    let mut task = Self::new(Desugar::Nil);
+    task.call(program, function, arguments)?;
+    Ok(task)
+  }
+
+  fn call(
+    &mut self,
+    program: &Program,
+    function: Value,
+    arguments: Vec<Value>,
+  ) -> Result<(), String> {
+    let definition = match function {
+      Value::Function { id, .. } => program.fn_definitions.get_index(id).map(|(_, f)| f),
+      _ => None,
+    };
+    let Some(definition) = definition else {
+      return Err("Expected call to a function definition".to_string());
+    };
+    if definition.parameters.len() != arguments.len() {
+      return Err(format!(
+        "Expected {} arguments but got {}",
+        definition.parameters.len(),
+        arguments.len()
+      ));
+    }
+    let parameters = definition.parameters.iter().cloned();
+    self.variables = Rc::new(parameters.zip(arguments).collect());
+    self.control = Control::Eval((*definition.body).clone());
+    Ok(())
+  }
+
+  fn call_builtin(
+    &mut self,
+    program: &mut Program,
+    ctx: &mut Context<'_, Task>,
+    builtin: Builtin,
+    arguments: Vec<Value>,
+  ) -> Result<Value, Crash<Value>> {
+    match builtin {
+      Builtin::Spawn | Builtin::SpawnLink => {
+        builtin.check_arity(arguments.len())?;
+        let mut arguments = arguments.into_iter();
+        let function = arguments.next().unwrap();
+        let Some(arguments) = arguments.next().unwrap().to_vec() else {
+          return Err(Crash::Error(format!("{}: bad argument", builtin.name())));
+        };
+        let task = Task::apply(program, function, arguments)?;
+        let pid = ctx.spawn(task.with_heap_limit(program.heap_limit));
+        if builtin == Builtin::SpawnLink {
+          ctx.link(pid);
+        }
+        Ok(Value::Pid(pid))
+      }
+      _ if builtin.is_process() => ctx.call_builtin(builtin, arguments),
+      _ => {
+        let value = builtin.apply(&mut program.host, arguments)?;
+        self.charge(ctx, &value)?;
+        Ok(value)
+      }
+    }
+  }
+
+  /// Evaluates the next argument of a call, or makes the call once all of
+  /// them have been evaluated.
+  fn arguments(
+    &mut self,
+    program: &mut Program,
+    ctx: &mut Context<'_, Task>,
+    callee: Callee,
+    values: Vec<Value>,
+    mut rest: Vec<Desugar>,
+    variables: Variables,
+  ) -> Result<Control, Crash<Value>> {
+    if let Some(next) = rest.pop() {
+      self.variables = variables.clone();
+      self.stack.push(Frame::Arguments {
+        callee,
+        values,
+        rest,
+        variables,
+      });
+      return Ok(Control::Eval(next));
+    }
+    match callee {
+      Callee::Function(function) => {
+        self.call(program, function, values)?;
+        Ok(std::mem::replace(
+          &mut self.control,
+          Control::Return(Value::Nil),
+        ))
+      }
+      Callee::Builtin(builtin) => Ok(Control::Return(
+        self.call_builtin(program, ctx, builtin, values)?,
+      )),
+      Callee::Native(name) => {
+        let value = program.host.natives.call(&name, values)?;
+        self.charge(ctx, &value)?;
+        Ok(Control::Return(value))
+      }
+    }
+  }
+
+  /// Drops frames up to the innermost `try` whose catch arms accept `crash`
+  /// and continues with the selected arm, or gives `crash` back if there is
+  /// none.
+  fn unwind(&mut self, program: &Program, crash: Crash<Value>) -> Result<(), Crash<Value>> {
+    while let Some(frame) = self.stack.pop() {
+      let Frame::Catch {
+        class,
+        reason,
+        tree,
+        mut actions,
+        variables,
+      } = frame
+      else {
+        continue;
+      };
+      let mut variables = (*variables).clone();
+      variables.insert(class, Value::atom(crash.class()));
+      variables.insert(reason, crash.value());
+      let variables = Rc::new(variables);
+      if let Some(action) = tree.select(program, &variables)? {
+        self.variables = variables;
+        self.control = Control::Eval(actions.swap_remove(action));
+        return Ok(());
+      }
+    }
+    Err(crash)
+  }
+
+  fn step(
+    &mut self,
+    program: &mut Program,
+    ctx: &mut Context<'_, Task>,
+  ) -> Result<Step, Crash<Value>> {
+    let control = std::mem::replace(&mut self.control, Control::Return(Value::Nil));
+    self.control = match control {
+      Control::Eval(Desugar::Receive {
+        name,
+        tree,
+        mut actions,
+      }) => {
+        for idx in 0..ctx.mailbox.len() {
+          let mut variables = (*self.variables).clone();
+          variables.insert(name.clone(), ctx.mailbox[idx].clone());
+          let variables = Rc::new(variables);
+          if let Some(action) = tree.select(program, &variables)? {
+            ctx.mailbox.remove(idx);
+            self.variables = variables;
+            self.control = Control::Eval(actions.swap_remove(action));
+            return Ok(Step::Continue);
+          }
+        }
+        self.control = Control::Eval(Desugar::Receive {
+          name,
+          tree,
+          actions,
+        });
+        return Ok(Step::Wait);
+      }
+      Control::Eval(expr) => self.eval(program, ctx, expr)?,
+      Control::Return(value) => match self.stack.pop() {
+        None => return Ok(Step::Done(value)),
+        Some(frame) => self.resume(program, ctx, frame, value)?,
+      },
+    };
+    Ok(Step::Continue)
+  }
+
+  fn eval(
+    &mut self,
+    program: &mut Program,
+    ctx: &mut Context<'_, Task>,
+    expr: Desugar,
+  ) -> Result<Control, Crash<Value>> {
+    let variables = self.variables.clone();
     match expr {
-      Desugar::Variable { name } => self.fetch(&name),
-      Desugar::Number { value } => Ok(Value::Number(value)),
-      Desugar::Atom { value } => Ok(Value::Atom(value)),
-      Desugar::String { value } => Ok(Value::String(value)),
+      Desugar::Variable { name } => Ok(Control::Return(program.fetch(&variables, &name)?)),
+      Desugar::Number { value } => Ok(Control::Return(Value::Number(value))),
+      Desugar::Atom { value } => Ok(Control::Return(Value::Atom(value))),
+      Desugar::String { value } => self.alloc(ctx, Value::String(value.into())),
       Desugar::Let { bind, value, next } => {
-        let mut new_env = self.clone();
-        new_env.variables.insert(bind, self.eval(*value)?);
-        new_env.eval(*next)
+        self.stack.push(Frame::Let {
+          bind,
+          next: *next,
+          variables,
+        });
+        Ok(Control::Eval(*value))
       }
-      Desugar::Match { ref tree, actions } => {
-        let idx = tree.eval(self)?;
-        let body = actions[idx].clone();
-        self.eval(body)
+      Desugar::Try {
+        body,
+        class,
+        reason,
+        tree,
+        actions,
+      } => {
+        self.stack.push(Frame::Catch {
+          class,
+          reason,
+          tree,
+          actions,
+          variables,
+        });
+        Ok(Control::Eval(*body))
       }
-      Desugar::Tuple { elements } => Ok(Value::Tuple(
-        elements.into_iter().flat_map(|e| self.eval(e)).collect(),
-      )),
-      Desugar::Binary { op, lhs, rhs } => match (op, self.eval(*lhs)?, self.eval(*rhs)?) {
-        (Operation::Add, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
-        (Operation::Sub, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a - b)),
-        (Operation::Mul, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a * b)),
-        (Operation::Div, Value::Number(a), Value::Number(b)) => Ok(Value::Number(a / b)),
-        (Operation::Equal, ref x, ref y) if equality(x, y) => Ok(Value::Atom("true".to_string())),
-        (Operation::Equal, _, _) => Ok(Value::Atom("false".to_string())),
-        _ => Err(format!("Invalid binary operation.")),
+      Desugar::Match { tree, mut actions } => match tree.select(program, &variables)? {
+        Some(idx) => Ok(Control::Eval(actions.swap_remove(idx))),
+        None => Err(Crash::Error("Match failure".to_string())),
       },
-      Desugar::Call { callee, arguments } => match self.eval(*callee)? {
-        Value::Function(parameters, body) => {
-          let mut new_env = self.clone();
-          for (x, y) in parameters.into_iter().zip(arguments) {
-            new_env.variables.insert(x, self.eval(y)?);
+      Desugar::Tuple { elements } => {
+        let mut rest = elements;
+        rest.reverse();
+        match rest.pop() {
+          Some(first) => {
+            self.stack.push(Frame::Tuple {
+              values: vec![],
+              rest,
+              variables,
+            });
+            Ok(Control::Eval(first))
           }
-          new_env.eval(*body)
+          None => Ok(Control::Return(Value::Tuple([].into()))),
         }
-        _ => Err(format!("Expected call to a function definition")),
-      },
-      Desugar::Access { expr, idx } => match self.eval(*expr)? {
-        Value::Tuple(elements) => {
-          todo!()
-          // if let Some(item) = elements.get(idx).cloned() {
-          //   Ok(item)
-          // } else {
-          //   Err(format!("Index {} out of bounds", idx))
-          // }
+      }
+      Desugar::Binary { op, lhs, rhs } => {
+        self.stack.push(Frame::Lhs {
+          op,
+          rhs: *rhs,
+          variables,
+        });
+        Ok(Control::Eval(*lhs))
+      }
+      Desugar::Call { callee, arguments } => match *callee {
+        Desugar::Variable { ref name } if !program.is_bound(&variables, name) => {
+          let callee = match Builtin::from_name(name, arguments.len()) {
+            Some(builtin) => Callee::Builtin(builtin),
+            None => Callee::Native(name.clone()),
+          };
+          let mut rest = arguments;
+          rest.reverse();
+          self.arguments(program, ctx, callee, vec![], rest, variables)
+        }
+        callee => {
+          self.stack.push(Frame::Callee {
+            arguments,
+            variables,
+          });
+          Ok(Control::Eval(callee))
         }
-        _ => Err(format!("Accessing not tuple element")),
       },
+      Desugar::Access { expr, idx } => {
+        self.stack.push(Frame::Access { idx });
+        Ok(Control::Eval(*expr))
+      }
       Desugar::If {
         condition,
         then_branch,
         else_branch,
-      } => match self.eval(*condition)? {
-        Value::Atom(ref value) if value == "true" => self.eval(*then_branch),
-        _ => self.eval(*else_branch),
-      },
-      Desugar::Cons { hd: _, tl: _ } => todo!(),
-      Desugar::Nil => todo!(),
+      } => {
+        self.stack.push(Frame::If {
+          then_branch: *then_branch,
+          else_branch: *else_branch,
+          variables,
+        });
+        Ok(Control::Eval(*condition))
+      }
+      Desugar::Cons { hd, tl } => {
+        self.stack.push(Frame::Head { tl: *tl, variables });
+        Ok(Control::Eval(*hd))
+      }
+      Desugar::Nil => Ok(Control::Return(Value::Nil)),
+      Desugar::Receive { .. } => unreachable!(),
+    }
+  }
+
+  fn resume(
+    &mut self,
+    program: &mut Program,
+    ctx: &mut Context<'_, Task>,
+    frame: Frame,
+    value: Value,
+  ) -> Result<Control, Crash<Value>> {
+    match frame {
+      Frame::Let {
+        bind,
+        next,
+        variables,
+      } => {
+        let mut variables = (*variables).clone();
+        variables.insert(bind, value);
+        self.variables = Rc::new(variables);
+        Ok(Control::Eval(next))
+      }
+      Frame::Callee {
+        arguments,
+        variables,
+      } => {
+        let mut rest = arguments;
+        rest.reverse();
+        let callee = Callee::Function(value);
+        self.arguments(program, ctx, callee, vec![], rest, variables)
+      }
+      Frame::Arguments {
+        callee,
+        mut values,
+        rest,
+        variables,
+      } => {
+        values.push(value);
+        self.arguments(program, ctx, callee, values, rest, variables)
+      }
+      Frame::Tuple {
+        mut values,
+        mut rest,
+        variables,
+      } => {
+        values.push(value);
+        match rest.pop() {
+          Some(next) => {
+            self.variables = variables.clone();
+            self.stack.push(Frame::Tuple {
+              values,
+              rest,
+              variables,
+            });
+            Ok(Control::Eval(next))
+          }
+          None => self.alloc(ctx, Value::Tuple(values.into())),
+        }
+      }
+      Frame::Lhs { op, rhs, variables } => {
+        self.variables = variables;
+        self.stack.push(Frame::Rhs { op, lhs: value });
+        Ok(Control::Eval(rhs))
+      }
+      Frame::Rhs { op, lhs } => Ok(Control::Return(binary(op, lhs, value)?)),
+      Frame::If {
+        then_branch,
+        else_branch,
+        variables,
+      } => {
+        self.variables = variables;
+        match value {
+          Value::Atom(Atom::TRUE) => Ok(Control::Eval(then_branch)),
+          _ => Ok(Control::Eval(else_branch)),
+        }
+      }
+      Frame::Access { idx } => Ok(Control::Return(access(value, &idx)?)),
+      Frame::Head { tl, variables } => {
+        self.variables = variables;
+        self.stack.push(Frame::Tail { hd: value });
+        Ok(Control::Eval(tl))
+      }
+      Frame::Tail { hd } => self.alloc(ctx, Value::cons(hd, value)),
+      Frame::Catch { .. } => Ok(Control::Return(value)),
+    }
+  }
+}
+
+impl Process for Task {
+  type Value = Value;
+  type Shared = Program;
+
+  fn run(
+    &mut self,
+    program: &mut Program,
+    ctx: &mut Context<'_, Self>,
+    reductions: &mut usize,
+  ) -> Status<Value> {
+    while *reductions > 0 {
+      *reductions -= 1;
+      match self.step(program, ctx) {
+        Ok(Step::Continue) => (),
+        Ok(Step::Wait) => return Status::Waiting,
+        Ok(Step::Done(value)) => return Status::Exited(Ok(value)),
+        Err(crash) => {
+          if let Err(crash) = self.unwind(program, crash) {
+            return Status::Exited(Err(crash));
+          }
+        }
+      }
     }
+    Status::Yielded
   }
 }
 
-fn equality(x: &Value, y: &Value) -> bool {
-  match (x, y) {
-    (Value::Number(a), Value::Number(b)) if a == b => true,
-    (Value::String(a), Value::String(b)) if a == b => true,
-    (Value::Atom(a), Value::Atom(b)) if a == b => true,
-    (Value::Tuple(a), Value::Tuple(b)) if a.len() == b.len() => a
-      .into_iter()
-      .zip(b.into_iter())
-      .all(|(x, y)| equality(x, y)),
-    _ => false,
+fn binary(op: Operation, lhs: Value, rhs: Value) -> Result<Value, String> {
+  match (op, lhs, rhs) {
+    (Operation::Div, Value::Number(_), Value::Number(0)) => Err("Division by zero".to_string()),
+    (
+      op @ (Operation::Add | Operation::Sub | Operation::Mul | Operation::Div),
+      Value::Number(a),
+      Value::Number(b),
+    ) => {
+      let result = match op {
+        Operation::Add => a.checked_add(b),
+        Operation::Sub => a.checked_sub(b),
+        Operation::Mul => a.checked_mul(b),
+        _ => a.checked_div(b),
+      };
+      result
+        .map(Value::Number)
+        .ok_or("Arithmetic overflow".to_string())
+    }
+    (Operation::Equal, ref x, ref y) => Ok(Value::Atom(Atom::boolean(x == y))),
+    _ => Err("Invalid binary operation.".to_string()),
+  }
+}
+
+fn access(value: Value, idx: &Acc) -> Result<Value, String> {
+  match (value, idx) {
+    (Value::Tuple(elements), Acc::Tup(idx)) => elements
+      .get(*idx)
+      .cloned()
+      .ok_or(format!("Index {idx} out of bounds")),
+    (Value::Cons(cell), Acc::Head) => Ok(cell.0.clone()),
+    (Value::Cons(cell), Acc::Tail) => Ok(cell.1.clone()),
+    _ => Err("Accessing not tuple element".to_string()),
   }
 }
 
 impl Tree {
-  pub fn eval(&self, env: &mut Env) -> Result<usize, String> {
+  /// Finds the action selected by the tree, or `None` if no pattern matches.
+  fn select(&self, program: &Program, variables: &Variables) -> Result<Option<usize>, String> {
     match self {
-      Tree::Failure => Err("Match failure".to_string()),
-      Tree::Leaf(idx) => Ok(*idx),
+      Tree::Failure => Ok(None),
+      Tree::Leaf(idx) => Ok(Some(*idx)),
       Tree::Switch(occ, branches, default) => {
-        let expr = env.eval(occ.clone().to_expression())?;
+        let expr = occurrence(program, variables, occ)?;
         for (case, branch) in branches {
           let res = match (case, &expr) {
-            (Cond::Number(a), Value::Number(b)) if a == b => branch.eval(env),
-            (Cond::String(a), Value::String(b)) if a == b => branch.eval(env),
-            (Cond::Atom(a), Value::Atom(b)) if a == b => branch.eval(env),
-            (Cond::Tuple(a), Value::Tuple(b)) if *a == b.len() => branch.eval(env),
+            (Cond::Number(a), Value::Number(b)) if a == b => branch.select(program, variables)?,
+            (Cond::String(a), Value::String(b)) if **a == **b => {
+              branch.select(program, variables)?
+            }
+            (Cond::Atom(a), Value::Atom(b)) if a == b => branch.select(program, variables)?,
+            (Cond::Tuple(a), Value::Tuple(b)) if *a == b.len() => {
+              branch.select(program, variables)?
+            }
+            (Cond::Cons, Value::Cons(..)) => branch.select(program, variables)?,
+            (Cond::Nil, Value::Nil) => branch.select(program, variables)?,
             _ => continue,
           };
           match res {
-            Ok(leaf) => return Ok(leaf),
-            Err(_) => return default.eval(env),
+            Some(leaf) => return Ok(Some(leaf)),
+            None => return default.select(program, variables),
           }
         }
-        default.eval(env)
+        default.select(program, variables)
       }
     }
   }
 }
+
+/// Evaluates an occurrence of a case tree, which is always a variable
+/// followed by accesses into it.
+fn occurrence(program: &Program, variables: &Variables, occ: &Occurrence) -> Result<Value, String> {
+  let Desugar::Variable { name } = &occ.0 else {
+    return Err("Unsupported occurrence".to_string());
+  };
+  occ
+    .1
+    .iter()
+    .try_fold(program.fetch(variables, name)?, access)
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{
+    compile::{Ctx, Machine},
+    desugar::{self, Desugar},
+    eval::Env,
+    lexer::Lexer,
+    parser::Parser,
+    process::Evaluation,
+    value::Value,
+  };
+
+  fn parse(src: &str) -> Result<desugar::Expression, String> {
+    Ok(
+      Parser::new(Lexer::new(src))
+        .expression()?
+        .desugar()
+        .unwrap(),
+    )
+  }
+
+  fn env(src: &str) -> Env {
+    let program = Parser::new(Lexer::new(src))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    Env::from_program(program)
+  }
+
+  fn eval(src: &str) -> Result<String, String> {
+    env("").eval(parse(src)?).map(|v| v.to_string())
+  }
+
+  fn run(src: &str) -> Result<String, String> {
+    let expr = Parser::new(Lexer::new(src))
+      .expression()?
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.fn_clause(expr);
+    let info = ctx.bytecode();
+    let value = Machine::new(&info).run()?;
+    Ok(value.to_string())
+  }
+
+  #[test]
+  fn exceptions() {
+    let cases = [
+      (
+        "try throw(#oops) catch #throw:r -> {#caught, r} end",
+        Ok("{#caught, #oops}"),
+      ),
+      ("try throw(1) catch r -> r end", Ok("1")),
+      (
+        "try error({#bad, 1}) catch #error:r -> r end",
+        Ok("{#bad, 1}"),
+      ),
+      (
+        "try case 1 of 2 -> 2 end catch #error:r -> r end",
+        Ok(r#""Match failure""#),
+      ),
+      ("try exit(#bye) catch #exit:r -> r end", Ok("#bye")),
+      ("try 1 of 1 -> #one catch _:_ -> #caught end", Ok("#one")),
+      (
+        "try 1 of 2 -> #two catch _:_ -> #caught end",
+        Err("Match failure"),
+      ),
+      (
+        "try throw(#x) catch #error:_ -> #no end",
+        Err("Uncaught throw #x"),
+      ),
+      (
+        "try try throw(1) catch #error:_ -> 0 end catch n -> {#outer, n} end",
+        Ok("{#outer, 1}"),
+      ),
+      ("{1, try {2, throw(3)} catch n -> n end}", Ok("{1, 3}")),
+      (
+        "let x = try throw(1) catch n -> {n} end in {x, x}",
+        Ok("{{1}, {1}}"),
+      ),
+      ("error(\"boom\")", Err("boom")),
+      ("error(#boom)", Err("Uncaught error #boom")),
+    ];
+    for (src, expected) in cases {
+      let expected = expected.map(str::to_string).map_err(str::to_string);
+      assert_eq!(eval(src), expected, "{src}");
+      assert_eq!(run(src), expected, "{src}");
+    }
+  }
+
+  #[test]
+  fn arithmetic_errors() {
+    let cases = [
+      ("try 1 / 0 catch #error:r -> r end", r#""Division by zero""#),
+      (
+        "try 2147483647 + 1 catch #error:r -> r end",
+        r#""Arithmetic overflow""#,
+      ),
+    ];
+    for (src, expected) in cases {
+      assert_eq!(eval(src), Ok(expected.to_string()), "{src}");
+    }
+  }
+
+  #[test]
+  fn fuel() {
+    let mut env =
+      env("fn loop(n) -> loop(n + 1)\nfn count(0) -> #done\nfn count(n) -> count(n - 1)");
+
+    let Evaluation::OutOfFuel(suspended) = env.eval_with_fuel(parse("loop(0)").unwrap(), 10000)
+    else {
+      panic!("loop should run out of fuel");
+    };
+    assert!(matches!(
+      env.resume(suspended, Some(10000)),
+      Evaluation::OutOfFuel(_)
+    ));
+    env.cancel(suspended);
+    // The cancelled loop doesn't eat into the fuel of later evaluations.
+    let res = env.eval_with_fuel(parse("count(10)").unwrap(), 1000);
+    assert!(matches!(res, Evaluation::Done(Ok(Value::Atom(a))) if a.as_str() == "done"));
+
+    let mut res = env.eval_with_fuel(parse("count(1000)").unwrap(), 1000);
+    let mut resumed = 0;
+    while let Evaluation::OutOfFuel(suspended) = res {
+      resumed += 1;
+      res = env.resume(suspended, Some(1000));
+    }
+    assert!(resumed > 1);
+    assert!(matches!(res, Evaluation::Done(Ok(Value::Atom(a))) if a.as_str() == "done"));
+
+    // Resuming a cancelled evaluation fails, even with other processes still
+    // running.
+    let res = env.eval(parse("let p = spawn(loop, [0]) in #spawned").unwrap());
+    assert_eq!(res.map(|v| v.to_string()), Ok("#spawned".to_string()));
+    let unknown = format!("{} is not a running process", suspended.0);
+    assert!(matches!(env.resume(suspended, None), Evaluation::Done(Err(err)) if err == unknown));
+
+    let expr = parse("{1, {2, 3}, [4]}").unwrap();
+    let mut ctx = Ctx::new();
+    ctx.fn_clause(expr);
+    let info = ctx.bytecode();
+    let mut machine = Machine::new(&info);
+    let Evaluation::OutOfFuel(suspended) = machine.run_with_fuel(3) else {
+      panic!("the machine should run out of fuel");
+    };
+    let Evaluation::Done(Ok(value)) = machine.resume(suspended, None) else {
+      panic!("the machine should finish");
+    };
+    assert_eq!(value.to_string(), "{1, {2, 3}, [4]}");
+  }
+
+  #[test]
+  fn heap_limit() {
+    let src = r#"
+fn build(l) -> build([1 | l])
+fn range(0) -> []
+fn range(n) -> [n | range(n - 1)]
+fn churn(0) -> #done
+fn churn(n) -> let l = range(100) in churn(n - 1)
+"#;
+    let mut limited = env(src).with_heap_limit(20_000);
+    let eval = |env: &mut Env, src| env.eval(parse(src).unwrap()).map(|v| v.to_string());
+    let caught = "try build([]) catch #error:r -> r end";
+    assert_eq!(eval(&mut limited, caught), Ok("#system_limit".to_string()));
+    let uncaught = "build([])";
+    let err = "Uncaught error #system_limit";
+    assert_eq!(eval(&mut limited, uncaught), Err(err.to_string()));
+    // Values that are no longer reachable don't count towards the limit.
+    assert_eq!(eval(&mut limited, "churn(300)"), Ok("#done".to_string()));
+
+    let src = r#"try {"a string longer than the limit", 1} catch #error:r -> r end"#;
+    assert_eq!(
+      env("")
+        .with_heap_limit(16)
+        .eval(parse(src).unwrap())
+        .map(|v| v.to_string()),
+      Ok("#system_limit".to_string())
+    );
+    let mut ctx = Ctx::new();
+    ctx.fn_clause(parse(src).unwrap());
+    let info = ctx.bytecode();
+    let value = Machine::new(&info).with_heap_limit(16).run().unwrap();
+    assert_eq!(value.to_string(), "#system_limit");
+  }
+}
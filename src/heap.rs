@@ -0,0 +1,69 @@
+/// Size in bytes of the memory a value owns outside of its own slot.
+pub trait HeapSize {
+  fn heap_size(&self) -> usize;
+}
+
+/// Accounting of the memory held by the values of a process.
+///
+/// Allocations are added up as they happen. Once the total goes over the
+/// limit, the process measures the values it can still reach, and only fails
+/// if those alone are over the limit. As measuring takes time proportional to
+/// the live values, like a collection, the next one waits until the total is
+/// twice what was live.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Heap {
+  limit: Option<usize>,
+  used: usize,
+  /// The total past which the live values are measured again.
+  threshold: usize,
+}
+
+impl Heap {
+  pub fn new(limit: Option<usize>) -> Self {
+    Self {
+      limit,
+      used: 0,
+      threshold: limit.unwrap_or(usize::MAX),
+    }
+  }
+
+  pub fn limit(&self) -> Option<usize> {
+    self.limit
+  }
+
+  /// Records an allocation of `size` bytes, returning whether the live values
+  /// should be measured.
+  pub fn alloc(&mut self, size: usize) -> bool {
+    self.used = self.used.saturating_add(size);
+    self.limit.is_some() && self.used > self.threshold
+  }
+
+  /// Records that the live values take `live` bytes, returning whether that
+  /// is over the limit.
+  pub fn measured(&mut self, live: usize) -> bool {
+    let Some(limit) = self.limit else {
+      return false;
+    };
+    self.used = live;
+    self.threshold = limit.max(live.saturating_mul(2));
+    live > limit
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::Heap;
+
+  #[test]
+  fn remeasures_after_growth() {
+    let mut heap = Heap::new(Some(100));
+    assert!(!heap.alloc(100));
+    assert!(heap.alloc(1));
+    assert!(!heap.measured(90));
+    // Measuring again waits until the total doubles what was live.
+    assert!(!heap.alloc(89));
+    assert!(heap.alloc(2));
+    assert!(heap.measured(181));
+    assert!(!Heap::new(None).alloc(usize::MAX));
+  }
+}
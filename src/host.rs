@@ -0,0 +1,156 @@
+use std::{
+  cell::RefCell,
+  collections::HashMap,
+  io::{BufRead, Read, Write},
+  rc::Rc,
+};
+
+use crate::value::Value;
+
+/// The outside world as seen by a running program: its arguments, the
+/// streams used by the console builtins, the files used by the file builtins
+/// and the natives it may call.
+pub struct Host {
+  pub args: Vec<String>,
+  pub stdin: Box<dyn BufRead>,
+  pub stdout: Box<dyn Write>,
+  pub files: Box<dyn Files>,
+  pub natives: Natives,
+}
+
+impl Host {
+  pub fn new(args: Vec<String>) -> Self {
+    Self {
+      args,
+      stdin: Box::new(StdinLines::default()),
+      stdout: Box::new(std::io::stdout()),
+      files: Box::new(Disk),
+      natives: Natives::default(),
+    }
+  }
+
+  /// Reads the file at `path`, for the `read_file` builtin.
+  pub fn read_file(&mut self, path: &str) -> std::io::Result<String> {
+    self.files.read(path)
+  }
+
+  /// Replaces the contents of the file at `path`, for the `write_file`
+  /// builtin.
+  pub fn write_file(&mut self, path: &str, contents: &str) -> std::io::Result<()> {
+    self.files.write(path, contents)
+  }
+}
+
+impl Default for Host {
+  fn default() -> Self {
+    Self::new(vec![])
+  }
+}
+
+/// The files a program can read and write, so that the embedder can restrict
+/// or replace the file system.
+pub trait Files {
+  fn read(&mut self, path: &str) -> std::io::Result<String>;
+  fn write(&mut self, path: &str, contents: &str) -> std::io::Result<()>;
+}
+
+/// The files of the real file system.
+pub struct Disk;
+
+impl Files for Disk {
+  fn read(&mut self, path: &str) -> std::io::Result<String> {
+    std::fs::read_to_string(path)
+  }
+
+  fn write(&mut self, path: &str, contents: &str) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+  }
+}
+
+/// A function of the embedder, called with the values of its arguments.
+pub type Native = dyn Fn(Vec<Value>) -> Result<Value, String>;
+
+/// Functions registered by the embedder, which programs call by name like
+/// builtins when no definition has that name.
+#[derive(Clone, Default)]
+pub struct Natives(HashMap<String, (usize, Rc<Native>)>);
+
+impl Natives {
+  pub fn register(
+    &mut self,
+    name: &str,
+    arity: usize,
+    native: impl Fn(Vec<Value>) -> Result<Value, String> + 'static,
+  ) {
+    self.0.insert(name.to_string(), (arity, Rc::new(native)));
+  }
+
+  /// Calls the native `name`, failing like an unbound function if there is
+  /// none.
+  pub fn call(&self, name: &str, arguments: Vec<Value>) -> Result<Value, String> {
+    let Some((arity, native)) = self.0.get(name) else {
+      return Err(format!("Unbound variable {name}"));
+    };
+    if *arity != arguments.len() {
+      return Err(format!(
+        "{name}: expected {arity} arguments but got {}",
+        arguments.len()
+      ));
+    }
+    native(arguments)
+  }
+}
+
+/// Reads the process standard input one line at a time, so that nothing past
+/// the current line is taken from other readers of stdin such as the REPL.
+#[derive(Default)]
+struct StdinLines {
+  line: String,
+  pos: usize,
+}
+
+impl Read for StdinLines {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let available = self.fill_buf()?;
+    let len = available.len().min(buf.len());
+    buf[..len].copy_from_slice(&available[..len]);
+    self.consume(len);
+    Ok(len)
+  }
+}
+
+impl BufRead for StdinLines {
+  fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+    if self.pos >= self.line.len() {
+      self.line.clear();
+      self.pos = 0;
+      std::io::stdin().read_line(&mut self.line)?;
+    }
+    Ok(&self.line.as_bytes()[self.pos..])
+  }
+
+  fn consume(&mut self, amt: usize) {
+    self.pos += amt;
+  }
+}
+
+/// An in-memory output stream that can be inspected after being handed to a
+/// [`Host`].
+#[derive(Clone, Default)]
+pub struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+  pub fn contents(&self) -> String {
+    String::from_utf8_lossy(&self.0.borrow()).into_owned()
+  }
+}
+
+impl Write for Capture {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0.borrow_mut().write(buf)
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
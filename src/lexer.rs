@@ -29,8 +29,12 @@ pub enum TokenKind {
   If,
   Then,
   Else,
+  Receive,
+  Try,
+  Catch,
   Comma,
   Semicolon,
+  Colon,
   Period,
   Arrow,
   Error,
@@ -96,6 +100,9 @@ impl<'input> Lexer<'input> {
       "if" => TokenKind::If,
       "then" => TokenKind::Then,
       "else" => TokenKind::Else,
+      "receive" => TokenKind::Receive,
+      "try" => TokenKind::Try,
+      "catch" => TokenKind::Catch,
       _ => TokenKind::Identifier,
     }
   }
@@ -144,6 +151,7 @@ impl<'input> Lexer<'input> {
         }
         ',' => TokenKind::Comma,
         ';' => TokenKind::Semicolon,
+        ':' => TokenKind::Colon,
         '.' => TokenKind::Period,
         '\"' => {
           let mut s = String::new();
@@ -162,7 +170,7 @@ impl<'input> Lexer<'input> {
         }
         '#' => {
           self.save();
-          self.advance_while(|c| c.is_ascii_alphanumeric());
+          self.advance_while(|c| c.is_ascii_alphanumeric() || *c == '_');
           TokenKind::Atom
         }
         '_' => TokenKind::Wildcard,
@@ -171,7 +179,7 @@ impl<'input> Lexer<'input> {
           TokenKind::Number
         }
         c if c.is_ascii_alphabetic() => {
-          self.advance_while(|c| c.is_ascii_alphanumeric());
+          self.advance_while(|c| c.is_ascii_alphanumeric() || *c == '_');
           self.qualify()
         }
         _ => TokenKind::Error,
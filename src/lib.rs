@@ -0,0 +1,33 @@
+//! lala, a small functional language with Erlang-like processes.
+//!
+//! Programs are embedded through an [`Engine`], which loads source or
+//! compiled modules and calls their functions with [`Value`]s, which Rust
+//! types convert to and from through [`IntoValue`] and [`FromValue`], or
+//! [`TryIntoValue`] for those that may not fit.
+
+// Lets the derived conversions name this crate from within it.
+extern crate self as lala;
+
+pub mod ast;
+pub mod atom;
+pub mod builtins;
+pub mod compile;
+pub mod convert;
+pub mod desugar;
+#[cfg(test)]
+mod differential;
+mod engine;
+pub mod eval;
+pub mod heap;
+pub mod host;
+pub mod lexer;
+pub mod parser;
+pub mod prelude;
+pub mod process;
+pub mod value;
+
+pub use atom::Atom;
+pub use convert::{FromValue, IntoValue, TryIntoValue};
+pub use engine::{Engine, Error, Session};
+pub use lala_derive::{FromValue, IntoValue};
+pub use value::Value;
@@ -1,50 +1,127 @@
-use std::{fs::File, io::Read};
+use std::{cell::RefCell, path::Path, rc::Rc};
 
-use eval::Env;
-use lexer::Lexer;
-use parser::Parser;
-
-pub mod ast;
-pub mod compile;
-pub mod desugar;
-pub mod eval;
-pub mod lexer;
-pub mod parser;
-
-use desugar::Desugar;
+use lala::{compile::trace::Printer, Engine};
 
 fn main() -> std::io::Result<()> {
-  // let lay = std::alloc::Layout::array::<u64>(2).unwrap();
-  // let m = unsafe { std::alloc::alloc(lay) as *mut u64 };
-  // unsafe { *m.offset(0) = 42 };
-  // unsafe { *m.offset(1) = 43 };
-
-  // println!("{m:?}");
-  // println!("{:?}", unsafe { *m.offset(0) });
-
-  // unsafe { std::alloc::dealloc(m as *mut u8, lay) };
-  // Ok(())
-
-  let mut args = std::env::args();
-  if let Some(file_path) = args.nth(1) {
-    let mut file = File::open(file_path)?;
-    let mut buf = String::new();
-    file.read_to_string(&mut buf)?;
-    let mut parser = Parser::new(Lexer::new(&buf));
-    let program = parser.program().map_err(std::io::Error::other)?;
-    let program = program.desugar().map_err(std::io::Error::other)?;
-    let mut env = Env::from_program(program);
+  let mut args = std::env::args().skip(1).peekable();
+  // Reductions an expression may take before the REPL suspends it.
+  let mut fuel = None;
+  // Bytes of values each process may hold.
+  let mut max_heap = None;
+  // Whether `run` prints what the VM does to stderr.
+  let mut trace = false;
+  // Whether `run` uses the register machine instead of the stack machine.
+  let mut register = false;
+  // Whether `run` prints what the garbage collector did to stderr.
+  let mut gc_stats = false;
+  while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+    if flag == "--trace" {
+      trace = true;
+      continue;
+    }
+    if flag == "--register" {
+      register = true;
+      continue;
+    }
+    if flag == "--gc-stats" {
+      gc_stats = true;
+      continue;
+    }
+    let value = args.next().and_then(|n| n.parse::<usize>().ok());
+    let value = value.ok_or(std::io::Error::other(format!("{flag} expects a number")))?;
+    match flag.as_str() {
+      "--fuel" => fuel = Some(value),
+      "--max-heap" => max_heap = Some(value),
+      _ => return Err(std::io::Error::other(format!("Unknown flag {flag}"))),
+    }
+  }
+  let command = args.peek().map(String::as_str);
+  if fuel.is_some() && matches!(command, Some("compile" | "run")) {
+    return Err(std::io::Error::other("--fuel only applies to the REPL"));
+  }
+  if gc_stats && register {
+    return Err(std::io::Error::other(
+      "--gc-stats only applies to the stack machine",
+    ));
+  }
+  match command {
+    Some("compile") => {
+      args.next();
+      let source = args
+        .next()
+        .ok_or(std::io::Error::other("compile expects a source file"))?;
+      let output = match (args.next().as_deref(), args.next()) {
+        (Some("-o"), Some(output)) => output,
+        (None, _) => Path::new(&source)
+          .with_extension("lalac")
+          .display()
+          .to_string(),
+        _ => return Err(std::io::Error::other("Expected -o <output>")),
+      };
+      let mut engine = Engine::new();
+      engine
+        .load_file(&source)
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+      return std::fs::write(output, engine.module());
+    }
+    Some("run") => {
+      args.next();
+      let path = args
+        .next()
+        .ok_or(std::io::Error::other("run expects a file"))?;
+      let mut engine = Engine::new().with_args(args.collect());
+      engine
+        .load_file(&path)
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+      if let Some(bytes) = max_heap {
+        engine = engine.with_heap_limit(bytes);
+      }
+      if trace {
+        let printer = Printer::new(std::io::stderr());
+        engine = engine.with_tracer(Rc::new(RefCell::new(printer)));
+      }
+      if register {
+        engine = engine.with_register_machine();
+      }
+      let value = engine.run();
+      if gc_stats {
+        eprintln!("{:?}", engine.gc_stats());
+      }
+      let value = value.map_err(|err| std::io::Error::other(err.to_string()))?;
+      println!("{value}");
+      return Ok(());
+    }
+    _ => {}
+  }
+  if let Some(file_path) = args.next() {
+    let mut engine = Engine::new().with_args(args.collect());
+    engine
+      .load_file(&file_path)
+      .map_err(|err| std::io::Error::other(err.to_string()))?;
+    if let Some(bytes) = max_heap {
+      engine = engine.with_heap_limit(bytes);
+    }
+    let mut session = engine
+      .into_session()
+      .map_err(|err| std::io::Error::other(err.to_string()))?;
+    if let Some(fuel) = fuel {
+      session = session.with_fuel(fuel);
+    }
     loop {
       let mut buf = String::new();
-      let _ = std::io::stdin().read_line(&mut buf)?;
-      let mut parser = Parser::new(Lexer::new(&buf));
-      let expr = parser.expression().map_err(std::io::Error::other)?;
-      let res = env.eval(
-        expr
-          .desugar()
-          .map_err(|_| std::io::Error::other("Desugar expr"))?,
-      );
-      println!("{res:?}");
+      if std::io::stdin().read_line(&mut buf)? == 0 {
+        return Ok(());
+      }
+      let res = if buf.trim() == ":continue" {
+        session.resume()
+      } else {
+        session.eval(&buf)
+      };
+      match res {
+        Ok(Some(value)) => println!("{value}"),
+        Ok(None) => println!("Out of fuel, enter :continue to resume"),
+        Err(err) => println!("Err({err})"),
+      }
     }
   } else {
     println!("Hello, world!");
@@ -60,15 +60,26 @@ impl<'input> Parser<'input> {
       TokenKind::LBracket => {
         self.expect(TokenKind::LBracket)?;
         let mut elements = vec![];
+        let mut has_tail = false;
         while !self.is(TokenKind::RBracket) {
           elements.push(self.expression()?);
           if self.is(TokenKind::RBracket) {
             break;
           }
+          if self.is(TokenKind::Pipe) {
+            self.expect(TokenKind::Pipe)?;
+            has_tail = true;
+            break;
+          }
           self.expect(TokenKind::Comma)?;
         }
+        let tail = if has_tail {
+          Some(Box::new(self.expression()?))
+        } else {
+          None
+        };
         self.expect(TokenKind::RBracket)?;
-        Ok(Expression::List { elements })
+        Ok(Expression::List { elements, tail })
       }
       TokenKind::LBrace => {
         self.expect(TokenKind::LBrace)?;
@@ -120,6 +131,8 @@ impl<'input> Parser<'input> {
       TokenKind::Let => self.let_expression(),
       TokenKind::Case => self.match_case_expression(),
       TokenKind::If => self.if_expression(),
+      TokenKind::Receive => self.receive_expression(),
+      TokenKind::Try => self.try_expression(),
       _ => self.infix(0),
     }
   }
@@ -225,6 +238,73 @@ impl<'input> Parser<'input> {
     Ok(Expression::Match { scrutinee, arms })
   }
 
+  fn receive_expression(&mut self) -> Result<Expression, String> {
+    self.expect(TokenKind::Receive)?;
+    let mut arms = vec![self.receive_arm()?];
+    while self.is(TokenKind::Semicolon) {
+      self.eat();
+      arms.push(self.receive_arm()?);
+    }
+    self.expect(TokenKind::End)?;
+    Ok(Expression::Receive { arms })
+  }
+
+  fn receive_arm(&mut self) -> Result<Arm, String> {
+    let lhs = vec![self.pattern()?];
+    self.expect(TokenKind::Arrow)?;
+    let rhs = self.expression()?;
+    Ok(Arm {
+      lhs,
+      rhs: Box::new(rhs),
+    })
+  }
+
+  fn try_expression(&mut self) -> Result<Expression, String> {
+    self.expect(TokenKind::Try)?;
+    let body = self.expression()?;
+    let mut arms = vec![];
+    if self.is(TokenKind::Of) {
+      self.eat();
+      arms.push(self.arm()?);
+      while self.is(TokenKind::Semicolon) {
+        self.eat();
+        arms.push(self.arm()?);
+      }
+    }
+    self.expect(TokenKind::Catch)?;
+    let mut catches = vec![self.catch_arm()?];
+    while self.is(TokenKind::Semicolon) {
+      self.eat();
+      catches.push(self.catch_arm()?);
+    }
+    self.expect(TokenKind::End)?;
+    Ok(Expression::Try {
+      body: Box::new(body),
+      arms,
+      catches,
+    })
+  }
+
+  /// A `class:reason -> expr` arm, where the class defaults to `#throw`.
+  fn catch_arm(&mut self) -> Result<Arm, String> {
+    let mut lhs = vec![self.pattern()?];
+    if self.is(TokenKind::Colon) {
+      self.eat();
+      lhs.push(self.pattern()?);
+    } else {
+      let class = Pattern::Atom {
+        value: "throw".to_string(),
+      };
+      lhs.insert(0, class);
+    }
+    self.expect(TokenKind::Arrow)?;
+    let rhs = self.expression()?;
+    Ok(Arm {
+      lhs,
+      rhs: Box::new(rhs),
+    })
+  }
+
   fn arm(&mut self) -> Result<Arm, String> {
     let mut lhs = vec![self.pattern()?];
     while self.is(TokenKind::Comma) {
@@ -292,7 +372,7 @@ impl<'input> Parser<'input> {
         self.expect(TokenKind::RBrace)?;
         Ok(Pattern::Tuple { elements })
       }
-      _ => todo!(),
+      _ => Err(format!("Expected pattern, got {:?}", self.curr.lexeme)),
     }
   }
 
@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+  desugar::{Desugar, Expression, FnDefinition, Tree},
+  lexer::Lexer,
+  parser::Parser,
+};
+
+/// Source of the functions bundled with every program.
+///
+/// It includes a small supervisor: `supervisor(strategy, max_restarts, specs)`
+/// starts a child with `spawn_link(f, args)` for every `{f, args}` in `specs`
+/// and restarts children that crash. With `#one_for_one` only the crashed
+/// child is restarted, with `#one_for_all` its siblings are killed and
+/// restarted as well. Children exiting with `#normal` are not restarted. Once
+/// more than `max_restarts` restarts are needed, the supervisor exits with
+/// `#shutdown`, which takes the remaining children down with it. Exits of
+/// processes other than its children are ignored. Sending it
+/// `{#which_children, pid}` replies with `{#children, pids}`.
+pub const SOURCE: &str = concat!(
+  include_str!("prelude.lala"),
+  include_str!("supervisor.lala")
+);
+
+/// The only function of the supervisor that programs see. Its helpers are
+/// renamed to `supervisor.<name>`, which no program can define or call.
+const SUPERVISOR: &str = "supervisor";
+
+pub fn definitions() -> BTreeMap<String, FnDefinition> {
+  let mut definitions = parse(include_str!("prelude.lala"));
+  let supervisor = parse(include_str!("supervisor.lala"));
+  let private: HashMap<String, String> = supervisor
+    .keys()
+    .filter(|name| *name != SUPERVISOR)
+    .map(|name| (name.clone(), format!("{SUPERVISOR}.{name}")))
+    .collect();
+  for (name, mut definition) in supervisor {
+    let name = private.get(&name).cloned().unwrap_or(name);
+    let mut names = private.clone();
+    for parameter in &definition.parameters {
+      names.remove(parameter);
+    }
+    rename(&mut definition.body, &names);
+    definition.name = name.clone();
+    definitions.insert(name, definition);
+  }
+  definitions
+}
+
+fn parse(src: &str) -> BTreeMap<String, FnDefinition> {
+  let mut parser = Parser::new(Lexer::new(src));
+  let program = parser
+    .program()
+    .and_then(|p| p.desugar())
+    .expect("prelude should compile");
+  program.definitions
+}
+
+/// Renames the variables of `expression` found in `names`, except where a
+/// binding shadows them.
+fn rename(expression: &mut Expression, names: &HashMap<String, String>) {
+  let without = |bound: &[&String]| {
+    let mut names = names.clone();
+    for name in bound {
+      names.remove(*name);
+    }
+    names
+  };
+  match expression {
+    Expression::Variable { name } => {
+      if let Some(renamed) = names.get(name) {
+        *name = renamed.clone();
+      }
+    }
+    Expression::Number { .. }
+    | Expression::Atom { .. }
+    | Expression::String { .. }
+    | Expression::Nil => (),
+    Expression::Let { bind, value, next } => {
+      rename(value, names);
+      rename(next, &without(&[bind]));
+    }
+    Expression::Match { tree, actions } => {
+      rename_tree(tree, names);
+      actions.iter_mut().for_each(|action| rename(action, names));
+    }
+    Expression::Tuple { elements } => elements.iter_mut().for_each(|e| rename(e, names)),
+    Expression::Binary { lhs, rhs, .. } | Expression::Cons { hd: lhs, tl: rhs } => {
+      rename(lhs, names);
+      rename(rhs, names);
+    }
+    Expression::Call { callee, arguments } => {
+      rename(callee, names);
+      arguments.iter_mut().for_each(|a| rename(a, names));
+    }
+    Expression::If {
+      condition,
+      then_branch,
+      else_branch,
+    } => {
+      rename(condition, names);
+      rename(then_branch, names);
+      rename(else_branch, names);
+    }
+    Expression::Access { expr, .. } => rename(expr, names),
+    Expression::Receive {
+      name,
+      tree,
+      actions,
+    } => {
+      let names = without(&[name]);
+      rename_tree(tree, &names);
+      actions.iter_mut().for_each(|action| rename(action, &names));
+    }
+    Expression::Try {
+      body,
+      class,
+      reason,
+      tree,
+      actions,
+    } => {
+      rename(body, names);
+      let names = without(&[class, reason]);
+      rename_tree(tree, &names);
+      actions.iter_mut().for_each(|action| rename(action, &names));
+    }
+  }
+}
+
+fn rename_tree(tree: &mut Tree, names: &HashMap<String, String>) {
+  if let Tree::Switch(occurrence, branches, default) = tree {
+    rename(&mut occurrence.0, names);
+    for (_, branch) in branches {
+      rename_tree(branch, names);
+    }
+    rename_tree(default, names);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{
+    compile::{Ctx, Machine},
+    desugar::Desugar,
+    eval::Env,
+    lexer::Lexer,
+    parser::Parser,
+  };
+
+  #[test]
+  fn prelude_functions() {
+    let src = r#"
+fn double(x) -> x * 2
+fn isOne(x) -> x == 1
+fn add(x, acc) -> x + acc
+fn push(x, acc) -> [x | acc]
+"#;
+    let program = || {
+      Parser::new(Lexer::new(src))
+        .program()
+        .unwrap()
+        .desugar()
+        .unwrap()
+    };
+    let mut env = Env::from_program(program());
+    let mut ctx = Ctx::new();
+    ctx.program(program());
+    let cases = [
+      ("map(double, [1, 2, 3])", "[2, 4, 6]"),
+      ("filter(isOne, [1, 2, 1])", "[1, 1]"),
+      ("foldl(add, 0, [1, 2, 3])", "6"),
+      ("foldl(push, [], [1, 2, 3])", "[3, 2, 1]"),
+      ("foldr(push, [], [1, 2, 3])", "[1, 2, 3]"),
+      ("any(isOne, [2, 1])", "#true"),
+      ("all(isOne, [2, 1])", "#false"),
+      ("member(3, [1, 2])", "#false"),
+      ("length(map(double, reverse([1, 2])))", "2"),
+    ];
+    let expression = |src| {
+      Parser::new(Lexer::new(src))
+        .expression()
+        .unwrap()
+        .desugar()
+        .unwrap()
+    };
+    let entries: Vec<_> = cases
+      .iter()
+      .map(|(src, _)| ctx.fn_clause(expression(src)))
+      .collect();
+    let mut info = ctx.bytecode();
+    for (entry, (src, expected)) in entries.into_iter().zip(cases) {
+      let res = env.eval(expression(src)).map(|v| v.to_string());
+      assert_eq!(res, Ok(expected.to_string()), "{src}");
+      info.entry = Some(entry);
+      let res = Machine::new(&info).run().map(|v| v.to_string());
+      assert_eq!(res, Ok(expected.to_string()), "{src} on the VM");
+    }
+  }
+}
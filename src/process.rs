@@ -0,0 +1,812 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::builtins::Builtin;
+
+/// Reductions a process may perform before it is preempted.
+pub const SLICE: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub usize);
+
+impl std::fmt::Display for Pid {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "<pid:{}>", self.0)
+  }
+}
+
+/// The values of an engine, as far as the scheduler needs to build and
+/// inspect them.
+pub trait Term: Clone + std::fmt::Display {
+  fn atom(name: &str) -> Self;
+  fn number(n: i32) -> Self;
+  fn string(s: String) -> Self;
+  fn tuple(elements: Vec<Self>) -> Self;
+  fn pid(pid: Pid) -> Self;
+  fn as_atom(&self) -> Option<&str>;
+  fn as_number(&self) -> Option<i32>;
+  fn as_pid(&self) -> Option<Pid>;
+  fn as_string(&self) -> Option<&str>;
+}
+
+/// An abnormal exit of a process, which is raised as an exception first and
+/// can be caught with `try`.
+#[derive(Debug)]
+pub enum Crash<V> {
+  /// A runtime error, such as a failed match, or `error` called with a
+  /// string.
+  Error(String),
+  /// A call to `error` with any other value.
+  Raise(V),
+  /// A call to `throw`.
+  Throw(V),
+  /// A call to `exit` or an exit signal from another process.
+  Exit(V),
+}
+
+impl<V> From<String> for Crash<V> {
+  fn from(err: String) -> Self {
+    Crash::Error(err)
+  }
+}
+
+impl<V: Term> Crash<V> {
+  /// Builds the exception of class `class` (#error, #throw or #exit) with
+  /// `reason`, as seen by a catch clause.
+  pub fn raise(class: &str, reason: V) -> Self {
+    match class {
+      "throw" => Crash::Throw(reason),
+      "exit" => Crash::Exit(reason),
+      _ => match reason.as_string() {
+        Some(err) => Crash::Error(err.to_string()),
+        None => Crash::Raise(reason),
+      },
+    }
+  }
+
+  pub fn class(&self) -> &'static str {
+    match self {
+      Crash::Error(_) | Crash::Raise(_) => "error",
+      Crash::Throw(_) => "throw",
+      Crash::Exit(_) => "exit",
+    }
+  }
+
+  /// The reason bound by a catch clause.
+  pub fn value(&self) -> V {
+    match self {
+      Crash::Error(err) => V::string(err.clone()),
+      Crash::Raise(value) | Crash::Throw(value) | Crash::Exit(value) => value.clone(),
+    }
+  }
+
+  /// The reason sent along with the exit signals of the process.
+  pub fn reason(&self) -> V {
+    match self {
+      Crash::Error(_) | Crash::Raise(_) => V::tuple(vec![V::atom("error"), self.value()]),
+      Crash::Throw(value) => V::tuple(vec![V::atom("nocatch"), value.clone()]),
+      Crash::Exit(reason) => reason.clone(),
+    }
+  }
+}
+
+/// How an uncaught crash is reported.
+impl<V: std::fmt::Display> std::fmt::Display for Crash<V> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Crash::Error(err) => write!(f, "{err}"),
+      Crash::Raise(value) => write!(f, "Uncaught error {value}"),
+      Crash::Throw(value) => write!(f, "Uncaught throw {value}"),
+      Crash::Exit(reason) => write!(f, "Exited with reason {reason}"),
+    }
+  }
+}
+
+/// How a process ended: with its final value or a crash.
+pub type Outcome<V> = Result<V, Crash<V>>;
+
+/// What a process reports back to the scheduler after running a slice.
+pub enum Status<V> {
+  /// The reduction budget ran out; the process can continue right away.
+  Yielded,
+  /// The process is blocked in a `receive` until a new message arrives.
+  Waiting,
+  Exited(Outcome<V>),
+}
+
+/// A lightweight process of one of the execution engines.
+pub trait Process: Sized {
+  type Value: Term;
+  /// State shared by every process of an engine, such as its code.
+  type Shared;
+
+  /// Runs the process for at most `reductions` steps, decrementing it for
+  /// every step taken.
+  fn run(
+    &mut self,
+    shared: &mut Self::Shared,
+    ctx: &mut Context<'_, Self>,
+    reductions: &mut usize,
+  ) -> Status<Self::Value>;
+}
+
+/// The result of running a process with a limited budget of reductions.
+#[derive(Debug)]
+pub enum Evaluation<V> {
+  Done(Result<V, String>),
+  /// The budget ran out before the process exited. It is left suspended and
+  /// continues where it stopped when resumed.
+  OutOfFuel(Suspended),
+}
+
+/// A process whose evaluation ran out of fuel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Suspended(pub Pid);
+
+/// Something a process did to the rest of the system, applied in order when
+/// its slice ends.
+enum Effect<P: Process> {
+  Spawn(Pid, P),
+  Send(Pid, P::Value),
+  Link(Pid),
+  Unlink(Pid),
+  Monitor(usize, Pid),
+  Demonitor(usize),
+  Exit(Pid, P::Value),
+}
+
+/// The view a running process has of the rest of the system.
+pub struct Context<'a, P: Process> {
+  pub pid: Pid,
+  pub mailbox: &'a mut VecDeque<P::Value>,
+  trap_exit: &'a mut bool,
+  next_pid: &'a mut usize,
+  next_ref: &'a mut usize,
+  effects: Vec<Effect<P>>,
+}
+
+impl<'a, P: Process> Context<'a, P> {
+  pub fn spawn(&mut self, process: P) -> Pid {
+    let pid = Pid(*self.next_pid);
+    *self.next_pid += 1;
+    self.effects.push(Effect::Spawn(pid, process));
+    pid
+  }
+
+  /// Sends `message` to `to`. Messages to the running process are visible to
+  /// it immediately; the others are delivered when its slice ends.
+  pub fn send(&mut self, to: Pid, message: P::Value) {
+    if to == self.pid {
+      self.mailbox.push_back(message);
+    } else {
+      self.effects.push(Effect::Send(to, message));
+    }
+  }
+
+  pub fn link(&mut self, to: Pid) {
+    if to != self.pid {
+      self.effects.push(Effect::Link(to));
+    }
+  }
+
+  /// Runs a process builtin other than `spawn` and `spawn_link`, which need
+  /// the engine to build the new process.
+  pub fn call_builtin(
+    &mut self,
+    builtin: Builtin,
+    arguments: Vec<P::Value>,
+  ) -> Result<P::Value, Crash<P::Value>> {
+    builtin.check_arity(arguments.len())?;
+    let bad_argument = || Err(Crash::Error(format!("{}: bad argument", builtin.name())));
+    let ok = P::Value::atom("ok");
+    let mut arguments = arguments.into_iter();
+    let mut next = || arguments.next().unwrap();
+    match builtin {
+      Builtin::SelfPid => Ok(P::Value::pid(self.pid)),
+      Builtin::Send => {
+        let (to, message) = (next(), next());
+        let Some(to) = to.as_pid() else {
+          return bad_argument();
+        };
+        self.send(to, message.clone());
+        Ok(message)
+      }
+      Builtin::Link | Builtin::Unlink => {
+        let Some(to) = next().as_pid() else {
+          return bad_argument();
+        };
+        if builtin == Builtin::Link {
+          self.link(to);
+        } else if to != self.pid {
+          self.effects.push(Effect::Unlink(to));
+        }
+        Ok(ok)
+      }
+      Builtin::Monitor => {
+        let Some(to) = next().as_pid() else {
+          return bad_argument();
+        };
+        let reference = *self.next_ref;
+        *self.next_ref += 1;
+        self.effects.push(Effect::Monitor(reference, to));
+        Ok(P::Value::number(reference as i32))
+      }
+      Builtin::Demonitor => {
+        let Some(reference) = next().as_number() else {
+          return bad_argument();
+        };
+        self.effects.push(Effect::Demonitor(reference as usize));
+        Ok(ok)
+      }
+      Builtin::TrapExit => {
+        let flag = match next().as_atom() {
+          Some("true") => true,
+          Some("false") => false,
+          _ => return bad_argument(),
+        };
+        let old = std::mem::replace(self.trap_exit, flag);
+        Ok(P::Value::atom(if old { "true" } else { "false" }))
+      }
+      Builtin::Exit => Err(Crash::Exit(next())),
+      Builtin::Throw => Err(Crash::Throw(next())),
+      Builtin::Error => Err(Crash::raise("error", next())),
+      Builtin::ExitProcess => {
+        let (to, reason) = (next(), next());
+        let Some(to) = to.as_pid() else {
+          return bad_argument();
+        };
+        if to != self.pid {
+          self.effects.push(Effect::Exit(to, reason));
+        } else if reason.as_atom() == Some("kill") {
+          return Err(Crash::Exit(P::Value::atom("killed")));
+        } else if *self.trap_exit {
+          let message = exit_message(self.pid, reason);
+          self.mailbox.push_back(message);
+        } else {
+          return Err(Crash::Exit(reason));
+        }
+        Ok(ok)
+      }
+      _ => Err(Crash::Error(format!(
+        "{}: not a process builtin",
+        builtin.name()
+      ))),
+    }
+  }
+}
+
+fn exit_message<V: Term>(from: Pid, reason: V) -> V {
+  V::tuple(vec![V::atom("EXIT"), V::pid(from), reason])
+}
+
+struct Slot<P: Process> {
+  process: P,
+  mailbox: VecDeque<P::Value>,
+  waiting: bool,
+  trap_exit: bool,
+  links: BTreeSet<Pid>,
+  /// References and pids of the processes monitoring this one.
+  monitors: Vec<(usize, Pid)>,
+}
+
+/// Runs processes round-robin on the current thread. Every process gets the
+/// same reduction budget per turn, so a run is fully deterministic.
+pub struct Scheduler<P: Process> {
+  processes: BTreeMap<Pid, Slot<P>>,
+  run_queue: VecDeque<Pid>,
+  next_pid: usize,
+  next_ref: usize,
+  /// Processes that exited since the last slice, with their results.
+  terminated: Vec<(Pid, Outcome<P::Value>)>,
+}
+
+impl<P: Process> Default for Scheduler<P> {
+  fn default() -> Self {
+    Self {
+      processes: BTreeMap::new(),
+      run_queue: VecDeque::new(),
+      next_pid: 0,
+      next_ref: 0,
+      terminated: vec![],
+    }
+  }
+}
+
+impl<P: Process> Scheduler<P> {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn spawn(&mut self, process: P) -> Pid {
+    let pid = Pid(self.next_pid);
+    self.next_pid += 1;
+    self.insert(pid, process);
+    pid
+  }
+
+  fn insert(&mut self, pid: Pid, process: P) {
+    let slot = Slot {
+      process,
+      mailbox: VecDeque::new(),
+      waiting: false,
+      trap_exit: false,
+      links: BTreeSet::new(),
+      monitors: vec![],
+    };
+    self.processes.insert(pid, slot);
+    self.run_queue.push_back(pid);
+  }
+
+  /// Delivers `message` to `to`, waking it up if needed. Messages to
+  /// processes that no longer exist are dropped.
+  pub fn send(&mut self, to: Pid, message: P::Value) {
+    if let Some(slot) = self.processes.get_mut(&to) {
+      slot.mailbox.push_back(message);
+      if slot.waiting {
+        slot.waiting = false;
+        self.run_queue.push_back(to);
+      }
+    }
+  }
+
+  pub fn is_alive(&self, pid: Pid) -> bool {
+    self.processes.contains_key(&pid)
+  }
+
+  /// Delivers an exit signal with `reason` from `from` to `to`. Processes
+  /// trapping exits get it as an `{#EXIT, from, reason}` message, the others
+  /// exit with the same reason unless it is `#normal`. A `#kill` sent
+  /// directly with `exit/2` can't be trapped.
+  fn signal(&mut self, from: Pid, to: Pid, reason: P::Value, linked: bool) {
+    let Some(slot) = self.processes.get(&to) else {
+      return;
+    };
+    if !linked && reason.as_atom() == Some("kill") {
+      self.terminate(to, Err(Crash::Exit(P::Value::atom("killed"))));
+    } else if slot.trap_exit {
+      self.send(to, exit_message(from, reason));
+    } else if reason.as_atom() != Some("normal") {
+      self.terminate(to, Err(Crash::Exit(reason)));
+    }
+  }
+
+  /// Removes `pid`, notifying the processes linked to or monitoring it.
+  fn terminate(&mut self, pid: Pid, result: Outcome<P::Value>) {
+    let Some(slot) = self.processes.remove(&pid) else {
+      return;
+    };
+    self.run_queue.retain(|p| *p != pid);
+    let reason = match &result {
+      Ok(_) => P::Value::atom("normal"),
+      Err(crash) => crash.reason(),
+    };
+    self.terminated.push((pid, result));
+    for (reference, watcher) in slot.monitors {
+      let message = P::Value::tuple(vec![
+        P::Value::atom("DOWN"),
+        P::Value::number(reference as i32),
+        P::Value::pid(pid),
+        reason.clone(),
+      ]);
+      self.send(watcher, message);
+    }
+    for linked in slot.links {
+      if let Some(other) = self.processes.get_mut(&linked) {
+        other.links.remove(&pid);
+      }
+      self.signal(pid, linked, reason.clone(), true);
+    }
+  }
+
+  fn apply(&mut self, from: Pid, effect: Effect<P>) {
+    let noproc = || P::Value::atom("noproc");
+    match effect {
+      Effect::Spawn(pid, process) => self.insert(pid, process),
+      Effect::Send(to, message) => self.send(to, message),
+      Effect::Link(to) => {
+        if !self.is_alive(from) {
+          return;
+        }
+        match self.processes.get_mut(&to) {
+          Some(other) => {
+            other.links.insert(from);
+            self.processes.get_mut(&from).unwrap().links.insert(to);
+          }
+          None => self.signal(to, from, noproc(), true),
+        }
+      }
+      Effect::Unlink(to) => {
+        for (a, b) in [(from, to), (to, from)] {
+          if let Some(slot) = self.processes.get_mut(&a) {
+            slot.links.remove(&b);
+          }
+        }
+      }
+      Effect::Monitor(reference, to) => match self.processes.get_mut(&to) {
+        Some(other) => other.monitors.push((reference, from)),
+        None => {
+          let message = P::Value::tuple(vec![
+            P::Value::atom("DOWN"),
+            P::Value::number(reference as i32),
+            P::Value::pid(to),
+            noproc(),
+          ]);
+          self.send(from, message);
+        }
+      },
+      Effect::Demonitor(reference) => {
+        for slot in self.processes.values_mut() {
+          slot.monitors.retain(|(r, _)| *r != reference);
+        }
+      }
+      Effect::Exit(to, reason) => self.signal(from, to, reason, false),
+    }
+  }
+
+  /// Kills `pid` as if by `exit(pid, #kill)`.
+  pub fn kill(&mut self, pid: Pid) {
+    self.terminate(pid, Err(Crash::Exit(P::Value::atom("killed"))));
+    self.terminated.clear();
+  }
+
+  /// Runs the scheduler until `pid` exits and returns its result.
+  pub fn run_until(&mut self, shared: &mut P::Shared, pid: Pid) -> Result<P::Value, String> {
+    self.outcome(shared, pid).map_err(|crash| crash.to_string())
+  }
+
+  /// Like [`Scheduler::run_until`], but gives back the crash of `pid` as it
+  /// is. A deadlock is reported as a [`Crash::Error`].
+  pub fn outcome(&mut self, shared: &mut P::Shared, pid: Pid) -> Outcome<P::Value> {
+    match self.drive(shared, pid, None) {
+      Ok(outcome) => outcome,
+      Err(_) => unreachable!("ran out of unlimited fuel"),
+    }
+  }
+
+  /// Runs the scheduler until `pid` exits or, when there is a `fuel` budget,
+  /// until all processes together have taken that many reductions.
+  pub fn run_for(
+    &mut self,
+    shared: &mut P::Shared,
+    pid: Pid,
+    fuel: Option<usize>,
+  ) -> Evaluation<P::Value> {
+    match self.drive(shared, pid, fuel) {
+      Ok(outcome) => Evaluation::Done(outcome.map_err(|crash| crash.to_string())),
+      Err(suspended) => Evaluation::OutOfFuel(suspended),
+    }
+  }
+
+  fn drive(
+    &mut self,
+    shared: &mut P::Shared,
+    pid: Pid,
+    mut fuel: Option<usize>,
+  ) -> Result<Outcome<P::Value>, Suspended> {
+    // Other processes could run forever without `pid` ever exiting.
+    if !self.is_alive(pid) {
+      let err = format!("{pid} is not a running process");
+      return Ok(Err(Crash::Error(err)));
+    }
+    loop {
+      if fuel == Some(0) {
+        return Err(Suspended(pid));
+      }
+      let Some(current) = self.run_queue.pop_front() else {
+        let err = format!("Deadlock: {pid} is waiting for a message");
+        return Ok(Err(Crash::Error(err)));
+      };
+      let mut slot = self.processes.remove(&current).unwrap();
+      let mut ctx = Context {
+        pid: current,
+        mailbox: &mut slot.mailbox,
+        trap_exit: &mut slot.trap_exit,
+        next_pid: &mut self.next_pid,
+        next_ref: &mut self.next_ref,
+        effects: vec![],
+      };
+      let budget = fuel.map_or(SLICE, |fuel| fuel.min(SLICE));
+      let mut reductions = budget;
+      let status = slot.process.run(shared, &mut ctx, &mut reductions);
+      if let Some(fuel) = &mut fuel {
+        *fuel -= budget - reductions;
+      }
+      let effects = ctx.effects;
+
+      let exited = match status {
+        Status::Yielded => {
+          self.run_queue.push_back(current);
+          None
+        }
+        Status::Waiting => {
+          slot.waiting = true;
+          None
+        }
+        Status::Exited(result) => Some(result),
+      };
+      self.processes.insert(current, slot);
+      for effect in effects {
+        self.apply(current, effect);
+      }
+      if let Some(result) = exited {
+        self.terminate(current, result);
+      }
+      let terminated = std::mem::take(&mut self.terminated);
+      if let Some((_, result)) = terminated.into_iter().find(|(p, _)| *p == pid) {
+        return Ok(result);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{
+    compile::{Ctx, Machine},
+    desugar::Desugar,
+    eval::Env,
+    lexer::Lexer,
+    parser::Parser,
+  };
+
+  const SRC: &str = r#"
+fn pong() ->
+  receive
+    {#ping, from} -> let sent = send(from, #pong) in pong();
+    #stop -> #stopped
+  end
+
+fn count(0, tag, to) -> send(to, tag)
+fn count(n, tag, to) -> count(n - 1, tag, to)
+
+fn collect(0) -> []
+fn collect(n) ->
+  receive
+    tag -> [tag | collect(n - 1)]
+  end
+
+fn crash() -> case 1 of 2 -> 2 end
+
+fn wait() -> receive #never -> 1 end
+
+fn trap(to) ->
+  let trapping = trap_exit(#true) in
+  let ready = send(to, #ready) in
+  wait()
+
+fn worker(id, to) ->
+  let started = send(to, {#started, id, self()}) in
+  wait()
+
+fn restart(x) -> {#mine, x}
+fn child_pids(_) -> #mine
+"#;
+
+  fn eval(src: &str) -> Result<String, String> {
+    let program = Parser::new(Lexer::new(SRC))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let expr = Parser::new(Lexer::new(src))
+      .expression()?
+      .desugar()
+      .unwrap();
+    Env::from_program(program).eval(expr).map(|v| v.to_string())
+  }
+
+  fn run(src: &str) -> Result<String, String> {
+    let program = Parser::new(Lexer::new(SRC))
+      .program()
+      .unwrap()
+      .desugar()
+      .unwrap();
+    let expr = Parser::new(Lexer::new(src))
+      .expression()?
+      .desugar()
+      .unwrap();
+    let mut ctx = Ctx::new();
+    ctx.program(program);
+    ctx.fn_clause(expr);
+    let info = ctx.bytecode();
+    let value = Machine::new(&info).run()?;
+    Ok(value.to_string())
+  }
+
+  #[test]
+  fn ping_pong() {
+    let src = r#"
+let p = spawn(pong, []) in
+let sent = send(p, {#ping, self()}) in
+receive
+  #pong -> send(p, #stop)
+end
+"#;
+    assert_eq!(eval(src), Ok("#stop".to_string()));
+    assert_eq!(run(src), Ok("#stop".to_string()));
+  }
+
+  #[test]
+  fn selective_receive() {
+    let src = r#"
+let a = send(self(), {#b, 2}) in
+let b = send(self(), {#a, 1}) in
+let first = receive {#a, x} -> x end in
+let second = receive {#b, x} -> x end in
+{first, second}
+"#;
+    assert_eq!(eval(src), Ok("{1, 2}".to_string()));
+    assert_eq!(run(src), Ok("{1, 2}".to_string()));
+  }
+
+  #[test]
+  fn preemption_is_deterministic() {
+    // The slow process is spawned first, but gets preempted long before it
+    // is done counting.
+    let src = r#"
+let slow = spawn(count, [5000, #slow, self()]) in
+let fast = spawn(count, [10, #fast, self()]) in
+collect(2)
+"#;
+    for _ in 0..3 {
+      assert_eq!(eval(src), Ok("[#fast, #slow]".to_string()));
+      assert_eq!(run(src), Ok("[#fast, #slow]".to_string()));
+    }
+  }
+
+  #[test]
+  fn deadlock() {
+    let src = "receive #never -> 1 end";
+    assert!(eval(src).unwrap_err().starts_with("Deadlock"));
+    assert!(run(src).unwrap_err().starts_with("Deadlock"));
+  }
+
+  #[test]
+  fn links() {
+    let src = "let p = spawn_link(crash, []) in wait()";
+    let reason = r#"Exited with reason {#error, "Match failure"}"#;
+    assert_eq!(eval(src), Err(reason.to_string()));
+    assert_eq!(run(src), Err(reason.to_string()));
+
+    let src = r#"
+let trapping = trap_exit(#true) in
+let p = spawn_link(crash, []) in
+receive
+  {#EXIT, from, reason} -> {from == p, reason}
+end
+"#;
+    let res = r#"{#true, {#error, "Match failure"}}"#;
+    assert_eq!(eval(src), Ok(res.to_string()));
+    assert_eq!(run(src), Ok(res.to_string()));
+
+    // Normal exits don't take linked processes down.
+    let src = r#"
+let p = spawn_link(count, [0, #done, self()]) in
+let done = receive #done -> #done end in
+let q = spawn(count, [0, #later, self()]) in
+receive #later -> #alive end
+"#;
+    assert_eq!(eval(src), Ok("#alive".to_string()));
+    assert_eq!(run(src), Ok("#alive".to_string()));
+  }
+
+  #[test]
+  fn monitors() {
+    let src = r#"
+let p = spawn(count, [3, #done, self()]) in
+let r = monitor(p) in
+receive
+  {#DOWN, ref, pid, reason} -> {ref == r, pid == p, reason}
+end
+"#;
+    assert_eq!(eval(src), Ok("{#true, #true, #normal}".to_string()));
+    assert_eq!(run(src), Ok("{#true, #true, #normal}".to_string()));
+
+    let src = r#"
+let p = spawn(count, [0, #done, self()]) in
+let done = receive #done -> #done end in
+let r = monitor(p) in
+receive
+  {#DOWN, _, _, reason} -> reason
+end
+"#;
+    assert_eq!(eval(src), Ok("#noproc".to_string()));
+    assert_eq!(run(src), Ok("#noproc".to_string()));
+  }
+
+  #[test]
+  fn exit_signals() {
+    let src = r#"
+let p = spawn(trap, [self()]) in
+let ready = receive #ready -> #ready end in
+let r = monitor(p) in
+let killed = exit(p, #kill) in
+receive
+  {#DOWN, _, _, reason} -> reason
+end
+"#;
+    assert_eq!(eval(src), Ok("#killed".to_string()));
+    assert_eq!(run(src), Ok("#killed".to_string()));
+
+    let src = r#"
+let trapping = trap_exit(#true) in
+let exited = exit(self(), #bye) in
+receive
+  {#EXIT, _, reason} -> reason
+end
+"#;
+    assert_eq!(eval(src), Ok("#bye".to_string()));
+    assert_eq!(run(src), Ok("#bye".to_string()));
+
+    let src = "exit(#bye)";
+    let reason = "Exited with reason #bye";
+    assert_eq!(eval(src), Err(reason.to_string()));
+    assert_eq!(run(src), Err(reason.to_string()));
+  }
+
+  #[test]
+  fn supervisor_one_for_one() {
+    let src = r#"
+let s = spawn(supervisor, [#one_for_one, 3, [{worker, [1, self()]}, {worker, [2, self()]}]]) in
+let a = receive {#started, 1, p} -> p end in
+let b = receive {#started, 2, p} -> p end in
+let crashed = exit(a, #boom) in
+let c = receive {#started, 1, p} -> p end in
+let asked = send(s, {#which_children, self()}) in
+receive
+  {#children, pids} -> {pids == [c, b], c == a}
+end
+"#;
+    assert_eq!(eval(src), Ok("{#true, #false}".to_string()));
+    assert_eq!(run(src), Ok("{#true, #false}".to_string()));
+  }
+
+  #[test]
+  fn supervisor_one_for_all() {
+    let src = r#"
+let s = spawn(supervisor, [#one_for_all, 3, [{worker, [1, self()]}, {worker, [2, self()]}]]) in
+let a = receive {#started, 1, p} -> p end in
+let b = receive {#started, 2, p} -> p end in
+let r = monitor(b) in
+let crashed = exit(a, #boom) in
+let c = receive {#started, 1, p} -> p end in
+let d = receive {#started, 2, p} -> p end in
+let reason = receive {#DOWN, _, _, reason} -> reason end in
+let asked = send(s, {#which_children, self()}) in
+receive
+  {#children, pids} -> {pids == [c, d], reason}
+end
+"#;
+    assert_eq!(eval(src), Ok("{#true, #killed}".to_string()));
+    assert_eq!(run(src), Ok("{#true, #killed}".to_string()));
+  }
+
+  #[test]
+  fn supervisor_gives_up() {
+    let src = r#"
+let s = spawn(supervisor, [#one_for_one, 2, [{crash, []}]]) in
+let r = monitor(s) in
+receive
+  {#DOWN, _, _, reason} -> reason
+end
+"#;
+    assert_eq!(eval(src), Ok("#shutdown".to_string()));
+    assert_eq!(run(src), Ok("#shutdown".to_string()));
+  }
+
+  #[test]
+  fn supervisor_helpers_are_private() {
+    // The exit of a process other than the child uses up no restart.
+    let src = r#"
+let s = spawn(supervisor, [#one_for_one, 0, [{worker, [1, self()]}]]) in
+let a = receive {#started, 1, p} -> p end in
+let fake = send(s, {#EXIT, self(), #boom}) in
+let asked = send(s, {#which_children, self()}) in
+receive
+  {#children, pids} -> {pids == [a], restart(1), child_pids(pids)}
+end
+"#;
+    let expected = "{#true, {#mine, 1}, #mine}".to_string();
+    assert_eq!(eval(src), Ok(expected.clone()));
+    assert_eq!(run(src), Ok(expected));
+  }
+}
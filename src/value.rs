@@ -0,0 +1,336 @@
+//! The values of lala programs, shared by the evaluator and the VM.
+//!
+//! Tuples, lists and strings are shared between the values referring to them,
+//! so cloning a value never copies them. Values are compared and ordered like
+//! in Erlang: numbers come before atoms, then functions, pids, tuples, the
+//! empty list, lists and strings.
+
+use std::{cmp::Ordering, collections::HashSet, rc::Rc};
+
+use crate::{
+  atom::Atom,
+  heap::HeapSize,
+  process::{Pid, Term},
+};
+
+#[cfg(feature = "serde")]
+pub mod serde;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+  Number(i32),
+  Atom(Atom),
+  String(Rc<str>),
+  Tuple(Rc<[Value]>),
+  Cons(Rc<Pair>),
+  Nil,
+  Pid(Pid),
+  /// A function defined by the program, given by its index in the function
+  /// table of the engine running it.
+  Function {
+    id: usize,
+    arity: usize,
+  },
+}
+
+/// A cell with the head and tail of a list.
+#[derive(Debug)]
+pub struct Pair(pub Value, pub Value);
+
+/// Lists can be too long to drop their tail recursively, so the cells no
+/// other value refers to are unlinked one at a time.
+impl Drop for Pair {
+  fn drop(&mut self) {
+    let mut tail = std::mem::take(&mut self.1);
+    while let Value::Cons(cell) = tail {
+      match Rc::try_unwrap(cell) {
+        Ok(mut cell) => tail = std::mem::take(&mut cell.1),
+        Err(_) => break,
+      }
+    }
+  }
+}
+
+impl Value {
+  pub fn cons(hd: Value, tl: Value) -> Self {
+    Value::Cons(Rc::new(Pair(hd, tl)))
+  }
+
+  pub fn from_vec(elements: Vec<Value>) -> Self {
+    elements
+      .into_iter()
+      .rfold(Value::Nil, |acc, nxt| Value::cons(nxt, acc))
+  }
+
+  /// Collects the elements of a proper list, or `None` if `self` is not one.
+  pub fn to_vec(&self) -> Option<Vec<Value>> {
+    let mut elements = vec![];
+    let mut curr = self;
+    loop {
+      match curr {
+        Value::Cons(cell) => {
+          elements.push(cell.0.clone());
+          curr = &cell.1;
+        }
+        Value::Nil => return Some(elements),
+        _ => return None,
+      }
+    }
+  }
+
+  /// The object on the heap the value refers to, if any.
+  pub(crate) fn object(&self) -> Option<*const ()> {
+    match self {
+      Value::Tuple(elements) => Some(Rc::as_ptr(elements).cast()),
+      Value::String(s) => Some(Rc::as_ptr(s).cast()),
+      Value::Cons(cell) => Some(Rc::as_ptr(cell).cast()),
+      _ => None,
+    }
+  }
+
+  /// Position of the kind of the value in the order of values.
+  fn rank(&self) -> u8 {
+    match self {
+      Value::Number(_) => 0,
+      Value::Atom(_) => 1,
+      Value::Function { .. } => 2,
+      Value::Pid(_) => 3,
+      Value::Tuple(_) => 4,
+      Value::Nil => 5,
+      Value::Cons(_) => 6,
+      Value::String(_) => 7,
+    }
+  }
+}
+
+impl Default for Value {
+  fn default() -> Self {
+    Self::Number(0)
+  }
+}
+
+impl std::fmt::Display for Value {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Value::Number(n) => write!(f, "{n}"),
+      Value::String(s) => write!(f, "{s:?}"),
+      Value::Atom(a) => write!(f, "#{a}"),
+      Value::Tuple(elements) => {
+        write!(f, "{{")?;
+        for (i, e) in elements.iter().enumerate() {
+          if i > 0 {
+            write!(f, ", ")?;
+          }
+          write!(f, "{e}")?;
+        }
+        write!(f, "}}")
+      }
+      Value::Cons(cell) => {
+        write!(f, "[{}", cell.0)?;
+        let mut curr = &cell.1;
+        while let Value::Cons(cell) = curr {
+          write!(f, ", {}", cell.0)?;
+          curr = &cell.1;
+        }
+        match curr {
+          Value::Nil => write!(f, "]"),
+          tail => write!(f, " | {tail}]"),
+        }
+      }
+      Value::Nil => write!(f, "[]"),
+      Value::Function { arity, .. } => write!(f, "<fn/{arity}>"),
+      Value::Pid(pid) => write!(f, "{pid}"),
+    }
+  }
+}
+
+/// Structural equality, as computed by `==` in programs. Functions are never
+/// equal, not even to themselves.
+impl PartialEq for Value {
+  fn eq(&self, other: &Self) -> bool {
+    self.partial_cmp(other) == Some(Ordering::Equal)
+  }
+}
+
+/// Functions can't be compared with each other, only with other kinds of
+/// values.
+impl PartialOrd for Value {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    match (self, other) {
+      (Value::Number(a), Value::Number(b)) => a.partial_cmp(b),
+      (Value::Atom(a), Value::Atom(b)) => a.partial_cmp(b),
+      (Value::String(a), Value::String(b)) => a.partial_cmp(b),
+      (Value::Pid(a), Value::Pid(b)) => a.partial_cmp(b),
+      (Value::Function { .. }, Value::Function { .. }) => None,
+      (Value::Tuple(a), Value::Tuple(b)) => match a.len().cmp(&b.len()) {
+        Ordering::Equal => {
+          for (x, y) in a.iter().zip(b.iter()) {
+            match x.partial_cmp(y)? {
+              Ordering::Equal => (),
+              ord => return Some(ord),
+            }
+          }
+          Some(Ordering::Equal)
+        }
+        ord => Some(ord),
+      },
+      (Value::Cons(_), Value::Cons(_)) => {
+        // Lists can be too long to follow their tail recursively.
+        let (mut x, mut y) = (self, other);
+        while let (Value::Cons(a), Value::Cons(b)) = (x, y) {
+          match a.0.partial_cmp(&b.0)? {
+            Ordering::Equal => (x, y) = (&a.1, &b.1),
+            ord => return Some(ord),
+          }
+        }
+        x.partial_cmp(y)
+      }
+      _ => self.rank().partial_cmp(&other.rank()),
+    }
+  }
+}
+
+/// The size of the object a value refers to, without the ones it shares with
+/// other values. Atoms are interned, so they take no room in a process.
+impl HeapSize for Value {
+  fn heap_size(&self) -> usize {
+    let slot = std::mem::size_of::<Value>();
+    match self {
+      Value::String(s) => s.len(),
+      Value::Tuple(elements) => slot * elements.len(),
+      Value::Cons(_) => 2 * slot,
+      _ => 0,
+    }
+  }
+}
+
+/// The size of every object reachable from `roots`, counting the objects they
+/// share once.
+pub fn live_size<'v>(roots: impl IntoIterator<Item = &'v Value>) -> usize {
+  let mut seen = HashSet::new();
+  let mut pending: Vec<&Value> = roots.into_iter().collect();
+  let mut size = 0;
+  while let Some(value) = pending.pop() {
+    if !value.object().is_some_and(|object| seen.insert(object)) {
+      continue;
+    }
+    size += value.heap_size();
+    match value {
+      Value::Tuple(elements) => pending.extend(elements.iter()),
+      Value::Cons(cell) => pending.extend([&cell.0, &cell.1]),
+      _ => (),
+    }
+  }
+  size
+}
+
+impl Term for Value {
+  fn atom(name: &str) -> Self {
+    Value::Atom(Atom::new(name))
+  }
+
+  fn number(n: i32) -> Self {
+    Value::Number(n)
+  }
+
+  fn string(s: String) -> Self {
+    Value::String(s.into())
+  }
+
+  fn tuple(elements: Vec<Self>) -> Self {
+    Value::Tuple(elements.into())
+  }
+
+  fn pid(pid: Pid) -> Self {
+    Value::Pid(pid)
+  }
+
+  fn as_atom(&self) -> Option<&str> {
+    match self {
+      Value::Atom(name) => Some(name.as_str()),
+      _ => None,
+    }
+  }
+
+  fn as_number(&self) -> Option<i32> {
+    match self {
+      Value::Number(n) => Some(*n),
+      _ => None,
+    }
+  }
+
+  fn as_pid(&self) -> Option<Pid> {
+    match self {
+      Value::Pid(pid) => Some(*pid),
+      _ => None,
+    }
+  }
+
+  fn as_string(&self) -> Option<&str> {
+    match self {
+      Value::String(s) => Some(s),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::process::{Pid, Term};
+
+  use super::{live_size, Value};
+
+  #[test]
+  fn display() {
+    let list = Value::from_vec(vec![Value::Number(1), Value::string("a".to_string())]);
+    let value = Value::tuple(vec![Value::atom("ok"), list, Value::Nil]);
+    assert_eq!(value.to_string(), r#"{#ok, [1, "a"], []}"#);
+    let improper = Value::cons(Value::Number(1), Value::Number(2));
+    assert_eq!(improper.to_string(), "[1 | 2]");
+    let function = Value::Function { id: 0, arity: 2 };
+    assert_eq!(function.to_string(), "<fn/2>");
+  }
+
+  #[test]
+  fn order() {
+    let ascending = [
+      Value::Number(-1),
+      Value::Number(2),
+      Value::atom("a"),
+      Value::atom("b"),
+      Value::Function { id: 0, arity: 0 },
+      Value::Pid(Pid(1)),
+      Value::tuple(vec![Value::Number(3)]),
+      Value::tuple(vec![Value::Number(1), Value::Number(2)]),
+      Value::Nil,
+      Value::from_vec(vec![Value::Number(1), Value::Number(2)]),
+      Value::from_vec(vec![Value::Number(1), Value::Number(2), Value::Number(0)]),
+      Value::from_vec(vec![Value::Number(2)]),
+      Value::string("a".to_string()),
+    ];
+    for (i, x) in ascending.iter().enumerate() {
+      for (j, y) in ascending
+        .iter()
+        .enumerate()
+        .filter(|(j, _)| *j != 4 || i != 4)
+      {
+        assert_eq!(x.partial_cmp(y), Some(i.cmp(&j)), "{x} and {y}");
+      }
+    }
+    // Functions can't be compared with each other.
+    let function = &ascending[4];
+    assert_ne!(function, function);
+    assert_eq!(function.partial_cmp(function), None);
+    let long = Value::from_vec(vec![Value::Number(1); 1_000_000]);
+    assert_eq!(long, long.clone());
+  }
+
+  #[test]
+  fn shared_size() {
+    let tuple = Value::tuple(vec![Value::Number(1), Value::Number(2)]);
+    let list = Value::cons(tuple.clone(), Value::cons(tuple.clone(), Value::Nil));
+    let slot = std::mem::size_of::<Value>();
+    assert_eq!(live_size([&list]), 2 * 2 * slot + 2 * slot);
+    assert_eq!(live_size([&list, &tuple]), live_size([&list]));
+  }
+}
@@ -0,0 +1,855 @@
+//! Serde support for values, with the `serde` feature.
+//!
+//! Values serialize to any format: booleans are atoms, sequences are lists,
+//! maps and structs are property lists of `{"key", value}` pairs and enum
+//! variants are tagged tuples, `{#variant, field, ...}`. Formats without
+//! atoms get their names as strings, so atoms only survive a round trip
+//! through formats that have variants, or through [`to_value`] and
+//! [`from_value`], which turn any Rust type into a value and back.
+
+use std::fmt;
+
+use ::serde::{
+  de::{
+    self, value::BorrowedStrDeserializer, DeserializeSeed, EnumAccess, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+  },
+  forward_to_deserialize_any,
+  ser::{self, SerializeMap, SerializeSeq, SerializeTuple, SerializeTupleVariant},
+  Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::Value;
+use crate::{atom::Atom, convert::mismatch};
+
+/// The name given to serde for the variants values are tagged with.
+const NAME: &str = "Value";
+
+/// Why a value could not be serialized or deserialized.
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+  fn custom<T: fmt::Display>(msg: T) -> Self {
+    Error(msg.to_string())
+  }
+}
+
+impl de::Error for Error {
+  fn custom<T: fmt::Display>(msg: T) -> Self {
+    Error(msg.to_string())
+  }
+}
+
+/// Converts any serializable Rust value to a lala value.
+pub fn to_value<T: Serialize + ?Sized>(x: &T) -> Result<Value, String> {
+  x.serialize(ValueSerializer).map_err(|Error(err)| err)
+}
+
+/// Converts a lala value to any deserializable Rust type.
+pub fn from_value<'v, T: Deserialize<'v>>(value: &'v Value) -> Result<T, String> {
+  T::deserialize(value).map_err(|Error(err)| err)
+}
+
+/// The atom `name`, failing once the atom table is full.
+fn atom(name: &str) -> Result<Value, Error> {
+  Atom::try_new(name).map(Value::Atom).map_err(Error)
+}
+
+/// The entries of a list of `{"key", value}` pairs, which serializes as a
+/// map. Other lists, including the empty one, serialize as sequences.
+fn properties(elements: &[Value]) -> Option<Vec<(&str, &Value)>> {
+  if elements.is_empty() {
+    return None;
+  }
+  let entries = elements.iter().map(|element| match element {
+    Value::Tuple(pair) => match &pair[..] {
+      [Value::String(key), value] => Some((&**key, value)),
+      _ => None,
+    },
+    _ => None,
+  });
+  entries.collect()
+}
+
+impl Serialize for Value {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match self {
+      Value::Number(n) => serializer.serialize_i32(*n),
+      Value::Atom(Atom::TRUE) => serializer.serialize_bool(true),
+      Value::Atom(Atom::FALSE) => serializer.serialize_bool(false),
+      Value::Atom(atom) => serializer.serialize_unit_variant(NAME, 0, atom.as_str()),
+      Value::String(s) => serializer.serialize_str(s),
+      Value::Tuple(elements) => match &elements[..] {
+        [] => serializer.serialize_unit(),
+        [Value::Atom(tag), x] => serializer.serialize_newtype_variant(NAME, 0, tag.as_str(), x),
+        [Value::Atom(tag), rest @ ..] => {
+          let mut variant =
+            serializer.serialize_tuple_variant(NAME, 0, tag.as_str(), rest.len())?;
+          for x in rest {
+            variant.serialize_field(x)?;
+          }
+          variant.end()
+        }
+        _ => {
+          let mut tuple = serializer.serialize_tuple(elements.len())?;
+          for x in elements.iter() {
+            tuple.serialize_element(x)?;
+          }
+          tuple.end()
+        }
+      },
+      Value::Nil | Value::Cons(_) => {
+        let Some(elements) = self.to_vec() else {
+          return Err(ser::Error::custom(format!("Can't serialize {self}")));
+        };
+        if let Some(entries) = properties(&elements) {
+          let mut map = serializer.serialize_map(Some(entries.len()))?;
+          for (key, value) in entries {
+            map.serialize_entry(key, value)?;
+          }
+          return map.end();
+        }
+        let mut seq = serializer.serialize_seq(Some(elements.len()))?;
+        for x in &elements {
+          seq.serialize_element(x)?;
+        }
+        seq.end()
+      }
+      Value::Pid(_) | Value::Function { .. } => {
+        Err(ser::Error::custom(format!("Can't serialize {self}")))
+      }
+    }
+  }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+  type Value = Value;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("a lala value")
+  }
+
+  fn visit_bool<E: de::Error>(self, b: bool) -> Result<Value, E> {
+    Ok(Value::Atom(Atom::boolean(b)))
+  }
+
+  fn visit_i64<E: de::Error>(self, n: i64) -> Result<Value, E> {
+    let n = i32::try_from(n).map_err(|_| E::custom(format!("{n} doesn't fit in a number")))?;
+    Ok(Value::Number(n))
+  }
+
+  fn visit_u64<E: de::Error>(self, n: u64) -> Result<Value, E> {
+    let n = i32::try_from(n).map_err(|_| E::custom(format!("{n} doesn't fit in a number")))?;
+    Ok(Value::Number(n))
+  }
+
+  fn visit_str<E: de::Error>(self, s: &str) -> Result<Value, E> {
+    Ok(Value::String(s.into()))
+  }
+
+  fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+    Ok(Value::Tuple([].into()))
+  }
+
+  fn visit_none<E: de::Error>(self) -> Result<Value, E> {
+    atom("none").map_err(|Error(err)| E::custom(err))
+  }
+
+  fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+    let x = Value::deserialize(deserializer)?;
+    let some = atom("some").map_err(|Error(err)| de::Error::custom(err))?;
+    Ok(Value::Tuple([some, x].into()))
+  }
+
+  fn visit_newtype_struct<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+    Value::deserialize(deserializer)
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+    let mut elements = vec![];
+    while let Some(x) = seq.next_element()? {
+      elements.push(x);
+    }
+    Ok(Value::from_vec(elements))
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+    let mut entries = vec![];
+    while let Some((key, value)) = map.next_entry::<Value, Value>()? {
+      entries.push(Value::Tuple([key, value].into()));
+    }
+    Ok(Value::from_vec(entries))
+  }
+}
+
+impl<'de> Deserialize<'de> for Value {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(ValueVisitor)
+  }
+}
+
+/// Builds values from the serde data model, as described in the module
+/// documentation.
+struct ValueSerializer;
+
+impl Serializer for ValueSerializer {
+  type Ok = Value;
+  type Error = Error;
+  type SerializeSeq = Elements;
+  type SerializeTuple = Elements;
+  type SerializeTupleStruct = Elements;
+  type SerializeTupleVariant = Elements;
+  type SerializeMap = Properties;
+  type SerializeStruct = Properties;
+  type SerializeStructVariant = Properties;
+
+  fn serialize_bool(self, b: bool) -> Result<Value, Error> {
+    Ok(Value::Atom(Atom::boolean(b)))
+  }
+
+  fn serialize_i8(self, n: i8) -> Result<Value, Error> {
+    Ok(Value::Number(n.into()))
+  }
+
+  fn serialize_i16(self, n: i16) -> Result<Value, Error> {
+    Ok(Value::Number(n.into()))
+  }
+
+  fn serialize_i32(self, n: i32) -> Result<Value, Error> {
+    Ok(Value::Number(n))
+  }
+
+  fn serialize_i64(self, n: i64) -> Result<Value, Error> {
+    let n = i32::try_from(n).map_err(|_| Error(format!("{n} doesn't fit in a number")))?;
+    Ok(Value::Number(n))
+  }
+
+  fn serialize_u8(self, n: u8) -> Result<Value, Error> {
+    Ok(Value::Number(n.into()))
+  }
+
+  fn serialize_u16(self, n: u16) -> Result<Value, Error> {
+    Ok(Value::Number(n.into()))
+  }
+
+  fn serialize_u32(self, n: u32) -> Result<Value, Error> {
+    self.serialize_u64(n.into())
+  }
+
+  fn serialize_u64(self, n: u64) -> Result<Value, Error> {
+    let n = i32::try_from(n).map_err(|_| Error(format!("{n} doesn't fit in a number")))?;
+    Ok(Value::Number(n))
+  }
+
+  fn serialize_f32(self, x: f32) -> Result<Value, Error> {
+    self.serialize_f64(x.into())
+  }
+
+  fn serialize_f64(self, x: f64) -> Result<Value, Error> {
+    Err(Error(format!("Can't represent {x}, as lala has no floats")))
+  }
+
+  fn serialize_char(self, c: char) -> Result<Value, Error> {
+    Ok(Value::String(c.to_string().into()))
+  }
+
+  fn serialize_str(self, s: &str) -> Result<Value, Error> {
+    Ok(Value::String(s.into()))
+  }
+
+  fn serialize_bytes(self, bytes: &[u8]) -> Result<Value, Error> {
+    let bytes = bytes.iter().map(|b| Value::Number((*b).into())).collect();
+    Ok(Value::from_vec(bytes))
+  }
+
+  fn serialize_none(self) -> Result<Value, Error> {
+    atom("none")
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(self, x: &T) -> Result<Value, Error> {
+    Ok(Value::Tuple([atom("some")?, x.serialize(self)?].into()))
+  }
+
+  fn serialize_unit(self) -> Result<Value, Error> {
+    Ok(Value::Tuple([].into()))
+  }
+
+  fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+    self.serialize_unit()
+  }
+
+  fn serialize_unit_variant(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+  ) -> Result<Value, Error> {
+    atom(variant)
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(
+    self,
+    _name: &'static str,
+    x: &T,
+  ) -> Result<Value, Error> {
+    x.serialize(self)
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+    x: &T,
+  ) -> Result<Value, Error> {
+    Ok(Value::Tuple([atom(variant)?, x.serialize(self)?].into()))
+  }
+
+  fn serialize_seq(self, len: Option<usize>) -> Result<Elements, Error> {
+    Ok(Elements::new(Shape::List, len.unwrap_or(0)))
+  }
+
+  fn serialize_tuple(self, len: usize) -> Result<Elements, Error> {
+    Ok(Elements::new(Shape::Tuple, len))
+  }
+
+  fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Elements, Error> {
+    Ok(Elements::new(Shape::Tuple, len))
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+    len: usize,
+  ) -> Result<Elements, Error> {
+    let mut elements = Elements::new(Shape::Tuple, len + 1);
+    elements.elements.push(atom(variant)?);
+    Ok(elements)
+  }
+
+  fn serialize_map(self, _len: Option<usize>) -> Result<Properties, Error> {
+    Ok(Properties::default())
+  }
+
+  fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Properties, Error> {
+    Ok(Properties::default())
+  }
+
+  fn serialize_struct_variant(
+    self,
+    _name: &'static str,
+    _index: u32,
+    variant: &'static str,
+    _len: usize,
+  ) -> Result<Properties, Error> {
+    Ok(Properties {
+      variant: Some(variant),
+      ..Properties::default()
+    })
+  }
+}
+
+enum Shape {
+  List,
+  Tuple,
+}
+
+/// The elements of a list or tuple being serialized.
+struct Elements {
+  shape: Shape,
+  elements: Vec<Value>,
+}
+
+impl Elements {
+  fn new(shape: Shape, len: usize) -> Self {
+    Self {
+      shape,
+      elements: Vec::with_capacity(len),
+    }
+  }
+
+  fn push<T: Serialize + ?Sized>(&mut self, x: &T) -> Result<(), Error> {
+    self.elements.push(x.serialize(ValueSerializer)?);
+    Ok(())
+  }
+
+  fn finish(self) -> Value {
+    match self.shape {
+      Shape::List => Value::from_vec(self.elements),
+      Shape::Tuple => Value::Tuple(self.elements.into()),
+    }
+  }
+}
+
+impl SerializeSeq for Elements {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, x: &T) -> Result<(), Error> {
+    self.push(x)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    Ok(self.finish())
+  }
+}
+
+impl SerializeTuple for Elements {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, x: &T) -> Result<(), Error> {
+    self.push(x)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    Ok(self.finish())
+  }
+}
+
+impl ser::SerializeTupleStruct for Elements {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, x: &T) -> Result<(), Error> {
+    self.push(x)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    Ok(self.finish())
+  }
+}
+
+impl SerializeTupleVariant for Elements {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(&mut self, x: &T) -> Result<(), Error> {
+    self.push(x)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    Ok(self.finish())
+  }
+}
+
+/// The `{key, value}` pairs of a map or struct being serialized, tagged
+/// with the variant of a struct variant.
+#[derive(Default)]
+struct Properties {
+  variant: Option<&'static str>,
+  entries: Vec<Value>,
+  key: Option<Value>,
+}
+
+impl Properties {
+  fn finish(self) -> Result<Value, Error> {
+    let entries = Value::from_vec(self.entries);
+    match self.variant {
+      Some(variant) => Ok(Value::Tuple([atom(variant)?, entries].into())),
+      None => Ok(entries),
+    }
+  }
+}
+
+impl SerializeMap for Properties {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+    self.key = Some(key.serialize(ValueSerializer)?);
+    Ok(())
+  }
+
+  fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+    let key = self
+      .key
+      .take()
+      .ok_or(Error("Value without a key".to_string()))?;
+    let value = value.serialize(ValueSerializer)?;
+    self.entries.push(Value::Tuple([key, value].into()));
+    Ok(())
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    self.finish()
+  }
+}
+
+impl ser::SerializeStruct for Properties {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    key: &'static str,
+    value: &T,
+  ) -> Result<(), Error> {
+    self.serialize_entry(key, value)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    self.finish()
+  }
+}
+
+impl ser::SerializeStructVariant for Properties {
+  type Ok = Value;
+  type Error = Error;
+
+  fn serialize_field<T: Serialize + ?Sized>(
+    &mut self,
+    key: &'static str,
+    value: &T,
+  ) -> Result<(), Error> {
+    self.serialize_entry(key, value)
+  }
+
+  fn end(self) -> Result<Value, Error> {
+    self.finish()
+  }
+}
+
+/// Whether `value` is a list that serializes as a map.
+fn is_properties(value: &Value) -> bool {
+  value
+    .to_vec()
+    .is_some_and(|elements| properties(&elements).is_some())
+}
+
+/// Reads values the way [`to_value`] builds them.
+impl<'de> Deserializer<'de> for &'de Value {
+  type Error = Error;
+
+  fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+    match self {
+      Value::Number(n) => visitor.visit_i32(*n),
+      Value::Atom(Atom::TRUE) => visitor.visit_bool(true),
+      Value::Atom(Atom::FALSE) => visitor.visit_bool(false),
+      Value::Atom(atom) => visitor.visit_borrowed_str(atom.as_str()),
+      Value::String(s) => visitor.visit_borrowed_str(s),
+      Value::Tuple(elements) if elements.is_empty() => visitor.visit_unit(),
+      Value::Tuple(elements) => visitor.visit_seq(Slice(elements.iter())),
+      Value::Nil | Value::Cons(_) if is_properties(self) => visitor.visit_map(List::new(self)),
+      Value::Nil | Value::Cons(_) => visitor.visit_seq(List::new(self)),
+      Value::Pid(_) | Value::Function { .. } => Err(Error(format!("Can't deserialize {self}"))),
+    }
+  }
+
+  fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+    match self {
+      Value::Atom(atom) if atom.as_str() == "none" => visitor.visit_none(),
+      Value::Tuple(elements) => match &elements[..] {
+        [Value::Atom(tag), x] if tag.as_str() == "some" => visitor.visit_some(x),
+        _ => Err(Error(mismatch("an option", self))),
+      },
+      _ => Err(Error(mismatch("an option", self))),
+    }
+  }
+
+  fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+    match self {
+      Value::Tuple(elements) if elements.is_empty() => visitor.visit_unit(),
+      _ => Err(Error(mismatch("an empty tuple", self))),
+    }
+  }
+
+  fn deserialize_unit_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    self.deserialize_unit(visitor)
+  }
+
+  fn deserialize_newtype_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    visitor.visit_newtype_struct(self)
+  }
+
+  fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+    match self {
+      Value::Tuple(elements) => visitor.visit_seq(Slice(elements.iter())),
+      Value::Nil | Value::Cons(_) => visitor.visit_seq(List::new(self)),
+      _ => Err(Error(mismatch("a list", self))),
+    }
+  }
+
+  fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+    self.deserialize_seq(visitor)
+  }
+
+  fn deserialize_tuple_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    _len: usize,
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    self.deserialize_seq(visitor)
+  }
+
+  fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+    match self {
+      Value::Nil | Value::Cons(_) => visitor.visit_map(List::new(self)),
+      _ => Err(Error(mismatch("a property list", self))),
+    }
+  }
+
+  fn deserialize_struct<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    _fields: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    self.deserialize_map(visitor)
+  }
+
+  fn deserialize_enum<V: Visitor<'de>>(
+    self,
+    _name: &'static str,
+    _variants: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self {
+      Value::Atom(tag) => visitor.visit_enum(Variant(tag.as_str(), &[])),
+      Value::Tuple(elements) => match &elements[..] {
+        [Value::Atom(tag), fields @ ..] => visitor.visit_enum(Variant(tag.as_str(), fields)),
+        _ => Err(Error(mismatch("a tagged tuple", self))),
+      },
+      _ => Err(Error(mismatch("a tagged tuple", self))),
+    }
+  }
+
+  forward_to_deserialize_any! {
+    bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+    identifier ignored_any
+  }
+}
+
+/// The elements of a tuple.
+struct Slice<'de>(std::slice::Iter<'de, Value>);
+
+impl<'de> SeqAccess<'de> for Slice<'de> {
+  type Error = Error;
+
+  fn next_element_seed<T: DeserializeSeed<'de>>(
+    &mut self,
+    seed: T,
+  ) -> Result<Option<T::Value>, Error> {
+    self.0.next().map(|x| seed.deserialize(x)).transpose()
+  }
+
+  fn size_hint(&self) -> Option<usize> {
+    Some(self.0.len())
+  }
+}
+
+/// The elements of a list, or the pairs of a property list, followed in
+/// place.
+struct List<'de> {
+  rest: &'de Value,
+  /// The value of the pair whose key was just read.
+  value: Option<&'de Value>,
+}
+
+impl<'de> List<'de> {
+  fn new(list: &'de Value) -> Self {
+    Self {
+      rest: list,
+      value: None,
+    }
+  }
+
+  fn next(&mut self) -> Result<Option<&'de Value>, Error> {
+    match self.rest {
+      Value::Cons(cell) => {
+        self.rest = &cell.1;
+        Ok(Some(&cell.0))
+      }
+      Value::Nil => Ok(None),
+      tail => Err(Error(format!("Improper list ending with {tail}"))),
+    }
+  }
+}
+
+impl<'de> SeqAccess<'de> for List<'de> {
+  type Error = Error;
+
+  fn next_element_seed<T: DeserializeSeed<'de>>(
+    &mut self,
+    seed: T,
+  ) -> Result<Option<T::Value>, Error> {
+    self.next()?.map(|x| seed.deserialize(x)).transpose()
+  }
+}
+
+impl<'de> MapAccess<'de> for List<'de> {
+  type Error = Error;
+
+  fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+    let Some(pair) = self.next()? else {
+      return Ok(None);
+    };
+    match pair {
+      Value::Tuple(elements) if elements.len() == 2 => {
+        self.value = Some(&elements[1]);
+        seed.deserialize(&elements[0]).map(Some)
+      }
+      _ => Err(Error(mismatch("a {key, value} pair", pair))),
+    }
+  }
+
+  fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+    let value = self
+      .value
+      .take()
+      .ok_or(Error("Value without a key".to_string()))?;
+    seed.deserialize(value)
+  }
+}
+
+/// A variant tag with the fields that follow it.
+struct Variant<'de>(&'static str, &'de [Value]);
+
+impl<'de> EnumAccess<'de> for Variant<'de> {
+  type Error = Error;
+  type Variant = Self;
+
+  fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+    let tag = seed.deserialize(BorrowedStrDeserializer::new(self.0))?;
+    Ok((tag, self))
+  }
+}
+
+impl<'de> VariantAccess<'de> for Variant<'de> {
+  type Error = Error;
+
+  fn unit_variant(self) -> Result<(), Error> {
+    match self.1 {
+      [] => Ok(()),
+      _ => Err(Error(format!("#{} takes no fields", self.0))),
+    }
+  }
+
+  fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+    match self.1 {
+      [x] => seed.deserialize(x),
+      _ => Err(Error(format!("#{} takes one field", self.0))),
+    }
+  }
+
+  fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+    visitor.visit_seq(Slice(self.1.iter()))
+  }
+
+  fn struct_variant<V: Visitor<'de>>(
+    self,
+    _fields: &'static [&'static str],
+    visitor: V,
+  ) -> Result<V::Value, Error> {
+    match self.1 {
+      [properties] => properties.deserialize_map(visitor),
+      _ => Err(Error(format!("#{} takes a property list", self.0))),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::BTreeMap;
+
+  use ::serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+  use super::{from_value, to_value};
+  use crate::{atom::Atom, value::Value};
+
+  #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+  struct Point {
+    x: i32,
+    y: i32,
+  }
+
+  #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+  #[serde(rename_all = "snake_case")]
+  enum Shape {
+    Empty,
+    Circle(Point, u16),
+    Square(u16),
+    BoundingBox {
+      corner: Point,
+      label: Option<String>,
+    },
+  }
+
+  fn round_trip<T>(x: T, shown: &str)
+  where
+    T: Serialize + DeserializeOwned + std::fmt::Debug + PartialEq,
+  {
+    let value = to_value(&x).unwrap();
+    assert_eq!(value.to_string(), shown);
+    assert_eq!(from_value::<T>(&value), Ok(x));
+  }
+
+  #[test]
+  fn rust_values() {
+    round_trip((1, true, ()), "{1, #true, {}}");
+    round_trip(vec![Some(1), None], "[{#some, 1}, #none]");
+    round_trip(Point { x: 1, y: 2 }, r#"[{"x", 1}, {"y", 2}]"#);
+    round_trip(Shape::Empty, "#empty");
+    round_trip(Shape::Square(3), "{#square, 3}");
+    let circle = Shape::Circle(Point { x: 0, y: 1 }, 2);
+    round_trip(circle, r#"{#circle, [{"x", 0}, {"y", 1}], 2}"#);
+    let bounding = Shape::BoundingBox {
+      corner: Point { x: 0, y: 0 },
+      label: Some("b".to_string()),
+    };
+    let shown = r#"{#bounding_box, [{"corner", [{"x", 0}, {"y", 0}]}, {"label", {#some, "b"}}]}"#;
+    round_trip(bounding, shown);
+    let map = BTreeMap::from([("a".to_string(), vec![1]), ("b".to_string(), vec![])]);
+    round_trip(map, r#"[{"a", [1]}, {"b", []}]"#);
+    assert_eq!(
+      to_value(&1.5).unwrap_err(),
+      "Can't represent 1.5, as lala has no floats"
+    );
+    assert_eq!(
+      to_value(&u32::MAX).unwrap_err(),
+      "4294967295 doesn't fit in a number"
+    );
+    let point = from_value::<Point>(&Value::Number(1));
+    assert_eq!(point, Err("Expected a property list but got 1".to_string()));
+  }
+
+  #[test]
+  fn json() {
+    let json = r#"{"name":"lala","tags":["a","b"],"ok":true,"none":null,"n":-3}"#;
+    let value: Value = serde_json::from_str(json).unwrap();
+    let shown =
+      r#"[{"name", "lala"}, {"tags", ["a", "b"]}, {"ok", #true}, {"none", {}}, {"n", -3}]"#;
+    assert_eq!(value.to_string(), shown);
+    assert_eq!(serde_json::to_string(&value).unwrap(), json);
+    let tagged = Value::Tuple([Value::Atom(Atom::new("ok")), Value::Number(1)].into());
+    assert_eq!(serde_json::to_string(&tagged).unwrap(), r#"{"ok":1}"#);
+    let circle = to_value(&Shape::Circle(Point { x: 0, y: 1 }, 2)).unwrap();
+    let json = serde_json::to_string(&circle).unwrap();
+    assert_eq!(json, r#"{"circle":[{"x":0,"y":1},2]}"#);
+    let shape: Shape = serde_json::from_str(&json).unwrap();
+    assert_eq!(to_value(&shape), Ok(circle));
+    assert!(serde_json::from_str::<Value>("1.5").is_err());
+  }
+}